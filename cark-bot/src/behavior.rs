@@ -0,0 +1,516 @@
+use std::collections::{HashSet, VecDeque};
+
+use cark_client::game::{Character, Game};
+use cark_common::{
+    direction::Direction,
+    entity::EntityKind,
+    field::{ChunkId, Field},
+    path::find_path,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::steering::{Keys, PathFollower};
+
+// Tiles visited by a single path search
+const MAX_PATH_NODES: usize = 2048;
+
+pub struct Context<'a> {
+    pub game: &'a Game,
+    pub character: &'a Character,
+    pub dt: f32,
+}
+
+impl<'a> Context<'a> {
+    pub fn field(&self) -> &'a Field {
+        self.game.field()
+    }
+
+    // Other characters with their positions seen from our chunk and their distances
+    pub fn others(&self) -> impl Iterator<Item = (&'a Character, [f32; 2], f32)> + '_ {
+        self.game
+            .characters
+            .iter()
+            .filter(|c| c.id() != self.game.player_id)
+            .filter_map(|c| {
+                let position = self.field().position_in(
+                    self.character.chunk_id,
+                    c.chunk_id,
+                    c.position,
+                    self.character.position,
+                )?;
+                let dx = position[0] - self.character.position[0];
+                let dy = position[1] - self.character.position[1];
+                Some((c, position, (dx * dx + dy * dy).sqrt()))
+            })
+    }
+
+    pub fn can_see(&self, other: &Character) -> bool {
+        self.field().line_of_sight(
+            (self.character.chunk_id, self.character.position),
+            (other.chunk_id, other.position),
+            self.game.tiles(),
+        )
+    }
+}
+
+#[derive(Default)]
+pub struct Output {
+    pub keys: Keys,
+    pub chat: Vec<String>,
+}
+
+pub trait Behavior {
+    // Returns true to take control of the movement, leaving `output.keys` to this behaviour.
+    // Behaviours that return false may still chat.
+    fn update(&mut self, context: &Context, output: &mut Output) -> bool;
+}
+
+// Runs the behaviours in order until one takes control
+pub struct Selector {
+    behaviors: Vec<Box<dyn Behavior>>,
+}
+
+impl Selector {
+    pub fn new(behaviors: Vec<Box<dyn Behavior>>) -> Self {
+        Self { behaviors }
+    }
+}
+
+impl Behavior for Selector {
+    fn update(&mut self, context: &Context, output: &mut Output) -> bool {
+        self.behaviors
+            .iter_mut()
+            .any(|behavior| behavior.update(context, output))
+    }
+}
+
+// Finds paths and follows them, finding a new one when the target moves to another tile
+#[derive(Default)]
+struct Navigator {
+    follower: PathFollower,
+    target: Option<(ChunkId, [i32; 2])>,
+}
+
+impl Navigator {
+    fn go_to(&mut self, context: &Context, chunk_id: ChunkId, position: [f32; 2]) -> Keys {
+        let tile = (
+            chunk_id,
+            [position[0].floor() as i32, position[1].floor() as i32],
+        );
+        if self.target != Some(tile) {
+            self.target = Some(tile);
+            match find_path(
+                context.field(),
+                context.game.tiles(),
+                (context.character.chunk_id, context.character.position),
+                (chunk_id, position),
+                MAX_PATH_NODES,
+            ) {
+                Some(path) => self.follower.set_path(path),
+                None => self.follower.clear(),
+            }
+        }
+        self.follower.keys(
+            context.field(),
+            context.character.chunk_id,
+            context.character.position,
+        )
+    }
+
+    fn arrived(&self) -> bool {
+        self.target.is_some() && self.follower.is_done()
+    }
+
+    fn reset(&mut self) {
+        self.target = None;
+        self.follower.clear();
+    }
+}
+
+// Holds random keys for random durations
+pub struct Wander {
+    rng: StdRng,
+    keys: Keys,
+    remaining: f32,
+}
+
+impl Wander {
+    pub fn new() -> Self {
+        Self {
+            rng: StdRng::from_entropy(),
+            keys: [false; 4],
+            remaining: 0.0,
+        }
+    }
+}
+
+impl Default for Wander {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Behavior for Wander {
+    fn update(&mut self, context: &Context, output: &mut Output) -> bool {
+        self.remaining -= context.dt;
+        if self.remaining <= 0.0 {
+            self.remaining = self.rng.gen_range(0.5..3.0);
+            // Stand still now and then
+            self.keys = if self.rng.gen_bool(0.25) {
+                [false; 4]
+            } else {
+                let up = self.rng.gen_bool(0.5);
+                let left = self.rng.gen_bool(0.5);
+                let vertical = self.rng.gen_bool(0.7);
+                let horizontal = !vertical || self.rng.gen_bool(0.5);
+                [
+                    vertical && up,
+                    vertical && !up,
+                    horizontal && left,
+                    horizontal && !left,
+                ]
+            };
+        }
+        output.keys = self.keys;
+        true
+    }
+}
+
+// Walks toward the nearest edge that has no chunk loaded behind it
+#[derive(Default)]
+pub struct Explore {
+    navigator: Navigator,
+}
+
+impl Explore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The tile at the middle of the nearest open edge
+    fn frontier(field: &Field, start: ChunkId) -> Option<(ChunkId, [f32; 2])> {
+        let size = field.chunk_size() as f32;
+        let mut visited = HashSet::from([start]);
+        let mut open = VecDeque::from([start]);
+        while let Some(id) = open.pop_front() {
+            let chunk = field.chunk(id)?;
+            for direction in Direction::ALL {
+                let related = ChunkId::new(chunk.related[direction.to_number()]);
+                match related.filter(|id| field.chunk(*id).is_some()) {
+                    Some(related) => {
+                        if visited.insert(related) {
+                            open.push_back(related);
+                        }
+                    }
+                    None => {
+                        let middle = size / 2.0 + 0.5;
+                        let edge = size - 0.5;
+                        let position = match direction {
+                            Direction::Left => [0.5, middle],
+                            Direction::Right => [edge, middle],
+                            Direction::Top => [middle, 0.5],
+                            Direction::Bottom => [middle, edge],
+                        };
+                        return Some((id, position));
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+impl Behavior for Explore {
+    fn update(&mut self, context: &Context, output: &mut Output) -> bool {
+        let Some((chunk_id, position)) =
+            Self::frontier(context.field(), context.character.chunk_id)
+        else {
+            return false;
+        };
+        output.keys = self.navigator.go_to(context, chunk_id, position);
+        if self.navigator.arrived() {
+            // Blocked or already there, let the chunk arrive or try again later
+            self.navigator.reset();
+            return false;
+        }
+        true
+    }
+}
+
+// Follows the named player, or the nearest one in sight if no name is given
+pub struct Follow {
+    name: Option<String>,
+    range: f32,
+    navigator: Navigator,
+}
+
+impl Follow {
+    pub fn new(name: Option<String>, range: f32) -> Self {
+        Self {
+            name,
+            range,
+            navigator: Navigator::default(),
+        }
+    }
+}
+
+impl Behavior for Follow {
+    fn update(&mut self, context: &Context, output: &mut Output) -> bool {
+        let target = context
+            .others()
+            .filter(|(c, _, distance)| match &self.name {
+                Some(name) => c.name() == name,
+                None => *distance < self.range && context.can_see(c),
+            })
+            .min_by(|a, b| a.2.total_cmp(&b.2));
+        let Some((target, _, distance)) = target else {
+            self.navigator.reset();
+            return false;
+        };
+        // Close enough, wait there
+        if distance > 1.5 {
+            output.keys = self
+                .navigator
+                .go_to(context, target.chunk_id, target.position);
+        }
+        true
+    }
+}
+
+// Runs straight away from the characters in sight within the radius
+pub struct Flee {
+    radius: f32,
+}
+
+impl Flee {
+    pub fn new(radius: f32) -> Self {
+        Self { radius }
+    }
+}
+
+impl Behavior for Flee {
+    fn update(&mut self, context: &Context, output: &mut Output) -> bool {
+        let mut away = [0.0f32; 2];
+        let mut found = false;
+        for (c, position, distance) in context.others() {
+            if distance >= self.radius || distance == 0.0 || !context.can_see(c) {
+                continue;
+            }
+            found = true;
+            // Nearer characters matter more
+            for i in 0..2 {
+                away[i] += (context.character.position[i] - position[i]) / (distance * distance);
+            }
+        }
+        if !found {
+            return false;
+        }
+        let threshold = away[0].abs().max(away[1].abs()) * 0.4;
+        output.keys = [
+            away[1] < -threshold,
+            away[1] > threshold,
+            away[0] < -threshold,
+            away[0] > threshold,
+        ];
+        true
+    }
+}
+
+// Visits the waypoints in turn
+pub struct Patrol {
+    waypoints: Vec<(ChunkId, [f32; 2])>,
+    next: usize,
+    navigator: Navigator,
+}
+
+impl Patrol {
+    pub fn new(waypoints: Vec<(ChunkId, [f32; 2])>) -> Self {
+        Self {
+            waypoints,
+            next: 0,
+            navigator: Navigator::default(),
+        }
+    }
+}
+
+impl Behavior for Patrol {
+    fn update(&mut self, context: &Context, output: &mut Output) -> bool {
+        let Some(&(chunk_id, position)) = self.waypoints.get(self.next) else {
+            return false;
+        };
+        output.keys = self.navigator.go_to(context, chunk_id, position);
+        if self.navigator.arrived() {
+            self.next = (self.next + 1) % self.waypoints.len();
+            self.navigator.reset();
+        }
+        true
+    }
+}
+
+// Says one of the lines now and then, never taking control of the movement
+pub struct IdleChat {
+    lines: Vec<String>,
+    interval: f32,
+    remaining: f32,
+    rng: StdRng,
+}
+
+impl IdleChat {
+    pub fn new(lines: Vec<String>, interval: f32) -> Self {
+        let mut rng = StdRng::from_entropy();
+        Self {
+            remaining: rng.gen_range(0.0..interval.max(f32::EPSILON)),
+            lines,
+            interval,
+            rng,
+        }
+    }
+}
+
+impl Behavior for IdleChat {
+    fn update(&mut self, context: &Context, output: &mut Output) -> bool {
+        self.remaining -= context.dt;
+        if self.remaining <= 0.0 && !self.lines.is_empty() {
+            self.remaining = self.interval * self.rng.gen_range(0.5..1.5);
+            let line = &self.lines[self.rng.gen_range(0..self.lines.len())];
+            output.chat.push(line.clone());
+        }
+        false
+    }
+}
+
+// Walks to the nearest item in sight within the range, touching it picks it up
+pub struct Collect {
+    range: f32,
+    navigator: Navigator,
+}
+
+impl Collect {
+    pub fn new(range: f32) -> Self {
+        Self {
+            range,
+            navigator: Navigator::default(),
+        }
+    }
+}
+
+impl Behavior for Collect {
+    fn update(&mut self, context: &Context, output: &mut Output) -> bool {
+        let character = context.character;
+        let target = context
+            .game
+            .entities
+            .iter()
+            .filter(|e| e.kind == EntityKind::Item)
+            .filter_map(|e| e.transform)
+            .filter_map(|t| {
+                let position = context.field().position_in(
+                    character.chunk_id,
+                    t.chunk_id,
+                    t.position,
+                    character.position,
+                )?;
+                let distance = (position[0] - character.position[0])
+                    .hypot(position[1] - character.position[1]);
+                Some((t, distance))
+            })
+            .filter(|(t, distance)| {
+                *distance < self.range
+                    && context.field().line_of_sight(
+                        (character.chunk_id, character.position),
+                        (t.chunk_id, t.position),
+                        context.game.tiles(),
+                    )
+            })
+            .min_by(|a, b| a.1.total_cmp(&b.1));
+        let Some((target, _)) = target else {
+            self.navigator.reset();
+            return false;
+        };
+        output.keys = self
+            .navigator
+            .go_to(context, target.chunk_id, target.position);
+        true
+    }
+}
+
+// Stands still
+pub struct Idle;
+
+impl Behavior for Idle {
+    fn update(&mut self, _context: &Context, output: &mut Output) -> bool {
+        output.keys = [false; 4];
+        true
+    }
+}
+
+#[test]
+fn test() {
+    use cark_common::field::Field;
+
+    let mut game = Game::new(Default::default());
+    let mut field = Field::new();
+    for chunk in field.chunks.values_mut() {
+        chunk.walls.fill(cark_common::tile::VOID);
+    }
+    game.set_world(1, field);
+    let id = ChunkId::MIN;
+    game.characters = vec![
+        Character::new(1, "bot".to_string(), id, [8.5, 8.5]),
+        Character::new(2, "alice".to_string(), id, [10.5, 8.5]),
+    ];
+    game.player_id = 1;
+    let context = Context {
+        game: &game,
+        character: &game.characters[0],
+        dt: 1.0,
+    };
+
+    let mut output = Output::default();
+    assert!(Flee::new(4.0).update(&context, &mut output));
+    assert_eq!(output.keys, [false, false, true, false]);
+
+    let mut output = Output::default();
+    assert!(Follow::new(Some("alice".to_string()), 0.0).update(&context, &mut output));
+    assert_eq!(output.keys, [false, false, false, true]);
+    assert!(!Follow::new(Some("bob".to_string()), 0.0).update(&context, &mut output));
+
+    // The first behaviour that takes control wins, chatting doesn't take it
+    let mut selector = Selector::new(vec![
+        Box::new(IdleChat::new(vec!["hi".to_string()], 0.5)),
+        Box::new(Flee::new(1.0)),
+        Box::new(Idle),
+        Box::new(Wander::new()),
+    ]);
+    let mut output = Output {
+        keys: [true; 4],
+        ..Default::default()
+    };
+    assert!(selector.update(&context, &mut output));
+    assert_eq!(output.keys, [false; 4]);
+    assert_eq!(output.chat, vec!["hi".to_string()]);
+
+    // Nothing to collect until an item shows up
+    let mut output = Output::default();
+    assert!(!Collect::new(6.0).update(&context, &mut output));
+    game.entities
+        .insert(cark_common::entity::Entity::new(3, EntityKind::Item).with(
+            cark_common::entity::Component::Transform(cark_common::entity::Transform {
+                chunk_id: id,
+                position: [8.5, 6.5],
+            }),
+        ));
+    let context = Context {
+        game: &game,
+        character: &game.characters[0],
+        dt: 1.0,
+    };
+    let mut output = Output::default();
+    assert!(Collect::new(6.0).update(&context, &mut output));
+    assert_eq!(output.keys, [true, false, false, false]);
+
+    // A lone chunk is open all around
+    let mut output = Output::default();
+    assert!(Explore::new().update(&context, &mut output));
+    assert!(output.keys[0] || output.keys[1] || output.keys[2] || output.keys[3]);
+}
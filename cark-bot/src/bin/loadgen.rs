@@ -0,0 +1,59 @@
+use std::time::{Duration, Instant};
+
+use cark_bot::loadgen::{self, Summary};
+
+fn main() {
+    use simplelog::*;
+    TermLogger::init(
+        LevelFilter::Warn,
+        Config::default(),
+        TerminalMode::Mixed,
+        ColorChoice::Auto,
+    )
+    .unwrap();
+    log_panics::init();
+
+    let path = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "loadgen.toml".to_string());
+    let config = loadgen::load_config(&path);
+    println!("{:?}", &config);
+
+    let started = Instant::now();
+    let interval = if config.count > 1 {
+        Duration::from_secs_f32(config.ramp_up / (config.count - 1) as f32)
+    } else {
+        Duration::ZERO
+    };
+    let handles: Vec<_> = (0..config.count)
+        .map(|index| {
+            if index > 0 {
+                std::thread::sleep(interval);
+            }
+            let config = config.clone();
+            std::thread::spawn(move || loadgen::run_bot(&config, index))
+        })
+        .collect();
+
+    let bots: Vec<_> = handles
+        .into_iter()
+        .enumerate()
+        .map(|(index, handle)| {
+            handle.join().unwrap_or_else(|_| loadgen::BotMetrics {
+                name: format!("{}{}", config.name_prefix, index),
+                error: Some("Panicked".to_string()),
+                ..Default::default()
+            })
+        })
+        .collect();
+
+    let summary = Summary::new(&bots, started.elapsed());
+    println!("{}", summary.table());
+    match std::fs::write(
+        &config.output,
+        serde_json::to_string_pretty(&summary).unwrap(),
+    ) {
+        Ok(()) => println!("Report written to {}", config.output),
+        Err(e) => eprintln!("Failed to write {}: {}", config.output, e),
+    }
+}
@@ -1,3 +1,7 @@
+use cark_common::field::ChunkId;
+
+use crate::behavior::{self, Behavior};
+
 pub fn load_config() -> Config {
     let path = "cark.toml";
     let Ok(config) = std::fs::read_to_string(path) else {
@@ -7,9 +11,16 @@ pub fn load_config() -> Config {
 }
 
 #[derive(serde::Deserialize, Debug)]
+#[serde(default)]
 pub struct Config {
     pub server_tcp_addr: String,
     pub server_udp_addr: String,
+    // Local address to bind for UDP, e.g. "0.0.0.0:34254", any port if not given
+    pub udp_local_addr: Option<String>,
+    pub name: String,
+    pub password: String,
+    // In order of priority, the first one that wants to move the bot does
+    pub behaviors: Vec<BehaviorConfig>,
 }
 
 impl Default for Config {
@@ -17,6 +28,123 @@ impl Default for Config {
         Self {
             server_tcp_addr: "127.0.0.1:8080".to_string(),
             server_udp_addr: "127.0.0.1:8081".to_string(),
+            udp_local_addr: None,
+            name: "NPC".to_string(),
+            password: String::new(),
+            behaviors: vec![
+                BehaviorConfig::Follow {
+                    name: None,
+                    range: default_range(),
+                },
+                BehaviorConfig::Collect {
+                    range: default_range(),
+                },
+                BehaviorConfig::Wander,
+            ],
+        }
+    }
+}
+
+impl Config {
+    pub fn behavior(&self) -> behavior::Selector {
+        behavior::Selector::new(self.behaviors.iter().map(|b| b.build()).collect())
+    }
+}
+
+// e.g.
+// [[behaviors]]
+// type = "flee"
+// radius = 4.0
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BehaviorConfig {
+    Wander,
+    Explore,
+    Follow {
+        // The nearest player in sight if not given
+        #[serde(default)]
+        name: Option<String>,
+        #[serde(default = "default_range")]
+        range: f32,
+    },
+    Flee {
+        #[serde(default = "default_range")]
+        radius: f32,
+    },
+    Patrol {
+        // [[chunk id, [x, y]], ...]
+        waypoints: Vec<(u32, [f32; 2])>,
+    },
+    IdleChat {
+        lines: Vec<String>,
+        // Average seconds between lines
+        #[serde(default = "default_chat_interval")]
+        interval: f32,
+    },
+    // Picks up the items lying in sight
+    Collect {
+        #[serde(default = "default_range")]
+        range: f32,
+    },
+    Idle,
+}
+
+fn default_range() -> f32 {
+    6.0
+}
+
+fn default_chat_interval() -> f32 {
+    20.0
+}
+
+impl BehaviorConfig {
+    pub fn build(&self) -> Box<dyn Behavior> {
+        match self {
+            Self::Wander => Box::new(behavior::Wander::new()),
+            Self::Explore => Box::new(behavior::Explore::new()),
+            Self::Follow { name, range } => Box::new(behavior::Follow::new(name.clone(), *range)),
+            Self::Flee { radius } => Box::new(behavior::Flee::new(*radius)),
+            Self::Patrol { waypoints } => Box::new(behavior::Patrol::new(
+                waypoints
+                    .iter()
+                    .filter_map(|(id, position)| Some((ChunkId::new(*id)?, *position)))
+                    .collect(),
+            )),
+            Self::IdleChat { lines, interval } => {
+                Box::new(behavior::IdleChat::new(lines.clone(), *interval))
+            }
+            Self::Collect { range } => Box::new(behavior::Collect::new(*range)),
+            Self::Idle => Box::new(behavior::Idle),
         }
     }
 }
+
+#[test]
+fn test() {
+    let config: Config = toml::from_str(
+        r#"
+        name = "guard"
+
+        [[behaviors]]
+        type = "idle_chat"
+        lines = ["Halt!"]
+
+        [[behaviors]]
+        type = "flee"
+        radius = 3.0
+
+        [[behaviors]]
+        type = "patrol"
+        waypoints = [[1, [2.5, 2.5]], [1, [12.5, 2.5]]]
+        "#,
+    )
+    .unwrap();
+    assert_eq!(config.name, "guard");
+    assert_eq!(config.server_tcp_addr, Config::default().server_tcp_addr);
+    assert_eq!(config.behaviors.len(), 3);
+    assert!(matches!(
+        config.behaviors[1],
+        BehaviorConfig::Flee { radius } if radius == 3.0
+    ));
+    config.behavior();
+}
@@ -0,0 +1,346 @@
+// Runs many bots in one process and reports how the server coped with them.
+// Each bot has its own thread, connections and game state.
+// Raise MAX_CONNECTIONS_PER_IP and MAX_JOINS_PER_MINUTE of the server for more than a few bots.
+
+use std::time::{Duration, Instant};
+
+use cark_client::{client::Client, communication::Communication, udp::UdpState, Input};
+
+use crate::config::BehaviorConfig;
+
+pub fn load_config(path: &str) -> LoadgenConfig {
+    let Ok(config) = std::fs::read_to_string(path) else {
+        log::info!("{} not found, using the default config", path);
+        return LoadgenConfig::default();
+    };
+    toml::from_str(&config).unwrap()
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LoadgenConfig {
+    pub server_tcp_addr: String,
+    pub server_udp_addr: String,
+    // Number of bots
+    pub count: usize,
+    // Seconds over which the bots join, evenly spaced
+    pub ramp_up: f32,
+    // Seconds each bot stays after joining
+    pub duration: f32,
+    pub ups: f32,
+    // Bots are named `{name_prefix}{index}`
+    pub name_prefix: String,
+    // Shared by all bots
+    pub password: String,
+    // Bots are given the behaviours of the mix entries in proportion to their weights
+    pub mix: Vec<MixEntry>,
+    // Where the JSON report is written
+    pub output: String,
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct MixEntry {
+    #[serde(default = "default_weight")]
+    pub weight: usize,
+    pub behaviors: Vec<BehaviorConfig>,
+}
+
+fn default_weight() -> usize {
+    1
+}
+
+impl Default for LoadgenConfig {
+    fn default() -> Self {
+        Self {
+            server_tcp_addr: "127.0.0.1:8080".to_string(),
+            server_udp_addr: "127.0.0.1:8081".to_string(),
+            count: 10,
+            ramp_up: 5.0,
+            duration: 30.0,
+            ups: 60.0,
+            name_prefix: "bot".to_string(),
+            password: String::new(),
+            mix: vec![
+                MixEntry {
+                    weight: 3,
+                    behaviors: vec![BehaviorConfig::Wander],
+                },
+                MixEntry {
+                    weight: 1,
+                    behaviors: vec![BehaviorConfig::Explore, BehaviorConfig::Wander],
+                },
+            ],
+            output: "loadgen.json".to_string(),
+        }
+    }
+}
+
+impl LoadgenConfig {
+    // Behaviours of the `index`th bot
+    pub fn behaviors_for(&self, index: usize) -> Vec<BehaviorConfig> {
+        let total: usize = self.mix.iter().map(|m| m.weight).sum();
+        if total == 0 {
+            return vec![];
+        }
+        let mut slot = index % total;
+        for entry in &self.mix {
+            if slot < entry.weight {
+                return entry.behaviors.clone();
+            }
+            slot -= entry.weight;
+        }
+        unreachable!()
+    }
+}
+
+// What a single bot saw
+#[derive(Debug, Default, Clone)]
+pub struct BotMetrics {
+    pub name: String,
+    pub error: Option<String>,
+    pub join_latency: Option<Duration>,
+    pub chunk_latencies: Vec<Duration>,
+    pub udp_sent: u64,
+    pub udp_received: u64,
+    // Ended up sending over TCP as UDP didn't come through
+    pub udp_fallback: bool,
+    pub messages_sent: u64,
+    pub messages_received: u64,
+}
+
+pub fn run_bot(config: &LoadgenConfig, index: usize) -> BotMetrics {
+    let name = format!("{}{}", config.name_prefix, index);
+    let mut metrics = BotMetrics {
+        name: name.clone(),
+        ..Default::default()
+    };
+
+    let communication =
+        match Communication::new(&config.server_tcp_addr, &config.server_udp_addr, None) {
+            Ok(communication) => communication,
+            Err(e) => {
+                metrics.error = Some(e.to_string());
+                return metrics;
+            }
+        };
+    let started = Instant::now();
+    let mut client = Client::new(
+        communication,
+        name,
+        config.password.clone(),
+        Default::default(),
+    );
+    let behavior = crate::behavior::Selector::new(
+        config
+            .behaviors_for(index)
+            .iter()
+            .map(|b| b.build())
+            .collect(),
+    );
+    let mut system = crate::system_bot(behavior);
+    let mut input = Input::new();
+    let frame = Duration::from_secs_f32(1.0 / config.ups);
+    let duration = Duration::from_secs_f32(config.duration);
+
+    let mut last = Instant::now();
+    loop {
+        let now = Instant::now();
+        input.dt = now.duration_since(last).as_secs_f32();
+        last = now;
+
+        if let Some(reason) = &client.game.join_rejected {
+            metrics.error = Some(format!("Join rejected: {}", reason));
+            break;
+        }
+        if metrics.join_latency.is_none() && client.game.player_character().is_some() {
+            metrics.join_latency = Some(started.elapsed());
+        }
+        let joined_at = metrics.join_latency.map(|latency| started + latency);
+        if joined_at.map_or(started.elapsed() > duration, |t| t.elapsed() > duration) {
+            break;
+        }
+
+        system(&client.game, &mut input, &mut client.communication);
+        client.process(&input);
+        input.reset();
+        metrics
+            .chunk_latencies
+            .extend(client.game.chunks_mut().take_request_latencies());
+
+        std::thread::sleep(frame.saturating_sub(now.elapsed()));
+    }
+
+    if metrics.join_latency.is_none() && metrics.error.is_none() {
+        metrics.error = Some("Timed out joining".to_string());
+    }
+    let stat = client.communication.udp.stat();
+    metrics.udp_sent = stat.sent;
+    metrics.udp_received = stat.received;
+    metrics.udp_fallback = client.communication.udp.state() == UdpState::Blocked;
+    (metrics.messages_sent, metrics.messages_received) = client.communication.message_counts();
+    metrics
+}
+
+#[derive(serde::Serialize, Debug, Default, Clone, PartialEq)]
+pub struct LatencyStats {
+    pub count: usize,
+    pub min_ms: f64,
+    pub mean_ms: f64,
+    pub p50_ms: f64,
+    pub p95_ms: f64,
+    pub max_ms: f64,
+}
+
+impl LatencyStats {
+    pub fn new(latencies: &[Duration]) -> Self {
+        if latencies.is_empty() {
+            return Self::default();
+        }
+        let mut ms: Vec<f64> = latencies.iter().map(|d| d.as_secs_f64() * 1000.0).collect();
+        ms.sort_by(f64::total_cmp);
+        let percentile = |p: f64| ms[((ms.len() - 1) as f64 * p).round() as usize];
+        Self {
+            count: ms.len(),
+            min_ms: ms[0],
+            mean_ms: ms.iter().sum::<f64>() / ms.len() as f64,
+            p50_ms: percentile(0.5),
+            p95_ms: percentile(0.95),
+            max_ms: ms[ms.len() - 1],
+        }
+    }
+}
+
+#[derive(serde::Serialize, Debug, Default, Clone, PartialEq)]
+pub struct Summary {
+    pub bots: usize,
+    pub joined: usize,
+    pub errors: Vec<String>,
+    pub elapsed_secs: f64,
+    pub join_latency: LatencyStats,
+    pub chunk_latency: LatencyStats,
+    pub udp_sent: u64,
+    pub udp_received: u64,
+    pub udp_loss_rate: f64,
+    // Bots that sent over TCP as UDP didn't come through
+    pub udp_fallbacks: usize,
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    pub messages_sent_per_sec: f64,
+    pub messages_received_per_sec: f64,
+}
+
+impl Summary {
+    pub fn new(bots: &[BotMetrics], elapsed: Duration) -> Self {
+        let join_latencies: Vec<_> = bots.iter().filter_map(|b| b.join_latency).collect();
+        let chunk_latencies: Vec<_> = bots
+            .iter()
+            .flat_map(|b| b.chunk_latencies.iter().copied())
+            .collect();
+        let udp_sent = bots.iter().map(|b| b.udp_sent).sum();
+        let udp_received = bots.iter().map(|b| b.udp_received).sum();
+        let messages_sent = bots.iter().map(|b| b.messages_sent).sum();
+        let messages_received = bots.iter().map(|b| b.messages_received).sum();
+        let secs = elapsed.as_secs_f64().max(f64::EPSILON);
+        Self {
+            bots: bots.len(),
+            joined: join_latencies.len(),
+            errors: bots
+                .iter()
+                .filter_map(|b| Some(format!("{}: {}", b.name, b.error.as_ref()?)))
+                .collect(),
+            elapsed_secs: elapsed.as_secs_f64(),
+            join_latency: LatencyStats::new(&join_latencies),
+            chunk_latency: LatencyStats::new(&chunk_latencies),
+            udp_sent,
+            udp_received,
+            udp_loss_rate: if udp_sent == 0 {
+                0.0
+            } else {
+                (udp_sent - udp_received) as f64 / udp_sent as f64
+            },
+            udp_fallbacks: bots.iter().filter(|b| b.udp_fallback).count(),
+            messages_sent,
+            messages_received,
+            messages_sent_per_sec: messages_sent as f64 / secs,
+            messages_received_per_sec: messages_received as f64 / secs,
+        }
+    }
+
+    pub fn table(&self) -> String {
+        let mut lines = vec![
+            format!("{:<22} {:>10}", "bots", self.bots),
+            format!("{:<22} {:>10}", "joined", self.joined),
+            format!("{:<22} {:>10}", "errors", self.errors.len()),
+            format!("{:<22} {:>10.1}", "elapsed (s)", self.elapsed_secs),
+            format!(
+                "{:<22} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+                "latency (ms)", "count", "min", "mean", "p50", "p95", "max"
+            ),
+        ];
+        for (name, stats) in [("join", &self.join_latency), ("chunk", &self.chunk_latency)] {
+            lines.push(format!(
+                "{:<22} {:>10} {:>10.1} {:>10.1} {:>10.1} {:>10.1} {:>10.1}",
+                name,
+                stats.count,
+                stats.min_ms,
+                stats.mean_ms,
+                stats.p50_ms,
+                stats.p95_ms,
+                stats.max_ms
+            ));
+        }
+        lines.extend([
+            format!("{:<22} {:>10}", "udp sent", self.udp_sent),
+            format!("{:<22} {:>10}", "udp received", self.udp_received),
+            format!("{:<22} {:>9.2}%", "udp loss", self.udp_loss_rate * 100.0),
+            format!("{:<22} {:>10}", "udp fallbacks", self.udp_fallbacks),
+            format!(
+                "{:<22} {:>10} {:>10.1}/s",
+                "messages sent", self.messages_sent, self.messages_sent_per_sec
+            ),
+            format!(
+                "{:<22} {:>10} {:>10.1}/s",
+                "messages received", self.messages_received, self.messages_received_per_sec
+            ),
+        ]);
+        lines.extend(self.errors.iter().map(|e| format!("error: {}", e)));
+        lines.join("\n")
+    }
+}
+
+#[test]
+fn test() {
+    let config = LoadgenConfig::default();
+    let behaviors: Vec<_> = (0..8).map(|i| config.behaviors_for(i).len()).collect();
+    assert_eq!(behaviors, [1, 1, 1, 2, 1, 1, 1, 2]);
+
+    let ms = Duration::from_millis;
+    let bots = [
+        BotMetrics {
+            name: "bot0".to_string(),
+            join_latency: Some(ms(10)),
+            chunk_latencies: vec![ms(1), ms(3)],
+            udp_sent: 100,
+            udp_received: 90,
+            udp_fallback: true,
+            messages_sent: 20,
+            messages_received: 200,
+            ..Default::default()
+        },
+        BotMetrics {
+            name: "bot1".to_string(),
+            error: Some("Timed out joining".to_string()),
+            ..Default::default()
+        },
+    ];
+    let summary = Summary::new(&bots, Duration::from_secs(10));
+    assert_eq!(summary.joined, 1);
+    assert_eq!(summary.errors, vec!["bot1: Timed out joining".to_string()]);
+    assert_eq!(summary.chunk_latency.count, 2);
+    assert_eq!(summary.chunk_latency.mean_ms, 2.0);
+    assert!((summary.udp_loss_rate - 0.1).abs() < 1e-9);
+    assert_eq!(summary.udp_fallbacks, 1);
+    assert_eq!(summary.messages_received_per_sec, 20.0);
+    assert!(summary.table().contains("bot1: Timed out joining"));
+    serde_json::to_string(&summary).unwrap();
+}
@@ -7,19 +7,25 @@ fn main() {
     let communication = cark_client::communication::Communication::new(
         &config.server_tcp_addr,
         &config.server_udp_addr,
+        config.udp_local_addr.as_deref(),
     )
     .unwrap();
-    let mut client = cark_client::client::Client::new(communication, "NPC".to_string());
+    let mut client = cark_client::client::Client::new(
+        communication,
+        config.name.clone(),
+        config.password.clone(),
+        Default::default(),
+    );
     let mut input = cark_client::Input::new();
 
-    let mut system = cark_bot::system_bot();
+    let mut system = cark_bot::system_bot(config.behavior());
 
     let ups = 60.0f32;
 
     loop {
         input.dt = 1.0 / ups;
 
-        system(&client.game, &mut input);
+        system(&client.game, &mut input, &mut client.communication);
         client.process(&input);
 
         input.reset();
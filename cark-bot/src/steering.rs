@@ -0,0 +1,121 @@
+use std::collections::VecDeque;
+
+use cark_client::Input;
+use cark_common::field::{ChunkId, Field};
+
+// Up, down, left, right as in `Input`
+pub type Keys = [bool; 4];
+
+// Distance at which a waypoint counts as reached
+const REACHED: f32 = 0.3;
+// Offsets smaller than this don't press a key, to avoid wobbling around the line
+const DEAD_ZONE: f32 = 0.15;
+
+// Turns the keys to hold into key down/up events
+#[derive(Default)]
+pub struct Steering {
+    held: Keys,
+}
+
+impl Steering {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn press(&mut self, input: &mut Input, keys: Keys) {
+        for (i, (&key, &held)) in keys.iter().zip(&self.held).enumerate() {
+            if key && !held {
+                input.key_down[i] = true;
+            }
+            if !key && held {
+                input.key_up[i] = true;
+            }
+        }
+        self.held = keys;
+    }
+
+    pub fn release(&mut self, input: &mut Input) {
+        self.press(input, [false; 4]);
+    }
+}
+
+// Walks through the waypoints given by `cark_common::path::find_path`
+#[derive(Default)]
+pub struct PathFollower {
+    waypoints: VecDeque<(ChunkId, [f32; 2])>,
+}
+
+impl PathFollower {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_path(&mut self, waypoints: Vec<(ChunkId, [f32; 2])>) {
+        self.waypoints = waypoints.into();
+    }
+
+    pub fn clear(&mut self) {
+        self.waypoints.clear();
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.waypoints.is_empty()
+    }
+
+    // Keys to hold to get to the next waypoint from the position
+    pub fn keys(&mut self, field: &Field, chunk_id: ChunkId, position: [f32; 2]) -> Keys {
+        while let Some(&(waypoint_chunk, waypoint)) = self.waypoints.front() {
+            let Some(target) = field.position_in(chunk_id, waypoint_chunk, waypoint, position)
+            else {
+                // Too far away from the path
+                self.waypoints.clear();
+                break;
+            };
+            let dx = target[0] - position[0];
+            let dy = target[1] - position[1];
+            if dx * dx + dy * dy < REACHED * REACHED {
+                self.waypoints.pop_front();
+                continue;
+            }
+            return [
+                dy < -DEAD_ZONE,
+                dy > DEAD_ZONE,
+                dx < -DEAD_ZONE,
+                dx > DEAD_ZONE,
+            ];
+        }
+        [false; 4]
+    }
+}
+
+#[test]
+fn test() {
+    let mut field = Field::new();
+    let a = ChunkId::MIN;
+    let b = field
+        .generate_chunk(a, cark_common::direction::Direction::Right)
+        .unwrap();
+
+    let mut follower = PathFollower::new();
+    follower.set_path(vec![(a, [15.5, 4.5]), (b, [0.5, 5.5])]);
+    assert_eq!(
+        follower.keys(&field, a, [14.5, 4.5]),
+        [false, false, false, true]
+    );
+    // The first waypoint is reached, then heads down right across the chunk edge
+    assert_eq!(
+        follower.keys(&field, a, [15.4, 4.5]),
+        [false, true, false, true]
+    );
+    assert_eq!(follower.keys(&field, b, [0.5, 5.4]), [false; 4]);
+    assert!(follower.is_done());
+
+    let mut steering = Steering::new();
+    let mut input = Input::new();
+    steering.press(&mut input, [true, false, false, true]);
+    assert_eq!(input.key_down[..4], [true, false, false, true]);
+    input.reset();
+    steering.press(&mut input, [true, false, false, false]);
+    assert_eq!(input.key_down[..4], [false; 4]);
+    assert_eq!(input.key_up[..4], [false, false, false, true]);
+}
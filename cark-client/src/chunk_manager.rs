@@ -0,0 +1,347 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use cark_common::{
+    direction::Direction,
+    field::{Chunk, ChunkChange, ChunkId, Field, Layer},
+    model::ClientMessage,
+};
+
+// Seconds to wait for a response before requesting a chunk again
+const REQUEST_TIMEOUT: f32 = 2.0;
+
+#[derive(Debug, Clone)]
+pub struct ChunkManagerConfig {
+    // Chunks within this many steps from the player are requested and kept
+    pub radius: usize,
+    // Number of chunks outside the radius that are kept in memory
+    pub capacity: usize,
+    // Where received chunks are stored between sessions
+    pub cache_dir: Option<PathBuf>,
+}
+
+impl Default for ChunkManagerConfig {
+    fn default() -> Self {
+        Self {
+            radius: 2,
+            capacity: 64,
+            cache_dir: None,
+        }
+    }
+}
+
+// Owns the client's copy of the field.
+// Requests the chunks around the player, evicts the least recently used ones
+// outside the radius and keeps received chunks on disk.
+pub struct ChunkManager {
+    config: ChunkManagerConfig,
+    field: Field,
+    world_id: Option<u64>,
+    tick: u64,
+    last_used: HashMap<ChunkId, u64>,
+    // (chunk id, direction) => remaining time
+    pending: HashMap<(ChunkId, Direction), f32>,
+    requested_at: HashMap<(ChunkId, Direction), Instant>,
+    // Time from requesting chunks to receiving them, until taken
+    latencies: Vec<Duration>,
+}
+
+impl ChunkManager {
+    pub fn new(config: ChunkManagerConfig) -> Self {
+        Self {
+            config,
+            field: Field::new(),
+            world_id: None,
+            tick: 0,
+            last_used: HashMap::new(),
+            pending: HashMap::new(),
+            requested_at: HashMap::new(),
+            latencies: vec![],
+        }
+    }
+
+    pub fn config(&self) -> &ChunkManagerConfig {
+        &self.config
+    }
+
+    pub fn field(&self) -> &Field {
+        &self.field
+    }
+
+    pub fn reset(&mut self, world_id: Option<u64>, field: Field) {
+        self.world_id = world_id;
+        self.field = field;
+        self.last_used.clear();
+        self.pending.clear();
+        self.requested_at.clear();
+    }
+
+    pub fn insert(&mut self, chunk: Chunk) {
+        let id = chunk.id;
+        self.save(&chunk);
+        self.last_used.insert(id, self.tick);
+        self.field.set_existed_chunk(chunk, true);
+
+        let field = &self.field;
+        let latencies = &mut self.latencies;
+        self.requested_at.retain(|(from, direction), requested_at| {
+            let answered = field
+                .chunk(*from)
+                .is_some_and(|c| c.related[direction.to_number()] == id.get());
+            if answered {
+                latencies.push(requested_at.elapsed());
+            }
+            !answered
+        });
+    }
+
+    pub fn take_request_latencies(&mut self) -> Vec<Duration> {
+        std::mem::take(&mut self.latencies)
+    }
+
+    // Returns false if the local copy is missing or of another version
+    pub fn apply_diff(
+        &mut self,
+        id: ChunkId,
+        from_version: u64,
+        to_version: u64,
+        changes: &[ChunkChange],
+    ) -> bool {
+        if !self.field.apply_diff(id, from_version, to_version, changes) {
+            return false;
+        }
+        self.save(self.field.chunk(id).unwrap());
+        true
+    }
+
+    pub fn update(&mut self, center: ChunkId, dt: f32, mut request: impl FnMut(ClientMessage)) {
+        self.tick += 1;
+        self.pending.retain(|_, time| {
+            *time -= dt;
+            *time > 0.0
+        });
+        let pending = &self.pending;
+        self.requested_at.retain(|key, _| pending.contains_key(key));
+
+        let distances = self.distances(center);
+        let mut keep: HashSet<_> = distances.keys().copied().collect();
+        for id in &keep {
+            self.last_used.insert(*id, self.tick);
+        }
+
+        // Request the missing neighbors of the chunks inside the radius
+        let mut missing = vec![];
+        for (&id, &distance) in &distances {
+            if distance >= self.config.radius {
+                continue;
+            }
+            let chunk = self.field.chunk(id).unwrap();
+            for direction in Direction::ALL {
+                let related = ChunkId::new(chunk.related[direction.to_number()]);
+                if related.is_some_and(|id| self.field.chunk(id).is_some()) {
+                    continue;
+                }
+                if self.pending.contains_key(&(id, direction)) {
+                    continue;
+                }
+                missing.push((id, direction, related));
+            }
+        }
+        for (id, direction, related) in missing {
+            // Show the cached copy until the server brings it up to date
+            if let Some(chunk) = related.and_then(|id| self.load(id)) {
+                log::info!(
+                    "Syncing cached chunk: id = {:?}, version = {}",
+                    chunk.id,
+                    chunk.version
+                );
+                request(ClientMessage::SyncChunk {
+                    id: chunk.id,
+                    known_version: chunk.version,
+                });
+                keep.insert(chunk.id);
+                self.last_used.insert(chunk.id, self.tick);
+                self.field.set_existed_chunk(chunk, true);
+            } else {
+                log::info!(
+                    "Requesting chunk: id = {:?}, direction = {:?}",
+                    id,
+                    direction
+                );
+                request(ClientMessage::RequestChunk { id, direction });
+                self.requested_at.insert((id, direction), Instant::now());
+            }
+            self.pending.insert((id, direction), REQUEST_TIMEOUT);
+        }
+
+        self.evict(&keep);
+    }
+
+    // Steps from `center` to every loaded chunk within the radius
+    fn distances(&self, center: ChunkId) -> HashMap<ChunkId, usize> {
+        let mut distances = HashMap::new();
+        if self.field.chunk(center).is_none() {
+            return distances;
+        }
+        distances.insert(center, 0);
+        let mut open = VecDeque::from([center]);
+        while let Some(id) = open.pop_front() {
+            let distance = distances[&id];
+            if distance >= self.config.radius {
+                continue;
+            }
+            for related in self.field.chunk(id).unwrap().related {
+                let Some(related) = ChunkId::new(related) else {
+                    continue;
+                };
+                if self.field.chunk(related).is_some() && !distances.contains_key(&related) {
+                    distances.insert(related, distance + 1);
+                    open.push_back(related);
+                }
+            }
+        }
+        distances
+    }
+
+    fn evict(&mut self, keep: &HashSet<ChunkId>) {
+        let mut candidates: Vec<_> = self
+            .field
+            .chunks
+            .keys()
+            .filter(|id| !keep.contains(id))
+            .map(|id| (self.last_used.get(id).copied().unwrap_or_default(), *id))
+            .collect();
+        if candidates.len() <= self.config.capacity {
+            return;
+        }
+        candidates.sort();
+        for (_, id) in &candidates[..candidates.len() - self.config.capacity] {
+            log::debug!("Chunk evicted: id = {:?}", id);
+            self.field.remove_chunk(*id);
+            self.last_used.remove(id);
+        }
+    }
+
+    fn cache_path(&self, id: ChunkId) -> Option<PathBuf> {
+        let dir = self.config.cache_dir.as_ref()?;
+        let world_id = self.world_id?;
+        Some(
+            dir.join(format!("{:016x}", world_id))
+                .join(format!("{}.chunk", id)),
+        )
+    }
+
+    fn save(&self, chunk: &Chunk) {
+        let Some(path) = self.cache_path(chunk.id) else {
+            return;
+        };
+        let result = std::fs::create_dir_all(path.parent().unwrap())
+            .and_then(|_| std::fs::File::create(&path))
+            .and_then(|file| {
+                cark_common::write(chunk, file)
+                    .map(|_| ())
+                    .map_err(std::io::Error::other)
+            });
+        if let Err(e) = result {
+            log::warn!("Failed to cache chunk: path = {:?}, {}", path, e);
+        }
+    }
+
+    fn load(&self, id: ChunkId) -> Option<Chunk> {
+        let path = self.cache_path(id)?;
+        let data = std::fs::read(&path).ok()?;
+        match cark_common::read_from_slice::<Chunk>(&data) {
+            Ok(chunk) if chunk.id == id && is_complete(&chunk, self.field.chunk_size()) => {
+                Some(chunk)
+            }
+            _ => {
+                log::warn!("Invalid cached chunk: path = {:?}", path);
+                None
+            }
+        }
+    }
+}
+
+// Whether every tile of a chunk of the size is there, files may be truncated or edited
+fn is_complete(chunk: &Chunk, size: usize) -> bool {
+    let len = size * size;
+    chunk.size == size
+        && Layer::ALL
+            .iter()
+            .all(|&layer| chunk.layer(layer).len() == len)
+        && chunk.meta.len() == len
+}
+
+#[test]
+fn test() {
+    let dir = std::env::temp_dir().join(format!("cark-chunk-cache-{}", std::process::id()));
+    let mut manager = ChunkManager::new(ChunkManagerConfig {
+        radius: 1,
+        capacity: 1,
+        cache_dir: Some(dir.clone()),
+    });
+    manager.reset(Some(1), Field::new());
+
+    // The server side of the world
+    let mut world = Field::new();
+    let center = ChunkId::MIN;
+    let mut requests = vec![];
+    manager.update(center, 0.1, |m| requests.push(m));
+    assert_eq!(requests.len(), 4);
+
+    // Nothing is requested again while waiting for the response
+    manager.update(center, 0.1, |m| requests.push(m));
+    assert_eq!(requests.len(), 4);
+
+    for request in requests.drain(..) {
+        let ClientMessage::RequestChunk { id, direction, .. } = request else {
+            unreachable!();
+        };
+        let new_id = world.generate_chunk(id, direction).unwrap();
+        manager.insert(world.chunk(new_id).unwrap().clone());
+    }
+    assert_eq!(manager.field().chunks.len(), 5);
+    assert_eq!(manager.take_request_latencies().len(), 4);
+
+    // Moving away evicts the chunks outside the radius but the last used one
+    let right =
+        ChunkId::new(world.chunk(center).unwrap().related[Direction::Right.to_number()]).unwrap();
+    manager.update(right, 0.1, |m| requests.push(m));
+    assert_eq!(manager.field().chunks.len(), 3);
+    assert!(manager.field().chunk(center).is_some());
+
+    // Coming back loads the evicted chunks from the disk
+    requests.clear();
+    manager.update(center, REQUEST_TIMEOUT, |m| requests.push(m));
+    assert_eq!(requests.len(), 2);
+    assert!(requests.iter().all(|m| matches!(
+        m,
+        ClientMessage::SyncChunk {
+            known_version: 0,
+            ..
+        }
+    )));
+    assert_eq!(manager.field().chunks.len(), 5);
+
+    // Diffs are applied only on top of the version they were made from
+    let left =
+        ChunkId::new(world.chunk(center).unwrap().related[Direction::Left.to_number()]).unwrap();
+    let change = ChunkChange::Meta { index: 0, value: 1 };
+    assert!(!manager.apply_diff(left, 1, 2, std::slice::from_ref(&change)));
+    assert!(manager.apply_diff(left, 0, 1, &[change]));
+    assert_eq!(manager.field().chunk(left).unwrap().version, 1);
+    assert_eq!(manager.field().chunk(left).unwrap().meta[0], 1);
+
+    // Cached chunks missing tiles are not loaded
+    let mut chunk = world.chunk(center).unwrap().clone();
+    manager.save(&chunk);
+    assert!(manager.load(center).is_some());
+    chunk.walls.pop();
+    manager.save(&chunk);
+    assert!(manager.load(center).is_none());
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
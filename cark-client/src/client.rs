@@ -1,6 +1,7 @@
-use cark_common::model::ServerMessage;
+use cark_common::{field::Field, model::ServerMessage, physics::Body};
 
 use crate::{
+    chunk_manager::ChunkManagerConfig,
     communication::Communication,
     game::{Character, Game},
     systems, Input,
@@ -13,18 +14,24 @@ pub struct Client {
 }
 
 impl Client {
-    pub fn new(mut communication: Communication, name: String) -> Self {
+    pub fn new(
+        mut communication: Communication,
+        name: String,
+        password: String,
+        chunk_config: ChunkManagerConfig,
+    ) -> Self {
         communication.push_tcp_event(cark_common::model::ClientMessage::Join(
-            cark_common::model::Join { name },
+            cark_common::model::Join { name, password },
         ));
         Self {
             communication,
-            game: Game::new(),
+            game: Game::new(chunk_config),
             systems: vec![
                 Box::new(systems::system_player_move()),
                 Box::new(systems::system_player_action_push()),
+                Box::new(systems::system_inventory()),
                 Box::new(systems::system_compute_ups()),
-                Box::new(systems::system_chunk_retriever()),
+                Box::new(systems::system_chunk_manager()),
             ],
         }
     }
@@ -35,15 +42,12 @@ impl Client {
         }
 
         let incoming_events = self.communication.process().unwrap();
+        self.game.transport = self.communication.transport();
 
         for event in incoming_events {
             // XXX
             if let cark_common::model::ServerMessage::Joined(joined) = &event {
-                self.communication
-                    .udp
-                    .send_init(joined.user_id)
-                    .or_else(map_err)
-                    .unwrap();
+                self.communication.udp.send_init(joined.udp_token);
             }
 
             handle_event(event, &mut self.game, &mut self.communication);
@@ -51,15 +55,34 @@ impl Client {
     }
 }
 
-fn handle_event(event: ServerMessage, game: &mut Game, mut comm: &mut Communication) {
+fn handle_event(event: ServerMessage, game: &mut Game, comm: &mut Communication) {
     match event {
         ServerMessage::Joined(joined) => {
+            // No chunk can be built, the server never uses such a size
+            if joined.chunk_size == 0 {
+                log::error!("Join rejected: chunk_size = {}", joined.chunk_size);
+                game.join_rejected = Some("Invalid chunk size".to_string());
+                return;
+            }
+            game.set_world(joined.world_id, Field::with_chunk_size(joined.chunk_size));
+            game.set_tiles(joined.tiles);
+            game.set_items(joined.items);
+            game.settings = joined.settings;
+            game.join_rejected = None;
             game.update_chunk(joined.chunk);
             game.characters = joined
                 .characters
                 .into_iter()
-                .map(|c| Character::new(c.id, c.name, c.chunk_id, c.position))
+                .map(|c| {
+                    let mut character = Character::new(c.id, c.name, c.chunk_id, c.position);
+                    character.npc = c.npc;
+                    character
+                })
                 .collect();
+            game.entities.clear();
+            for entity in joined.entities {
+                game.entities.insert(entity);
+            }
             game.player_id = joined.user_id;
         }
         // ServerMessage::UpdateField(update_field) => {
@@ -84,17 +107,36 @@ fn handle_event(event: ServerMessage, game: &mut Game, mut comm: &mut Communicat
                 character.velocity = velocity;
             }
         }
+        ServerMessage::Ping { nonce } => {
+            comm.push_udp_event(cark_common::model::ClientMessage::Pong { nonce });
+        }
+        ServerMessage::Notice { text } => {
+            log::info!("Notice: {}", text);
+            game.push_notice(text);
+        }
+        ServerMessage::Teleport { chunk_id, position } => {
+            let player_id = game.player_id;
+            if let Some(character) = game.characters.iter_mut().find(|c| c.id() == player_id) {
+                character.set_body(Body::new(chunk_id, position, [0.0, 0.0]));
+            }
+        }
+        ServerMessage::JoinRejected { reason } => {
+            log::error!("Join rejected: {}", reason);
+            game.join_rejected = Some(reason);
+        }
         ServerMessage::PlayerJoined {
             id,
             name,
             chunk_id,
             position,
+            npc,
         } => {
             if game.characters.iter().any(|c| c.id() == id) {
                 return;
             }
-            game.characters
-                .push(Character::new(id, name, chunk_id, position));
+            let mut character = Character::new(id, name, chunk_id, position);
+            character.npc = npc;
+            game.characters.push(character);
         }
         ServerMessage::PlayerLeft { user_id } => {
             game.characters.retain(|c| c.id() != user_id);
@@ -103,13 +145,45 @@ fn handle_event(event: ServerMessage, game: &mut Game, mut comm: &mut Communicat
             log::info!("Chunk received: id = {:?}", chunk.id);
             game.update_chunk(chunk);
         }
-    }
-}
-
-fn map_err(e: std::io::Error) -> Result<(), std::io::Error> {
-    if e.kind() == std::io::ErrorKind::WouldBlock {
-        Ok(())
-    } else {
-        Err(e)
+        ServerMessage::ChunkUnchanged { id } => {
+            log::info!("Cached chunk is up to date: id = {:?}", id);
+        }
+        ServerMessage::ChunkDiff {
+            id,
+            from_version,
+            to_version,
+            changes,
+        } => {
+            if game
+                .chunks_mut()
+                .apply_diff(id, from_version, to_version, &changes)
+            {
+                log::debug!("Chunk updated: id = {:?}, version = {}", id, to_version);
+            } else if let Some(chunk) = game.field().chunk(id) {
+                // Our copy is out of sync, ask for what we missed
+                log::info!(
+                    "Chunk diff not applicable: id = {:?}, local = {}, from = {}",
+                    id,
+                    chunk.version,
+                    from_version
+                );
+                comm.push_tcp_event(cark_common::model::ClientMessage::SyncChunk {
+                    id,
+                    known_version: chunk.version,
+                });
+            }
+        }
+        ServerMessage::EntitySpawned { entity } => {
+            game.entities.insert(entity);
+        }
+        ServerMessage::EntityDespawned { id } => {
+            game.entities.remove(id);
+        }
+        ServerMessage::Inventory { inventory } => {
+            game.inventory = inventory;
+            game.selected_item = game
+                .selected_item
+                .min(game.inventory.items.len().saturating_sub(1));
+        }
     }
 }
@@ -1,25 +1,73 @@
+use cark_common::transport::{Net, Transport};
+
 use crate::tcp_connection::TcpConnection;
-use crate::udp::Udp;
+use crate::udp::{Udp, UdpState};
+
+// What carries the messages meant for UDP
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route {
+    Udp,
+    Tcp,
+}
+
+impl std::fmt::Display for Route {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Udp => write!(f, "udp"),
+            Self::Tcp => write!(f, "tcp"),
+        }
+    }
+}
 
 pub struct Communication {
     pub tcp: TcpConnection,
     pub udp: Udp,
+    transport: Route,
+    // Whether the server has been asked to send over TCP
+    udp_fallback: bool,
+    sent_messages: u64,
+    received_messages: u64,
 }
 
 impl Communication {
-    pub fn new(tcp_addr: &str, udp_addr: &str) -> std::io::Result<Self> {
+    // `udp_local_addr` is the local address to bind for UDP, any port if not given
+    pub fn new(
+        tcp_addr: &str,
+        udp_addr: &str,
+        udp_local_addr: Option<&str>,
+    ) -> std::io::Result<Self> {
+        Self::with_transport(&Net, tcp_addr, udp_addr, udp_local_addr)
+    }
+
+    // Over the loopback in tests
+    pub fn with_transport(
+        transport: &impl Transport,
+        tcp_addr: &str,
+        udp_addr: &str,
+        udp_local_addr: Option<&str>,
+    ) -> std::io::Result<Self> {
         Ok(Self {
-            tcp: TcpConnection::new(tcp_addr)?,
-            udp: Udp::new(udp_addr)?,
+            tcp: TcpConnection::with_transport(transport, tcp_addr)?,
+            udp: Udp::with_transport(transport, udp_addr, udp_local_addr)?,
+            transport: Route::Tcp,
+            udp_fallback: false,
+            sent_messages: 0,
+            received_messages: 0,
         })
     }
 
     pub fn push_tcp_event(&mut self, event: cark_common::model::ClientMessage) {
+        self.sent_messages += 1;
         self.tcp.push_event(event);
     }
 
+    // Sent over TCP until UDP is known to work
     pub fn push_udp_event(&mut self, event: cark_common::model::ClientMessage) {
-        self.udp.push_event(event);
+        self.sent_messages += 1;
+        match self.transport {
+            Route::Udp => self.udp.push_event(event),
+            Route::Tcp => self.tcp.push_event(event),
+        }
     }
 
     pub fn process(&mut self) -> std::io::Result<Vec<cark_common::model::ServerMessage>> {
@@ -28,7 +76,40 @@ impl Communication {
 
         self.tcp.process(&mut handler)?;
         self.udp.process(&mut handler)?;
+        self.update_transport();
 
+        self.received_messages += incoming_events.len() as u64;
         Ok(incoming_events)
     }
+
+    fn update_transport(&mut self) {
+        let state = self.udp.state();
+        let transport = match state {
+            UdpState::Connected => Route::Udp,
+            _ => Route::Tcp,
+        };
+        if transport != self.transport {
+            log::info!("Transport: {} -> {}", self.transport, transport);
+            self.transport = transport;
+        }
+
+        // The server keeps sending over UDP while the handshake may still succeed
+        let udp_fallback = state == UdpState::Blocked;
+        if udp_fallback != self.udp_fallback {
+            self.udp_fallback = udp_fallback;
+            self.tcp
+                .push_event(cark_common::model::ClientMessage::UdpFallback {
+                    enabled: udp_fallback,
+                });
+        }
+    }
+
+    pub fn transport(&self) -> Route {
+        self.transport
+    }
+
+    // Messages pushed and received over both TCP and UDP so far
+    pub fn message_counts(&self) -> (u64, u64) {
+        (self.sent_messages, self.received_messages)
+    }
 }
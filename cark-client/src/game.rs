@@ -1,33 +1,121 @@
-use cark_common::field::{Chunk, ChunkId, Field};
+use std::collections::BTreeMap;
+
+use cark_common::{
+    entity::{Entities, Inventory, ItemStack},
+    field::{Chunk, ChunkId, Field, Layer},
+    item::{ItemKind, ItemRegistry},
+    physics::Body,
+    tile::{TileKind, TileRegistry},
+};
+
+use crate::{
+    chunk_manager::{ChunkManager, ChunkManagerConfig},
+    communication::Route,
+};
+
+const MAX_NOTICES: usize = 5;
 
 pub struct Game {
-    field: Field,
+    chunks: ChunkManager,
+    tiles: TileRegistry,
     pub characters: Vec<Character>,
+    pub entities: Entities,
+    items: ItemRegistry,
+    pub inventory: Inventory,
+    // Index into `inventory.items` of the stack to use, drop or give
+    pub selected_item: usize,
     pub player_id: u64,
     pub ups: f32,
+    // What the position updates go over
+    pub transport: Route,
+    // Saved with the account, send `ClientMessage::Settings` to change them
+    pub settings: BTreeMap<String, String>,
+    // Why the server refused to let us join
+    pub join_rejected: Option<String>,
+    // Recent notices from the server, oldest first
+    pub notices: Vec<String>,
 }
 
 impl Game {
-    pub fn new() -> Self {
+    pub fn new(chunk_config: ChunkManagerConfig) -> Self {
         Self {
-            field: Field::new(),
+            chunks: ChunkManager::new(chunk_config),
+            tiles: TileRegistry::default(),
             characters: vec![],
+            entities: Entities::new(),
+            items: ItemRegistry::default(),
+            inventory: Inventory::default(),
+            selected_item: 0,
             player_id: 0,
             ups: 0.0,
+            transport: Route::Tcp,
+            settings: BTreeMap::new(),
+            join_rejected: None,
+            notices: vec![],
         }
     }
 
     pub fn field(&self) -> &Field {
-        &self.field
+        self.chunks.field()
+    }
+
+    pub fn set_world(&mut self, world_id: u64, field: Field) {
+        self.chunks.reset(Some(world_id), field);
+    }
+
+    pub fn chunks(&self) -> &ChunkManager {
+        &self.chunks
+    }
+
+    pub fn chunks_mut(&mut self) -> &mut ChunkManager {
+        &mut self.chunks
+    }
+
+    pub fn tiles(&self) -> &TileRegistry {
+        &self.tiles
+    }
+
+    pub fn set_tiles(&mut self, tiles: TileRegistry) {
+        self.tiles = tiles;
     }
 
-    pub fn set_field(&mut self, field: Field) {
-        self.field = field;
+    pub fn items(&self) -> &ItemRegistry {
+        &self.items
+    }
+
+    pub fn set_items(&mut self, items: ItemRegistry) {
+        self.items = items;
+    }
+
+    // The selected stack with its kind
+    pub fn selected_item(&self) -> Option<(&ItemStack, Option<&ItemKind>)> {
+        let stack = self.inventory.items.get(self.selected_item)?;
+        Some((stack, self.items.get(stack.item)))
+    }
+
+    // The kind of the tile under the character's center
+    pub fn tile_under(&self, character: &Character) -> &TileKind {
+        let tile = self.field().tile(
+            character.chunk_id,
+            Layer::Ground,
+            [
+                character.position[0].floor() as i32,
+                character.position[1].floor() as i32,
+            ],
+        );
+        self.tiles.get(tile)
     }
 
     pub fn update_chunk(&mut self, chunk: Chunk) {
         log::debug!("Chunk received: {:?}", chunk);
-        self.field.set_existed_chunk(chunk, true);
+        self.chunks.insert(chunk);
+    }
+
+    pub fn push_notice(&mut self, text: String) {
+        self.notices.push(text);
+        if self.notices.len() > MAX_NOTICES {
+            self.notices.remove(0);
+        }
     }
 
     pub fn player_character(&self) -> Option<&Character> {
@@ -41,6 +129,8 @@ pub struct Character {
     pub chunk_id: ChunkId,
     pub position: [f32; 2],
     pub velocity: [f32; 2],
+    // Hosted by the server
+    pub npc: bool,
 }
 
 impl Character {
@@ -51,6 +141,7 @@ impl Character {
             chunk_id,
             position,
             velocity: [0.0, 0.0],
+            npc: false,
         }
     }
 
@@ -61,4 +152,14 @@ impl Character {
     pub fn name(&self) -> &str {
         &self.name
     }
+
+    pub fn body(&self) -> Body {
+        Body::new(self.chunk_id, self.position, self.velocity)
+    }
+
+    pub fn set_body(&mut self, body: Body) {
+        self.chunk_id = body.chunk_id;
+        self.position = body.position;
+        self.velocity = body.velocity;
+    }
 }
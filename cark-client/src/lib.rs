@@ -1,3 +1,4 @@
+pub mod chunk_manager;
 pub mod client;
 pub mod communication;
 pub mod game;
@@ -5,7 +6,8 @@ pub mod systems;
 pub mod tcp_connection;
 pub mod udp;
 
-const KEY_COUNT: usize = 6;
+// Up, down, left, right, space, escape, next item, use item, drop item, give item
+const KEY_COUNT: usize = 10;
 
 pub struct Input {
     pub key_down: [bool; KEY_COUNT],
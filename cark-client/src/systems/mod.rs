@@ -1,7 +1,6 @@
 use cark_common::{
-    direction::Direction,
-    field::{ChunkId, CHUNK_SIZE},
     model,
+    physics::{self, FixedStep, MoveInput, PhysicsParams},
 };
 
 use crate::{communication::Communication, game::Game, Input};
@@ -9,23 +8,19 @@ use crate::{communication::Communication, game::Game, Input};
 pub type BoxedSystemFn = Box<dyn FnMut(&mut Game, &Input, &mut Communication)>;
 
 pub fn system_player_move() -> impl FnMut(&mut Game, &Input, &mut Communication) {
-    let mut ddx = 0.0;
-    let mut ddy = 0.0;
-    let dv = 60.0;
-    let fract = 0.04f32;
-
-    return move |game, input, comm| {
-        if input.key_down[0] {
-            ddy -= dv;
-        }
-        if input.key_down[1] {
-            ddy += dv;
-        }
-        if input.key_down[2] {
-            ddx -= dv;
-        }
-        if input.key_down[3] {
-            ddx += dv;
+    let params = PhysicsParams::default();
+    let mut fixed_step = FixedStep::new();
+    // Up, down, left, right
+    let mut held = [false; 4];
+
+    move |game, input, _comm| {
+        for (i, held) in held.iter_mut().enumerate() {
+            if input.key_down[i] {
+                *held = true;
+            }
+            if input.key_up[i] {
+                *held = false;
+            }
         }
         // if input.key_down[4] {
         //     if let Some(i) = game
@@ -46,196 +41,35 @@ pub fn system_player_move() -> impl FnMut(&mut Game, &Input, &mut Communication)
         //         }));
         //     }
         // }
+        let move_input = MoveInput::from_keys(held[0], held[1], held[2], held[3]);
 
-        if input.key_up[0] {
-            ddy += dv;
-        }
-        if input.key_up[1] {
-            ddy -= dv;
-        }
-        if input.key_up[2] {
-            ddx += dv;
-        }
-        if input.key_up[3] {
-            ddx -= dv;
-        }
-
-        let mut dt = input.dt;
-
+        let steps = fixed_step.advance(&params, input.dt);
         if let Some(i) = game
             .characters
             .iter()
             .position(|c| c.id() == game.player_id)
         {
-            let chunk_id = game.characters[i].chunk_id;
-
-            let fract = fract.powf(dt);
-            game.characters[i].velocity = [
-                game.characters[i].velocity[0] * fract + ddx * dt,
-                game.characters[i].velocity[1] * fract + ddy * dt,
-            ];
-
-            let pos = parry2d::na::Vector2::from(game.characters[i].position);
-            let character_shape = parry2d::shape::Ball::new(0.5);
-            loop {
-                let block = parry2d::shape::SharedShape::new(parry2d::shape::Cuboid::new(
-                    parry2d::na::Vector2::new(0.5, 0.5),
-                ));
-                let field = game.field();
-                let cx = game.characters[i].position[0] as i32;
-                let cy = game.characters[i].position[1] as i32;
-                let rect = [cx - 1, cy - 1, cx + 2, cy + 2];
-                let shapes: Vec<_> = field
-                    .view(chunk_id, rect)
-                    .iter()
-                    .enumerate()
-                    .filter_map(|(i, &v)| {
-                        if v == 1 {
-                            let x = i as i32 % 3 + cx - 1;
-                            let y = i as i32 / 3 + cy - 1;
-                            Some((
-                                parry2d::na::Isometry2::new(
-                                    parry2d::na::Vector2::new(x as f32 + 0.5, y as f32 + 0.5),
-                                    0.0,
-                                ),
-                                block.clone(),
-                            ))
-                        } else {
-                            None
-                        }
-                    })
-                    .collect();
-                if shapes.is_empty() {
-                    break;
-                }
-                let col = parry2d::shape::Compound::new(shapes);
-                if let Some(toi) = parry2d::query::time_of_impact(
-                    &parry2d::na::Isometry2::new(pos, 0.0),
-                    &parry2d::na::Vector2::from(game.characters[i].velocity),
-                    &character_shape,
-                    &Default::default(),
-                    &Default::default(),
-                    &col,
-                    dt,
-                    false,
-                )
-                .unwrap()
-                {
-                    // log::info!("toi: {:?}", toi);
-                    // toi.status == parry2d::query::TOIStatus::Converged;
-                    game.characters[i].position[0] +=
-                        game.characters[i].velocity[0] * toi.toi * 0.99;
-                    game.characters[i].position[1] +=
-                        game.characters[i].velocity[1] * toi.toi * 0.99;
-                    let refl = toi.normal2.scale(
-                        1.5 * parry2d::na::Vector2::from(game.characters[i].velocity).norm(),
-                    );
-                    game.characters[i].velocity[0] += refl.x;
-                    game.characters[i].velocity[1] += refl.y;
-                    dt -= toi.toi;
-                    continue;
-                }
-                break;
-            }
-
-            game.characters[i].position[0] += game.characters[i].velocity[0] * dt;
-            game.characters[i].position[1] += game.characters[i].velocity[1] * dt;
-
-            let chunks_around: Vec<_> = game
-                .field()
-                .chunks_around(chunk_id)
-                .into_iter()
-                .map(|c| (c.0, c.1.map(|c| c.id)))
+            let others: Vec<_> = game
+                .characters
+                .iter()
+                .filter(|c| c.id() != game.player_id)
+                .map(|c| c.body())
                 .collect();
-            for j in 0..game.characters.len() {
-                if i == j {
-                    continue;
-                }
-
-                let Some((rel, _)) = chunks_around.iter().find(|c| {
-                    c.1.map(|id| id == game.characters[j].chunk_id)
-                        .unwrap_or_default()
-                }) else {
-                    continue;
-                };
-
-                let pos2 = parry2d::na::Vector2::from([
-                    game.characters[j].position[0] + rel[0] as f32 * CHUNK_SIZE as f32,
-                    game.characters[j].position[1] + rel[1] as f32 * CHUNK_SIZE as f32,
-                ]);
-                if let Ok(dist) = parry2d::query::details::distance(
-                    &parry2d::na::Isometry2::new(pos, 0.0),
-                    &character_shape,
-                    &parry2d::na::Isometry2::new(pos2, 0.0),
-                    &character_shape,
-                ) {
-                    if dist == 0.0 && pos != pos2 {
-                        let d = (pos - pos2).normalize() * 0.25;
-                        game.characters[i].position[0] += d.x;
-                        game.characters[i].position[1] += d.y;
-                        game.characters[i].velocity[0] *= -0.5;
-                        game.characters[i].velocity[1] *= -0.5;
-                    }
-                }
-            }
-
-            // Move to the next chunk
-            {
-                if game.characters[i].position[0] < 0.0 {
-                    if let Some(chunk_id) = game
-                        .field()
-                        .chunk(game.characters[i].chunk_id)
-                        .and_then(|c| ChunkId::new(c.related[Direction::Left.to_number()]))
-                    {
-                        game.characters[i].chunk_id = chunk_id;
-                        game.characters[i].position[0] += CHUNK_SIZE as f32;
-                    } else {
-                        game.characters[i].position[0] = 0.0;
-                        game.characters[i].velocity[0] = 0.0;
-                    }
-                }
-                if game.characters[i].position[0] >= CHUNK_SIZE as f32 {
-                    if let Some(chunk_id) = game
-                        .field()
-                        .chunk(game.characters[i].chunk_id)
-                        .and_then(|c| ChunkId::new(c.related[Direction::Right.to_number()]))
-                    {
-                        game.characters[i].chunk_id = chunk_id;
-                        game.characters[i].position[0] -= CHUNK_SIZE as f32;
-                    } else {
-                        game.characters[i].position[0] = CHUNK_SIZE as f32 - 0.1;
-                        game.characters[i].velocity[0] = 0.0;
-                    }
-                }
-                if game.characters[i].position[1] < 0.0 {
-                    if let Some(chunk_id) = game
-                        .field()
-                        .chunk(game.characters[i].chunk_id)
-                        .and_then(|c| ChunkId::new(c.related[Direction::Top.to_number()]))
-                    {
-                        game.characters[i].chunk_id = chunk_id;
-                        game.characters[i].position[1] += CHUNK_SIZE as f32;
-                    } else {
-                        game.characters[i].position[1] = 0.0;
-                        game.characters[i].velocity[1] = 0.0;
-                    }
-                }
-                if game.characters[i].position[1] >= CHUNK_SIZE as f32 {
-                    if let Some(chunk_id) = game
-                        .field()
-                        .chunk(game.characters[i].chunk_id)
-                        .and_then(|c| ChunkId::new(c.related[Direction::Bottom.to_number()]))
-                    {
-                        game.characters[i].chunk_id = chunk_id;
-                        game.characters[i].position[1] -= CHUNK_SIZE as f32;
-                    } else {
-                        game.characters[i].position[1] = CHUNK_SIZE as f32 - 0.1;
-                        game.characters[i].velocity[1] = 0.0;
-                    }
-                }
+            let mut body = game.characters[i].body();
+            for _ in 0..steps {
+                body = physics::step(
+                    &params,
+                    &body,
+                    &move_input,
+                    game.field(),
+                    game.tiles(),
+                    &others,
+                    params.timestep,
+                );
             }
+            game.characters[i].set_body(body);
         }
-    };
+    }
 }
 
 pub fn system_player_action_push() -> impl FnMut(&mut Game, &Input, &mut Communication) {
@@ -258,6 +92,47 @@ pub fn system_player_action_push() -> impl FnMut(&mut Game, &Input, &mut Communi
     };
 }
 
+pub fn system_inventory() -> impl FnMut(&mut Game, &Input, &mut Communication) {
+    move |game, input, comm| {
+        if input.key_down[6] && !game.inventory.items.is_empty() {
+            game.selected_item = (game.selected_item + 1) % game.inventory.items.len();
+        }
+        let Some(item) = game.selected_item().map(|(stack, _)| stack.item) else {
+            return;
+        };
+        if input.key_down[7] {
+            comm.push_tcp_event(model::ClientMessage::UseItem { item });
+        }
+        if input.key_down[8] {
+            comm.push_tcp_event(model::ClientMessage::DropItem { item, count: 1 });
+        }
+        if input.key_down[9] {
+            // To the nearest player, the server checks the distance
+            let Some(me) = game.player_character() else {
+                return;
+            };
+            let nearest = game
+                .characters
+                .iter()
+                .filter(|c| c.id() != me.id() && !c.npc)
+                .filter_map(|c| {
+                    let p = game.field().position_in(
+                        me.chunk_id,
+                        c.chunk_id,
+                        c.position,
+                        me.position,
+                    )?;
+                    let d = (p[0] - me.position[0]).hypot(p[1] - me.position[1]);
+                    Some((c.id(), d))
+                })
+                .min_by(|a, b| a.1.total_cmp(&b.1));
+            if let Some((to, _)) = nearest {
+                comm.push_tcp_event(model::ClientMessage::GiveItem { to, item, count: 1 });
+            }
+        }
+    }
+}
+
 pub fn system_compute_ups() -> impl FnMut(&mut Game, &Input, &mut Communication) {
     let mut last = std::time::Instant::now();
 
@@ -269,56 +144,12 @@ pub fn system_compute_ups() -> impl FnMut(&mut Game, &Input, &mut Communication)
     };
 }
 
-pub fn system_chunk_retriever() -> impl FnMut(&mut Game, &Input, &mut Communication) {
-    let mut requested = vec![];
-    let mut time = 0.5;
-
-    return move |game, input, comm| {
-        if time <= 0.0 {
-            time = 0.5;
-            requested.clear();
-        }
-        time -= input.dt;
-
-        let mut request = |chunk_id, i| {
-            log::info!("Requesting chunk: id = {:?}, direction = {:?}", chunk_id, i);
-            comm.push_tcp_event(model::ClientMessage::RequestChunk {
-                id: chunk_id,
-                direction: Direction::from_number(i),
-            });
-        };
-
-        let Some(character) = game.characters.iter().find(|c| c.id() == game.player_id) else {
+pub fn system_chunk_manager() -> impl FnMut(&mut Game, &Input, &mut Communication) {
+    move |game, input, comm| {
+        let Some(chunk_id) = game.player_character().map(|c| c.chunk_id) else {
             return;
         };
-        let chunk_id = character.chunk_id;
-        if let Some(chunk) = game.field().chunk(chunk_id) {
-            for i in 0..4 {
-                if requested.contains(&(chunk_id, i)) {
-                    continue;
-                }
-
-                if let Some(chunk) =
-                    ChunkId::new(chunk.related[i]).and_then(|id| game.field().chunk(id))
-                {
-                    let i = Direction::from_number(i).turn_left().to_number();
-
-                    if requested.contains(&(chunk_id, i)) {
-                        continue;
-                    }
-
-                    if let Some(_) =
-                        ChunkId::new(chunk.related[i]).and_then(|id| game.field().chunk(id))
-                    {
-                        continue;
-                    }
-                    request(chunk.id, i);
-                    requested.push((chunk_id, i));
-                    continue;
-                }
-                request(chunk_id, i);
-                requested.push((chunk_id, i));
-            }
-        }
-    };
+        game.chunks_mut()
+            .update(chunk_id, input.dt, |message| comm.push_tcp_event(message));
+    }
 }
@@ -1,20 +1,23 @@
-use std::{
-    io::{Read, Write},
-    net::TcpStream,
-};
+use std::io::{Read, Write};
 
-use cark_common::model::{ClientMessage, ServerMessage};
+use cark_common::{
+    model::{ClientMessage, ServerMessage},
+    transport::{self, Net, Stream, Transport},
+};
 
 pub struct TcpConnection {
-    pub stream: TcpStream,
+    pub stream: Box<dyn Stream>,
     pub buf: Vec<u8>,
     outgoing_events: Vec<ClientMessage>,
 }
 
 impl TcpConnection {
     pub fn new(addr: &str) -> Result<Self, std::io::Error> {
-        let stream = TcpStream::connect(addr)?;
-        stream.set_nonblocking(true)?;
+        Self::with_transport(&Net, addr)
+    }
+
+    pub fn with_transport(transport: &impl Transport, addr: &str) -> Result<Self, std::io::Error> {
+        let stream = transport.connect(transport::resolve(addr)?)?;
         Ok(Self {
             stream,
             buf: Vec::new(),
@@ -1,42 +1,84 @@
-use std::net::{SocketAddr, UdpSocket};
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant},
+};
 
 use cark_common::{
     model::{ClientMessage, ClientUdpMessage, ServerMessage, ServerUdpMessage},
-    udp_stat::{SequenceGen, UdpStat},
+    transport::{self, Datagram, Net, Transport},
+    udp_stat::{read_datagram, write_datagram, DropStat, SequenceGen, UdpStat, MAX_DATAGRAM_SIZE},
 };
 
+// Interval of the Init retries until acknowledged, and then once UDP is given up on
+const INIT_RETRY_INTERVAL: Duration = Duration::from_millis(500);
+const BLOCKED_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+// How long to wait for the acknowledgement before giving up on UDP
+const INIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UdpState {
+    // Not joined yet
+    Idle,
+    // Waiting for the server to acknowledge Init
+    Connecting,
+    Connected,
+    // No acknowledgement in time, still retried in case UDP comes through later
+    Blocked,
+}
+
 pub struct Udp {
-    socket: UdpSocket,
+    socket: Box<dyn Datagram>,
+    // Datagrams from anywhere else are ignored
+    server_addr: SocketAddr,
+    // Given by the server in `Joined`, nothing is sent before it
+    token: Option<u64>,
+    state: UdpState,
+    // When the first and the last Init were sent
+    init_started: Instant,
+    init_sent: Instant,
     outgoing_events: Vec<ClientMessage>,
     stat: UdpStat,
     sequence: SequenceGen,
+    drops: DropStat,
 }
 
 impl Udp {
-    pub fn new(server_addr: &str) -> std::io::Result<Self> {
-        for port in 34254..65535 {
-            match UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], port))) {
-                Ok(socket) => {
-                    log::info!("UDP socket bound to port {}", port);
-
-                    socket.set_nonblocking(true)?;
-                    socket.connect(server_addr)?;
-                    return Ok(Self {
-                        socket,
-                        outgoing_events: vec![],
-                        stat: UdpStat::new(),
-                        sequence: SequenceGen::default(),
-                    });
-                }
-                Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => {}
-                Err(e) => return Err(e),
-            }
-        }
+    // Bind the local address if given, otherwise any port the OS assigns
+    pub fn new(server_addr: &str, local_addr: Option<&str>) -> std::io::Result<Self> {
+        Self::with_transport(&Net, server_addr, local_addr)
+    }
+
+    pub fn with_transport(
+        transport: &impl Transport,
+        server_addr: &str,
+        local_addr: Option<&str>,
+    ) -> std::io::Result<Self> {
+        let server_addr = transport::resolve(server_addr)?;
+        let local_addr = match local_addr {
+            Some(local_addr) => transport::resolve(local_addr)?,
+            None if server_addr.is_ipv4() => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            None => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        };
+        let socket = transport.bind(local_addr)?;
+        log::info!("UDP socket bound: addr = {}", socket.local_addr()?);
 
-        Err(std::io::Error::new(
-            std::io::ErrorKind::AddrInUse,
-            "No available port",
-        ))
+        let now = socket.now();
+        Ok(Self {
+            socket,
+            server_addr,
+            token: None,
+            state: UdpState::Idle,
+            init_started: now,
+            init_sent: now,
+            outgoing_events: vec![],
+            stat: UdpStat::new(),
+            sequence: SequenceGen::default(),
+            drops: DropStat::default(),
+        })
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
     }
 
     pub fn push_event(&mut self, event: ClientMessage) {
@@ -44,58 +86,268 @@ impl Udp {
     }
 
     pub fn process(&mut self, mut handler: impl FnMut(ServerMessage)) -> std::io::Result<()> {
-        let mut buf = [0; 1024];
+        // One byte more than the limit to tell the datagrams over it
+        let mut buf = [0; MAX_DATAGRAM_SIZE + 1];
 
         // Receive
         loop {
-            match self.socket.recv(&mut buf) {
-                Ok(size) => {
-                    let message = &buf[..size];
-
-                    let message: ServerUdpMessage = cark_common::read_from_slice(&message).unwrap();
-                    log::debug!("Received {:?}", message);
-
-                    match message {
-                        ServerUdpMessage::Init => todo!(),
-                        ServerUdpMessage::Message { sequence, message } => {
-                            self.stat.update(sequence);
-
-                            handler(message);
-                        }
-                    }
+            match self.socket.recv_from(&mut buf) {
+                Ok((_, addr)) if addr != self.server_addr => {
+                    log::debug!("Datagram ignored: addr = {}, not from the server", addr);
                 }
+                Ok((size, _)) => self.receive(&buf[..size], &mut handler),
                 Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
                     break;
                 }
-                Err(e) => return Err(e),
+                // The server isn't listening yet or anymore, which TCP will tell
+                Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {}
+                Err(e) => {
+                    self.fall_back(e);
+                    break;
+                }
             }
         }
 
+        self.retry_init();
+
         // Send
-        for event in self.outgoing_events.drain(..) {
+        let events = std::mem::take(&mut self.outgoing_events);
+        for event in events {
+            let Some(token) = self.token else {
+                log::debug!("Message not sent before the session: {}", event.name());
+                continue;
+            };
             let message = ClientUdpMessage::Message {
-                sequence: self.sequence.next(),
+                token,
+                sequence: self.sequence.next_sequence(),
                 message: event,
             };
 
-            let buf = cark_common::write_to_slice(&message, &mut buf).unwrap();
-            self.socket.send(&buf)?;
+            match write_datagram(&message, &mut buf) {
+                Ok(buf) => {
+                    if let Err(e) = self.socket.send_to(buf, self.server_addr) {
+                        // The rest is dropped, later messages go over TCP
+                        self.fall_back(e);
+                        break;
+                    }
+                }
+                Err(e) => log::warn!("Message not sent: {}", e),
+            }
         }
 
         Ok(())
     }
 
-    pub fn send_init(&mut self, id: u64) -> std::io::Result<()> {
-        let mut buf = [0; 1024];
+    // Decode a datagram and pass the message on, dropping the datagram if it's malformed
+    fn receive(&mut self, data: &[u8], handler: &mut impl FnMut(ServerMessage)) {
+        let message: ServerUdpMessage = match read_datagram(data) {
+            Ok(message) => message,
+            Err(e) => {
+                log::debug!("Datagram dropped: {}", e);
+                self.drops.record(e.reason());
+                return;
+            }
+        };
+        log::debug!("Received {:?}", message);
 
-        let message = ClientUdpMessage::Init { id };
-        let buf = cark_common::write_to_slice(&message, &mut buf).unwrap();
-        self.socket.send(&buf)?;
+        match message {
+            ServerUdpMessage::Init if self.token.is_some() => self.set_connected(),
+            ServerUdpMessage::Init => {
+                log::debug!("Datagram dropped: unexpected Init");
+                self.drops.record("unexpected");
+            }
+            ServerUdpMessage::Message { sequence, message } => {
+                // Only sent to sessions, so the acknowledgement must have been lost
+                self.set_connected();
+                self.stat.update(sequence);
 
-        Ok(())
+                handler(message);
+            }
+        }
+    }
+
+    // Start the session with the token from `Joined`, retried until acknowledged
+    pub fn send_init(&mut self, token: u64) {
+        self.token = Some(token);
+        self.state = UdpState::Connecting;
+        self.init_started = self.socket.now();
+        self.write_init(token)
+    }
+
+    fn retry_init(&mut self) {
+        let Some(token) = self.token else {
+            return;
+        };
+        let now = self.socket.now();
+        let interval = match self.state {
+            UdpState::Connecting if now.duration_since(self.init_started) >= INIT_TIMEOUT => {
+                log::warn!("UDP seems blocked, falling back to TCP");
+                self.state = UdpState::Blocked;
+                BLOCKED_RETRY_INTERVAL
+            }
+            UdpState::Connecting => INIT_RETRY_INTERVAL,
+            UdpState::Blocked => BLOCKED_RETRY_INTERVAL,
+            UdpState::Idle | UdpState::Connected => return,
+        };
+        if now.duration_since(self.init_sent) >= interval {
+            self.write_init(token);
+        }
+    }
+
+    fn write_init(&mut self, token: u64) {
+        let mut buf = [0; MAX_DATAGRAM_SIZE];
+        let buf = write_datagram(&ClientUdpMessage::Init { token }, &mut buf).unwrap();
+        self.init_sent = self.socket.now();
+        match self.socket.send_to(buf, self.server_addr) {
+            // Not reachable yet, the next retry will tell
+            Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {}
+            Err(e) => self.fall_back(e),
+            Ok(_) => {}
+        }
+    }
+
+    // Any other socket error gives up on UDP until an Init retry gets through
+    fn fall_back(&mut self, e: std::io::Error) {
+        log::warn!("UDP failed, falling back to TCP: {}", e);
+        if self.token.is_some() {
+            self.state = UdpState::Blocked;
+        }
+    }
+
+    fn set_connected(&mut self) {
+        if self.state != UdpState::Connected {
+            log::info!(
+                "UDP connected: after {:?}",
+                self.socket.now().duration_since(self.init_started)
+            );
+            self.state = UdpState::Connected;
+        }
+    }
+
+    pub fn state(&self) -> UdpState {
+        self.state
     }
 
     pub fn stat(&self) -> &UdpStat {
         &self.stat
     }
+
+    // Datagrams dropped on receive
+    pub fn drops(&self) -> &DropStat {
+        &self.drops
+    }
+}
+
+#[test]
+fn test_fuzz() {
+    use cark_common::field::ChunkId;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::net::UdpSocket;
+
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let mut udp = Udp::new(&server.local_addr().unwrap().to_string(), None).unwrap();
+    let mut buf = [0; MAX_DATAGRAM_SIZE];
+    let sample = write_datagram(
+        &ServerUdpMessage::Message {
+            sequence: 0,
+            message: ServerMessage::Position {
+                user_id: 1,
+                chunk_id: ChunkId::MIN,
+                position: [1.5, 2.5],
+                velocity: [0.0, 0.0],
+            },
+        },
+        &mut buf,
+    )
+    .unwrap()
+    .to_vec();
+
+    // Random bytes and broken valid datagrams, none of which may panic
+    let mut rng = StdRng::seed_from_u64(0);
+    for i in 0..20000 {
+        let data: Vec<u8> = if i % 2 == 0 {
+            let len = rng.gen_range(0..=MAX_DATAGRAM_SIZE + 1);
+            (0..len).map(|_| rng.gen()).collect()
+        } else {
+            let mut data = sample.clone();
+            let i = rng.gen_range(0..data.len());
+            data[i] = rng.gen();
+            if rng.gen_bool(0.5) {
+                data.truncate(rng.gen_range(0..data.len()));
+            }
+            data
+        };
+        udp.receive(&data, &mut |_| {});
+    }
+    assert!(udp.drops().get("malformed") > 0);
+    assert!(udp.drops().get("too_large") > 0);
+
+    // Through the socket
+    let client_addr = SocketAddr::from(([127, 0, 0, 1], udp.local_addr().unwrap().port()));
+    let drops = udp.drops().total();
+    server.send_to(&[0xff; 2000], client_addr).unwrap();
+    server
+        .send_to(&sample[..sample.len() - 1], client_addr)
+        .unwrap();
+    server.send_to(&sample, client_addr).unwrap();
+    let mut messages = vec![];
+    for _ in 0..100 {
+        udp.process(|m| messages.push(m)).unwrap();
+        if !messages.is_empty() {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    assert_eq!(udp.drops().total(), drops + 2);
+    assert!(matches!(
+        messages[..],
+        [ServerMessage::Position { user_id: 1, .. }]
+    ));
+}
+
+#[test]
+fn test_handshake() {
+    use std::net::UdpSocket;
+
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let mut udp = Udp::new(&server.local_addr().unwrap().to_string(), None).unwrap();
+    assert_eq!(udp.state(), UdpState::Idle);
+    udp.send_init(5);
+    assert_eq!(udp.state(), UdpState::Connecting);
+
+    let mut buf = [0; MAX_DATAGRAM_SIZE];
+    let (size, client_addr) = server.recv_from(&mut buf).unwrap();
+    assert!(matches!(
+        read_datagram(&buf[..size]),
+        Ok(ClientUdpMessage::Init { token: 5 })
+    ));
+
+    // No acknowledgement in time
+    udp.init_started -= INIT_TIMEOUT;
+    udp.process(|_| {}).unwrap();
+    assert_eq!(udp.state(), UdpState::Blocked);
+
+    // A late one still connects
+    let ack = write_datagram(&ServerUdpMessage::Init, &mut buf).unwrap();
+    server.send_to(ack, client_addr).unwrap();
+    for _ in 0..100 {
+        udp.process(|_| {}).unwrap();
+        if udp.state() == UdpState::Connected {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(udp.state(), UdpState::Connected);
+}
+
+#[test]
+fn test_send_error() {
+    // An IPv4 socket can't send to an IPv6 address
+    let mut udp = Udp::new("[::1]:9", Some("127.0.0.1:0")).unwrap();
+    udp.send_init(5);
+    assert_eq!(udp.state(), UdpState::Blocked);
+
+    udp.push_event(ClientMessage::Leave);
+    udp.process(|_| {}).unwrap();
+    assert_eq!(udp.state(), UdpState::Blocked);
 }
@@ -0,0 +1,344 @@
+// Compact encoding of chunk tile data.
+//
+// Layout: [header, length (u32 LE), body...]
+// The low bits of the header select the encoding of the body and the
+// `COMPRESSED` bit tells whether the body has been deflated afterwards.
+// The encoder tries every encoding and keeps the smallest one.
+
+const RAW: u8 = 0;
+const RLE: u8 = 1;
+const PALETTE: u8 = 2;
+const ENCODING_MASK: u8 = 0b11;
+const COMPRESSED: u8 = 0b1000_0000;
+
+const HEADER_LEN: usize = 5;
+// Chunks are encoded on the tick thread, so a fast level, and small bodies aren't worth the time
+#[cfg(feature = "compress")]
+const COMPRESSION_LEVEL: u8 = 1;
+#[cfg(feature = "compress")]
+const MIN_COMPRESSED_LEN: usize = 64;
+// Upper bound of the decoded length, so that a broken header can't make us allocate too much
+const MAX_LEN: usize = 1 << 20;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    UnexpectedEnd,
+    InvalidHeader(u8),
+    InvalidLength { expected: usize, actual: usize },
+    InvalidPalette,
+    Decompress,
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnexpectedEnd => write!(f, "Unexpected end of chunk data"),
+            Self::InvalidHeader(header) => write!(f, "Invalid chunk data header: {}", header),
+            Self::InvalidLength { expected, actual } => write!(
+                f,
+                "Invalid chunk data length: expected = {}, actual = {}",
+                expected, actual
+            ),
+            Self::InvalidPalette => write!(f, "Invalid chunk data palette"),
+            Self::Decompress => write!(f, "Failed to decompress chunk data"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+pub fn encode(data: &[u8]) -> Vec<u8> {
+    let candidates = [
+        (RAW, data.to_vec()),
+        (RLE, encode_rle(data)),
+        (PALETTE, encode_palette(data)),
+    ];
+    let (encoding, body) = candidates
+        .into_iter()
+        .min_by_key(|(_, body)| body.len())
+        .unwrap();
+
+    #[cfg(feature = "compress")]
+    if body.len() >= MIN_COMPRESSED_LEN {
+        let compressed = miniz_oxide::deflate::compress_to_vec(&body, COMPRESSION_LEVEL);
+        if compressed.len() < body.len() {
+            return with_header(encoding | COMPRESSED, data.len(), &compressed);
+        }
+    }
+
+    with_header(encoding, data.len(), &body)
+}
+
+pub fn decode(encoded: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    if encoded.len() < HEADER_LEN {
+        return Err(DecodeError::UnexpectedEnd);
+    }
+    let header = encoded[0];
+    let len = u32::from_le_bytes(encoded[1..HEADER_LEN].try_into().unwrap()) as usize;
+    let body = &encoded[HEADER_LEN..];
+
+    if header & !(ENCODING_MASK | COMPRESSED) != 0 {
+        return Err(DecodeError::InvalidHeader(header));
+    }
+    if len > MAX_LEN {
+        return Err(DecodeError::InvalidLength {
+            expected: MAX_LEN,
+            actual: len,
+        });
+    }
+
+    let decompressed;
+    let body = if header & COMPRESSED != 0 {
+        decompressed = decompress(body, len)?;
+        &decompressed[..]
+    } else {
+        body
+    };
+
+    let data = match header & ENCODING_MASK {
+        RAW => body.to_vec(),
+        RLE => decode_rle(body, len)?,
+        PALETTE => decode_palette(body, len)?,
+        _ => return Err(DecodeError::InvalidHeader(header)),
+    };
+    if data.len() != len {
+        return Err(DecodeError::InvalidLength {
+            expected: len,
+            actual: data.len(),
+        });
+    }
+    Ok(data)
+}
+
+fn with_header(header: u8, len: usize, body: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(HEADER_LEN + body.len());
+    encoded.push(header);
+    encoded.extend((len as u32).to_le_bytes());
+    encoded.extend(body);
+    encoded
+}
+
+#[cfg(feature = "compress")]
+fn decompress(body: &[u8], len: usize) -> Result<Vec<u8>, DecodeError> {
+    // Any body is at most a little larger than the raw data
+    miniz_oxide::inflate::decompress_to_vec_with_limit(body, len * 2 + 16)
+        .map_err(|_| DecodeError::Decompress)
+}
+
+#[cfg(not(feature = "compress"))]
+fn decompress(_body: &[u8], _len: usize) -> Result<Vec<u8>, DecodeError> {
+    Err(DecodeError::Decompress)
+}
+
+// Pairs of (run length, value)
+fn encode_rle(data: &[u8]) -> Vec<u8> {
+    let mut body = vec![];
+    let mut iter = data.iter().peekable();
+    while let Some(&value) = iter.next() {
+        let mut run = 1u8;
+        while run < u8::MAX && iter.peek() == Some(&&value) {
+            iter.next();
+            run += 1;
+        }
+        body.extend([run, value]);
+    }
+    body
+}
+
+fn decode_rle(body: &[u8], len: usize) -> Result<Vec<u8>, DecodeError> {
+    if !body.len().is_multiple_of(2) {
+        return Err(DecodeError::UnexpectedEnd);
+    }
+    let mut data = Vec::with_capacity(len);
+    for pair in body.chunks(2) {
+        data.extend(std::iter::repeat_n(pair[1], pair[0] as usize));
+        if data.len() > len {
+            break;
+        }
+    }
+    Ok(data)
+}
+
+// [palette length, palette..., packed indices...]
+// Each index takes just enough bits to address the palette, packed LSB first.
+fn encode_palette(data: &[u8]) -> Vec<u8> {
+    let mut palette: Vec<u8> = data.to_vec();
+    palette.sort_unstable();
+    palette.dedup();
+    if palette.is_empty() {
+        return vec![0];
+    }
+
+    let bits = bits_for(palette.len());
+    let mut body = Vec::with_capacity(1 + palette.len() + (data.len() * bits).div_ceil(8));
+    body.push((palette.len() - 1) as u8);
+    body.extend(&palette);
+
+    let mut acc = 0u32;
+    let mut acc_bits = 0;
+    for value in data {
+        let index = palette.binary_search(value).unwrap() as u32;
+        acc |= index << acc_bits;
+        acc_bits += bits;
+        while acc_bits >= 8 {
+            body.push(acc as u8);
+            acc >>= 8;
+            acc_bits -= 8;
+        }
+    }
+    if acc_bits > 0 {
+        body.push(acc as u8);
+    }
+    body
+}
+
+fn decode_palette(body: &[u8], len: usize) -> Result<Vec<u8>, DecodeError> {
+    if len == 0 {
+        return Ok(vec![]);
+    }
+    let palette_len = *body.first().ok_or(DecodeError::UnexpectedEnd)? as usize + 1;
+    let palette = body
+        .get(1..1 + palette_len)
+        .ok_or(DecodeError::UnexpectedEnd)?;
+    let mut packed = body[1 + palette_len..].iter();
+
+    let bits = bits_for(palette_len);
+    let mask = (1u32 << bits) - 1;
+    let mut data = Vec::with_capacity(len);
+    let mut acc = 0u32;
+    let mut acc_bits = 0;
+    while data.len() < len {
+        while acc_bits < bits {
+            acc |= (*packed.next().ok_or(DecodeError::UnexpectedEnd)? as u32) << acc_bits;
+            acc_bits += 8;
+        }
+        let index = (acc & mask) as usize;
+        data.push(*palette.get(index).ok_or(DecodeError::InvalidPalette)?);
+        acc >>= bits;
+        acc_bits -= bits;
+    }
+    Ok(data)
+}
+
+fn bits_for(palette_len: usize) -> usize {
+    (usize::BITS - (palette_len - 1).leading_zeros()) as usize
+}
+
+// For `#[serde(with = "crate::codec::serde_data")]`
+pub mod serde_data {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer, T: AsRef<[u8]>>(
+        data: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        super::encode(data.as_ref()).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, T: TryFrom<Vec<u8>>>(
+        deserializer: D,
+    ) -> Result<T, D::Error> {
+        let encoded = Vec::<u8>::deserialize(deserializer)?;
+        let data = super::decode(&encoded).map_err(serde::de::Error::custom)?;
+        let len = data.len();
+        T::try_from(data)
+            .map_err(|_| serde::de::Error::custom(format!("Invalid chunk data length: {}", len)))
+    }
+}
+
+// For `#[serde(with = "crate::codec::serde_meta")]`
+// The low and high bytes are laid out in separate planes, as the high bytes are mostly zero.
+pub mod serde_meta {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &[u16], serializer: S) -> Result<S::Ok, S::Error> {
+        let planes: Vec<u8> = data
+            .iter()
+            .map(|x| *x as u8)
+            .chain(data.iter().map(|x| (*x >> 8) as u8))
+            .collect();
+        super::encode(&planes).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u16>, D::Error> {
+        let encoded = Vec::<u8>::deserialize(deserializer)?;
+        let planes = super::decode(&encoded).map_err(serde::de::Error::custom)?;
+        if planes.len() % 2 != 0 {
+            return Err(serde::de::Error::custom(format!(
+                "Invalid chunk meta length: {}",
+                planes.len()
+            )));
+        }
+        let (low, high) = planes.split_at(planes.len() / 2);
+        Ok(low
+            .iter()
+            .zip(high)
+            .map(|(&low, &high)| low as u16 | (high as u16) << 8)
+            .collect())
+    }
+}
+
+#[test]
+fn test() {
+    let cases: Vec<Vec<u8>> = vec![
+        vec![],
+        vec![7],
+        vec![1; 256],
+        (0..=255).collect(),
+        (0..300).map(|i| (i % 3) as u8).collect(),
+        (0..1000).map(|i| (i / 100) as u8).collect(),
+    ];
+    for data in cases {
+        assert_eq!(decode_rle(&encode_rle(&data), data.len()).unwrap(), data);
+        assert_eq!(
+            decode_palette(&encode_palette(&data), data.len()).unwrap(),
+            data
+        );
+        assert_eq!(decode(&encode(&data)).unwrap(), data);
+    }
+
+    assert_eq!(decode(&[]), Err(DecodeError::UnexpectedEnd));
+    assert_eq!(
+        decode(&[0x40, 0, 0, 0, 0]),
+        Err(DecodeError::InvalidHeader(0x40))
+    );
+    assert!(decode(&[PALETTE, 4, 0, 0, 0, 1, 1]).is_err());
+    assert!(decode(&[RAW, 0xff, 0xff, 0xff, 0xff]).is_err());
+}
+
+#[test]
+fn test_generated_chunks() {
+    use crate::field::{Chunk, ChunkId, Layer, DEFAULT_CHUNK_SIZE};
+
+    // Total sizes over the chunks, of the layers encoded and of the whole messages
+    let (mut encoded_size, mut message_size) = (0, 0);
+    let count = 256;
+    for id in 1..=count {
+        let chunk = Chunk::new(ChunkId::new(id).unwrap(), DEFAULT_CHUNK_SIZE);
+        for layer in Layer::ALL {
+            let data = chunk.layer(layer);
+            let encoded = encode(data);
+            assert_eq!(decode(&encoded).unwrap(), data);
+
+            let best = data
+                .len()
+                .min(encode_rle(data).len())
+                .min(encode_palette(data).len());
+            // The smallest encoding is kept, deflated only when that helps
+            assert!(encoded.len() <= HEADER_LEN + best);
+            encoded_size += encoded.len();
+        }
+
+        let message = postcard::to_allocvec(&chunk).unwrap();
+        let decoded: Chunk = postcard::from_bytes(&message).unwrap();
+        assert_eq!(decoded.ground, chunk.ground);
+        assert_eq!(decoded.walls, chunk.walls);
+        assert_eq!(decoded.meta, chunk.meta);
+        message_size += message.len();
+    }
+
+    // The generated layers are mostly runs of a few tiles, at least 4 times smaller encoded
+    let raw_size = DEFAULT_CHUNK_SIZE * DEFAULT_CHUNK_SIZE * Layer::ALL.len() * count as usize;
+    assert!(encoded_size * 4 < raw_size);
+    assert!(message_size * 4 < raw_size);
+}
@@ -0,0 +1,203 @@
+use std::collections::BTreeMap;
+
+use crate::{field::ChunkId, item::ItemId};
+
+pub type EntityId = u64;
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntityKind {
+    Item,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Transform {
+    pub chunk_id: ChunkId,
+    pub position: [f32; 2],
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Sprite {
+    // Column and row of the chip in the sprite sheet
+    pub chip: [u32; 2],
+    // Size in tiles
+    pub scale: f32,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Collider {
+    pub radius: f32,
+    // Blocks characters rather than only being touched by them
+    pub solid: bool,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ItemStack {
+    pub item: ItemId,
+    pub count: u32,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Inventory {
+    pub items: Vec<ItemStack>,
+}
+
+impl Inventory {
+    pub fn count(&self, item: ItemId) -> u32 {
+        self.items
+            .iter()
+            .find(|stack| stack.item == item)
+            .map_or(0, |stack| stack.count)
+    }
+
+    pub fn add(&mut self, item: ItemId, count: u32) {
+        if count == 0 {
+            return;
+        }
+        match self.items.iter_mut().find(|stack| stack.item == item) {
+            Some(stack) => stack.count += count,
+            None => self.items.push(ItemStack { item, count }),
+        }
+    }
+
+    // Returns false without removing anything if there are not enough
+    pub fn remove(&mut self, item: ItemId, count: u32) -> bool {
+        let Some(index) = self.items.iter().position(|stack| stack.item == item) else {
+            return false;
+        };
+        let stack = &mut self.items[index];
+        if stack.count < count {
+            return false;
+        }
+        stack.count -= count;
+        if stack.count == 0 {
+            self.items.remove(index);
+        }
+        true
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+pub enum Component {
+    Transform(Transform),
+    Sprite(Sprite),
+    Collider(Collider),
+    Inventory(Inventory),
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+pub struct Entity {
+    pub id: EntityId,
+    pub kind: EntityKind,
+    pub transform: Option<Transform>,
+    pub sprite: Option<Sprite>,
+    pub collider: Option<Collider>,
+    pub inventory: Option<Inventory>,
+}
+
+impl Entity {
+    pub fn new(id: EntityId, kind: EntityKind) -> Self {
+        Self {
+            id,
+            kind,
+            transform: None,
+            sprite: None,
+            collider: None,
+            inventory: None,
+        }
+    }
+
+    pub fn with(mut self, component: Component) -> Self {
+        self.set(component);
+        self
+    }
+
+    pub fn set(&mut self, component: Component) {
+        match component {
+            Component::Transform(c) => self.transform = Some(c),
+            Component::Sprite(c) => self.sprite = Some(c),
+            Component::Collider(c) => self.collider = Some(c),
+            Component::Inventory(c) => self.inventory = Some(c),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Entities {
+    entities: BTreeMap<EntityId, Entity>,
+}
+
+impl Entities {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, id: EntityId) -> Option<&Entity> {
+        self.entities.get(&id)
+    }
+
+    pub fn get_mut(&mut self, id: EntityId) -> Option<&mut Entity> {
+        self.entities.get_mut(&id)
+    }
+
+    // Replaces the entity with the same id if any
+    pub fn insert(&mut self, entity: Entity) {
+        self.entities.insert(entity.id, entity);
+    }
+
+    pub fn remove(&mut self, id: EntityId) -> Option<Entity> {
+        self.entities.remove(&id)
+    }
+
+    pub fn clear(&mut self) {
+        self.entities.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Entity> {
+        self.entities.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entities.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+}
+
+#[test]
+fn test() {
+    let id = ChunkId::MIN;
+    let mut entities = Entities::new();
+    entities.insert(
+        Entity::new(1, EntityKind::Item)
+            .with(Component::Transform(Transform {
+                chunk_id: id,
+                position: [1.5, 2.5],
+            }))
+            .with(Component::Collider(Collider {
+                radius: 0.5,
+                solid: false,
+            })),
+    );
+    let entity = entities.get_mut(1).unwrap();
+    assert!(entity.sprite.is_none());
+
+    entity.set(Component::Sprite(Sprite {
+        chip: [1, 1],
+        scale: 1.0,
+    }));
+    assert_eq!(entity.sprite.unwrap().chip, [1, 1]);
+    assert_eq!(entity.transform.unwrap().position, [1.5, 2.5]);
+
+    assert!(entities.remove(1).is_some());
+    assert!(entities.is_empty());
+
+    let mut inventory = Inventory::default();
+    inventory.add(3, 2);
+    inventory.add(3, 1);
+    assert_eq!(inventory.count(3), 3);
+    assert!(!inventory.remove(3, 4));
+    assert!(inventory.remove(3, 3));
+    assert!(inventory.items.is_empty());
+}
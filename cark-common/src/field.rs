@@ -1,59 +1,359 @@
-use std::{collections::HashMap, num::NonZeroU32};
+use std::{
+    collections::{HashMap, VecDeque},
+    num::NonZeroU32,
+};
 
-use crate::direction::Direction;
+use crate::{
+    direction::Direction,
+    tile::{self, TileId, TileRegistry},
+};
 
 pub type ChunkId = NonZeroU32;
 pub type OptChunkId = u32;
 
-pub const CHUNK_SIZE: usize = 16;
+pub const DEFAULT_CHUNK_SIZE: usize = 16;
+// Most tiles a ray walks, portals may loop it forever
+pub const MAX_RAY_STEPS: usize = 1024;
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Layer {
+    Ground,
+    Walls,
+    Decoration,
+}
+
+impl Layer {
+    // In drawing order
+    pub const ALL: [Self; 3] = [Self::Ground, Self::Walls, Self::Decoration];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Ground => "ground",
+            Self::Walls => "walls",
+            Self::Decoration => "decoration",
+        }
+    }
+}
+
+impl std::str::FromStr for Layer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|layer| layer.name() == s)
+            .ok_or_else(|| format!("Invalid layer: {}", s))
+    }
+}
 
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub struct Chunk {
     pub id: ChunkId,
+    // Incremented on every change, see `Field::keep_history`
+    pub version: u64,
     // Related chunk ids: [left, right, top, bottom]
     pub related: [OptChunkId; 4],
-    #[serde(with = "serde_big_array::BigArray")]
-    pub data: [u8; CHUNK_SIZE * CHUNK_SIZE],
+    // Whether each related edge is a portal: [left, right, top, bottom]
+    // Portal edges are kept as is and never recomputed from the grid.
+    pub portals: [bool; 4],
+    // Width and height in tiles
+    pub size: usize,
+    #[serde(with = "crate::codec::serde_data")]
+    pub ground: Vec<TileId>,
+    #[serde(with = "crate::codec::serde_data")]
+    pub walls: Vec<TileId>,
+    #[serde(with = "crate::codec::serde_data")]
+    pub decoration: Vec<TileId>,
+    // Free for game logic, not drawn
+    #[serde(with = "crate::codec::serde_meta")]
+    pub meta: Vec<u16>,
 }
 
 impl Chunk {
-    pub fn new(id: ChunkId) -> Self {
+    pub fn new(id: ChunkId, size: usize) -> Self {
         use rand::Rng;
-        let mut data = [1; CHUNK_SIZE * CHUNK_SIZE];
+        let len = size * size;
+        let mut ground = vec![tile::FLOOR; len];
+        let mut walls = vec![tile::VOID; len];
         let mut rng: rand::rngs::StdRng = rand::SeedableRng::seed_from_u64(id.get() as u64);
-        data[0] = 3;
-        for i in 0..8 {
-            data[i + 1] = if id.get() >> i & 1 == 1 { 5 } else { 2 };
-        }
-        for i in 9..data.len() {
-            data[i] = if rng.gen_bool(0.025) {
-                1
-            } else if rng.gen_bool(0.75) {
-                2
-            } else {
-                rng.gen_range(3..=4)
-            };
+        ground[0] = tile::FLOOR_A;
+        for i in 0..8.min(len - 1) {
+            if id.get() >> i & 1 == 1 {
+                ground[i + 1] = tile::ID_BIT;
+            }
+        }
+        for i in 9..len {
+            if rng.gen_bool(0.025) {
+                walls[i] = tile::WALL;
+            } else if !rng.gen_bool(0.75) {
+                ground[i] = rng.gen_range(tile::FLOOR_A..=tile::FLOOR_B);
+            }
         }
         Self {
             id,
+            version: 0,
             related: [0, 0, 0, 0],
-            data,
+            portals: [false; 4],
+            size,
+            ground,
+            walls,
+            decoration: vec![tile::VOID; len],
+            meta: vec![0; len],
         }
     }
+
+    pub fn layer(&self, layer: Layer) -> &[TileId] {
+        match layer {
+            Layer::Ground => &self.ground,
+            Layer::Walls => &self.walls,
+            Layer::Decoration => &self.decoration,
+        }
+    }
+
+    pub fn layer_mut(&mut self, layer: Layer) -> &mut [TileId] {
+        match layer {
+            Layer::Ground => &mut self.ground,
+            Layer::Walls => &mut self.walls,
+            Layer::Decoration => &mut self.decoration,
+        }
+    }
+
+    // Returns false if the change has no effect
+    pub fn apply(&mut self, change: &ChunkChange) -> bool {
+        let (target, value) = match *change {
+            ChunkChange::Tile {
+                layer,
+                index,
+                value,
+            } => {
+                let Some(tile) = self.layer_mut(layer).get_mut(index as usize) else {
+                    return false;
+                };
+                (tile, value)
+            }
+            ChunkChange::Meta { index, value } => {
+                let Some(meta) = self.meta.get_mut(index as usize) else {
+                    return false;
+                };
+                if *meta == value {
+                    return false;
+                }
+                *meta = value;
+                return true;
+            }
+            ChunkChange::Edge {
+                direction,
+                related,
+                portal,
+            } => {
+                let i = direction.to_number();
+                if self.related[i] == related && self.portals[i] == portal {
+                    return false;
+                }
+                self.related[i] = related;
+                self.portals[i] = portal;
+                return true;
+            }
+        };
+        if *target == value {
+            return false;
+        }
+        *target = value;
+        true
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+pub enum ChunkChange {
+    Tile {
+        layer: Layer,
+        index: u32,
+        value: TileId,
+    },
+    Meta {
+        index: u32,
+        value: u16,
+    },
+    Edge {
+        direction: Direction,
+        related: OptChunkId,
+        portal: bool,
+    },
+}
+
+// Recent changes of each chunk, to send diffs instead of whole chunks
+struct ChunkHistory {
+    max_changes: usize,
+    // Each change with the version it produced
+    logs: HashMap<ChunkId, VecDeque<(u64, ChunkChange)>>,
+    // Chunks changed since the last `take_modified`, with their version before that
+    modified: Vec<(ChunkId, u64)>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RayHit {
+    pub chunk_id: ChunkId,
+    // Coordinates of the tile in its chunk
+    pub position: [usize; 2],
+    pub layer: Layer,
+    pub tile: TileId,
+    // Distance from the origin to where the ray enters the tile
+    pub distance: f32,
+}
+
+// How a ray ended
+enum Ray {
+    Hit(RayHit),
+    // Nothing solid within the distance
+    Clear,
+    // Left the known chunks or walked too many tiles
+    Unknown,
 }
 
 pub struct Field {
     pub new_id: ChunkId,
     pub chunks: HashMap<ChunkId, Chunk>,
+    chunk_size: usize,
+    history: Option<ChunkHistory>,
+}
+
+impl Default for Field {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Field {
     pub fn new() -> Self {
+        Self::with_chunk_size(DEFAULT_CHUNK_SIZE)
+    }
+
+    pub fn with_chunk_size(chunk_size: usize) -> Self {
         let id = ChunkId::MIN;
         Self {
             new_id: id.checked_add(1).unwrap(),
-            chunks: [(id, Chunk::new(id))].into_iter().collect(),
+            chunks: [(id, Chunk::new(id, chunk_size))].into_iter().collect(),
+            chunk_size,
+            history: None,
+        }
+    }
+
+    // A field of the chunks generated before, such as the ones of a saved world
+    pub fn with_chunks(chunk_size: usize, chunks: Vec<Chunk>) -> Self {
+        let Some(last_id) = chunks.iter().map(|c| c.id).max() else {
+            return Self::with_chunk_size(chunk_size);
+        };
+        Self {
+            new_id: last_id.checked_add(1).unwrap(),
+            chunks: chunks.into_iter().map(|c| (c.id, c)).collect(),
+            chunk_size,
+            history: None,
+        }
+    }
+
+    // Version chunks and keep up to `max_changes` recent changes of each chunk.
+    // Only the authoritative field does this; copies take versions from diffs.
+    pub fn keep_history(&mut self, max_changes: usize) {
+        self.history = Some(ChunkHistory {
+            max_changes,
+            logs: HashMap::new(),
+            modified: vec![],
+        });
+    }
+
+    // Apply a change to the chunk, returns false if nothing has changed
+    pub fn modify(&mut self, id: ChunkId, change: ChunkChange) -> bool {
+        let Some(chunk) = self.chunks.get_mut(&id) else {
+            return false;
+        };
+        if !chunk.apply(&change) {
+            return false;
+        }
+        if let Some(history) = &mut self.history {
+            if !history.modified.iter().any(|(i, _)| *i == id) {
+                history.modified.push((id, chunk.version));
+            }
+            chunk.version += 1;
+            let log = history.logs.entry(id).or_default();
+            log.push_back((chunk.version, change));
+            if log.len() > history.max_changes {
+                log.pop_front();
+            }
         }
+        true
+    }
+
+    pub fn set_tile(
+        &mut self,
+        id: ChunkId,
+        layer: Layer,
+        position: [usize; 2],
+        value: TileId,
+    ) -> bool {
+        if position[0] >= self.chunk_size || position[1] >= self.chunk_size {
+            return false;
+        }
+        let index = (position[1] * self.chunk_size + position[0]) as u32;
+        self.modify(
+            id,
+            ChunkChange::Tile {
+                layer,
+                index,
+                value,
+            },
+        )
+    }
+
+    // Changes since `from_version`, if they are still kept
+    pub fn diff(&self, id: ChunkId, from_version: u64) -> Option<Vec<ChunkChange>> {
+        let chunk = self.chunk(id)?;
+        if from_version == chunk.version {
+            return Some(vec![]);
+        }
+        if from_version > chunk.version {
+            return None;
+        }
+        let log = self.history.as_ref()?.logs.get(&id)?;
+        // The change right after `from_version` must still be in the log
+        if log.front()?.0 > from_version + 1 {
+            return None;
+        }
+        Some(
+            log.iter()
+                .filter(|(version, _)| *version > from_version)
+                .map(|(_, change)| change.clone())
+                .collect(),
+        )
+    }
+
+    // Chunks changed since the last call, with their version before the changes
+    pub fn take_modified(&mut self) -> Vec<(ChunkId, u64)> {
+        self.history
+            .as_mut()
+            .map(|history| std::mem::take(&mut history.modified))
+            .unwrap_or_default()
+    }
+
+    // Bring a copy of a chunk up to date, returns false if the diff doesn't apply to it
+    pub fn apply_diff(
+        &mut self,
+        id: ChunkId,
+        from_version: u64,
+        to_version: u64,
+        changes: &[ChunkChange],
+    ) -> bool {
+        if self.chunk(id).map(|c| c.version) != Some(from_version) {
+            return false;
+        }
+        for change in changes {
+            self.modify(id, change.clone());
+        }
+        self.chunks.get_mut(&id).unwrap().version = to_version;
+        true
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
     }
 
     pub fn chunk(&self, id: ChunkId) -> Option<&Chunk> {
@@ -108,13 +408,20 @@ impl Field {
 
         let new_id = self.new_id;
         self.new_id = new_id.checked_add(1).unwrap();
-        let mut new_chunk = Chunk::new(new_id);
+        let mut new_chunk = Chunk::new(new_id, self.chunk_size);
         new_chunk.related[direction.opposite().to_number()] = id.get();
 
         self.chunks.insert(new_id, new_chunk);
 
         self.compute_related_chunks(new_id);
 
+        // Linking the new chunk is part of generating it, which the clients get in full
+        if let Some(history) = &mut self.history {
+            history.modified.retain(|(id, _)| *id != new_id);
+            history.logs.remove(&new_id);
+            self.chunks.get_mut(&new_id).unwrap().version = 0;
+        }
+
         Some(new_id)
     }
 
@@ -122,40 +429,17 @@ impl Field {
         let mut open = vec![(new_id, [0, 0])];
         let mut closed = vec![new_id];
         while let Some((id, pos)) = open.pop() {
-            if pos == [-1, 0] {
-                if let Some(chunk) = self.chunks.get_mut(&new_id) {
-                    chunk.related[Direction::Left.to_number()] = id.get();
-                }
-                if let Some(chunk) = self.chunks.get_mut(&id) {
-                    chunk.related[Direction::Right.to_number()] = new_id.get();
-                }
-            }
-            if pos == [1, 0] {
-                if let Some(chunk) = self.chunks.get_mut(&new_id) {
-                    chunk.related[Direction::Right.to_number()] = id.get();
-                }
-                if let Some(chunk) = self.chunks.get_mut(&id) {
-                    chunk.related[Direction::Left.to_number()] = new_id.get();
-                }
-            }
-            if pos == [0, -1] {
-                if let Some(chunk) = self.chunks.get_mut(&new_id) {
-                    chunk.related[Direction::Top.to_number()] = id.get();
-                }
-                if let Some(chunk) = self.chunks.get_mut(&id) {
-                    chunk.related[Direction::Bottom.to_number()] = new_id.get();
-                }
-            }
-            if pos == [0, 1] {
-                if let Some(chunk) = self.chunks.get_mut(&new_id) {
-                    chunk.related[Direction::Bottom.to_number()] = id.get();
-                }
-                if let Some(chunk) = self.chunks.get_mut(&id) {
-                    chunk.related[Direction::Top.to_number()] = new_id.get();
+            for direction in Direction::ALL {
+                if pos == direction.move_pos([0, 0]) {
+                    self.link_grid(new_id, direction, id);
                 }
             }
             if let Some(chunk) = self.chunk(id) {
                 for (i, &related_id) in chunk.related.iter().enumerate() {
+                    // Portals do not belong to the grid, so don't walk through them
+                    if chunk.portals[i] {
+                        continue;
+                    }
                     if let Some(chunk_id) = ChunkId::new(related_id) {
                         if closed.contains(&chunk_id) {
                             continue;
@@ -168,6 +452,93 @@ impl Field {
         }
     }
 
+    // Link two chunks as grid neighbors unless either edge is a portal
+    fn link_grid(&mut self, id: ChunkId, direction: Direction, other_id: ChunkId) {
+        let is_portal = |field: &Self, id: ChunkId, direction: Direction| {
+            field
+                .chunk(id)
+                .map(|c| c.portals[direction.to_number()])
+                .unwrap_or_default()
+        };
+        if is_portal(self, id, direction) || is_portal(self, other_id, direction.opposite()) {
+            return;
+        }
+
+        self.set_edge(id, direction, other_id.get(), false);
+        self.set_edge(other_id, direction.opposite(), id.get(), false);
+    }
+
+    fn set_edge(&mut self, id: ChunkId, direction: Direction, related: OptChunkId, portal: bool) {
+        self.modify(
+            id,
+            ChunkChange::Edge {
+                direction,
+                related,
+                portal,
+            },
+        );
+    }
+
+    // Link the `direction` edge of `from` to the opposite edge of `to` as a portal.
+    // Returns the ids of all chunks that have been modified.
+    pub fn link_portal(
+        &mut self,
+        from: ChunkId,
+        direction: Direction,
+        to: ChunkId,
+    ) -> Option<Vec<ChunkId>> {
+        if self.chunk(from).is_none() || self.chunk(to).is_none() {
+            return None;
+        }
+
+        let mut modified = self.unlink(from, direction);
+        modified.extend(self.unlink(to, direction.opposite()));
+
+        self.set_edge(from, direction, to.get(), true);
+        self.set_edge(to, direction.opposite(), from.get(), true);
+
+        modified.extend([from, to]);
+        modified.sort();
+        modified.dedup();
+        Some(modified)
+    }
+
+    // Remove the portal on the `direction` edge of `id`.
+    // Returns the ids of all chunks that have been modified.
+    pub fn unlink_portal(&mut self, id: ChunkId, direction: Direction) -> Option<Vec<ChunkId>> {
+        if !self.chunk(id)?.portals[direction.to_number()] {
+            return None;
+        }
+
+        let mut modified = self.unlink(id, direction);
+        modified.sort();
+        modified.dedup();
+        Some(modified)
+    }
+
+    // Cut the `direction` edge of `id` on both sides
+    fn unlink(&mut self, id: ChunkId, direction: Direction) -> Vec<ChunkId> {
+        let Some(chunk) = self.chunk(id) else {
+            return vec![];
+        };
+        let other_id = ChunkId::new(chunk.related[direction.to_number()]);
+        self.set_edge(id, direction, 0, false);
+
+        let mut modified = vec![id];
+        if let Some(other) = other_id.and_then(|other_id| self.chunk(other_id)) {
+            if other.related[direction.opposite().to_number()] == id.get() {
+                let other_id = other.id;
+                self.set_edge(other_id, direction.opposite(), 0, false);
+                modified.push(other_id);
+            }
+        }
+        modified
+    }
+
+    pub fn remove_chunk(&mut self, id: ChunkId) -> Option<Chunk> {
+        self.chunks.remove(&id)
+    }
+
     pub fn set_existed_chunk(&mut self, chunk: Chunk, compute_related: bool) {
         let id = chunk.id;
         self.chunks.insert(chunk.id, chunk);
@@ -177,9 +548,204 @@ impl Field {
         }
     }
 
-    pub fn view(&self, chunk_id: ChunkId, rect: [i32; 4]) -> Vec<u8> {
-        let mut view = vec![0; (rect[2] - rect[0]) as usize * (rect[3] - rect[1]) as usize];
-        for cy in rect[1].div_euclid(CHUNK_SIZE as i32)..=rect[3].div_euclid(CHUNK_SIZE as i32) {
+    pub fn tile(&self, chunk_id: ChunkId, layer: Layer, position: [i32; 2]) -> TileId {
+        self.view(
+            chunk_id,
+            layer,
+            [position[0], position[1], position[0] + 1, position[1] + 1],
+        )[0]
+    }
+
+    pub fn view(&self, chunk_id: ChunkId, layer: Layer, rect: [i32; 4]) -> Vec<TileId> {
+        self.view_with(chunk_id, rect, |chunk| chunk.layer(layer))
+    }
+
+    pub fn view_meta(&self, chunk_id: ChunkId, rect: [i32; 4]) -> Vec<u16> {
+        self.view_with(chunk_id, rect, |chunk| &chunk.meta)
+    }
+
+    // Whether any layer of each tile is solid
+    pub fn solid_view(&self, chunk_id: ChunkId, rect: [i32; 4], tiles: &TileRegistry) -> Vec<bool> {
+        let mut view = vec![false; (rect[2] - rect[0]) as usize * (rect[3] - rect[1]) as usize];
+        for layer in Layer::ALL {
+            for (solid, tile) in view.iter_mut().zip(self.view(chunk_id, layer, rect)) {
+                *solid |= tiles.is_solid(tile);
+            }
+        }
+        view
+    }
+
+    // Position of a point in `to` seen from the chunk `from`.
+    // Through portals the same chunk may appear at several offsets, the one nearest to `near` is taken.
+    pub fn position_in(
+        &self,
+        from: ChunkId,
+        to: ChunkId,
+        position: [f32; 2],
+        near: [f32; 2],
+    ) -> Option<[f32; 2]> {
+        let size = self.chunk_size as f32;
+        self.chunks_around(from)
+            .iter()
+            .filter(|c| c.1.is_some_and(|c| c.id == to))
+            .map(|(rel, _)| {
+                [
+                    position[0] + rel[0] as f32 * size,
+                    position[1] + rel[1] as f32 * size,
+                ]
+            })
+            .min_by(|p, q| {
+                let dp = (p[0] - near[0]).powi(2) + (p[1] - near[1]).powi(2);
+                let dq = (q[0] - near[0]).powi(2) + (q[1] - near[1]).powi(2);
+                dp.total_cmp(&dq)
+            })
+    }
+
+    // The first solid tile on the ray, walking the tiles across the related chunks.
+    // Returns None if nothing is hit within `max_distance` or `MAX_RAY_STEPS` tiles,
+    // or the ray leaves the known chunks.
+    pub fn raycast(
+        &self,
+        chunk_id: ChunkId,
+        origin: [f32; 2],
+        direction: [f32; 2],
+        max_distance: f32,
+        tiles: &TileRegistry,
+    ) -> Option<RayHit> {
+        match self.cast(chunk_id, origin, direction, max_distance, tiles) {
+            Ray::Hit(hit) => Some(hit),
+            Ray::Clear | Ray::Unknown => None,
+        }
+    }
+
+    fn cast(
+        &self,
+        chunk_id: ChunkId,
+        origin: [f32; 2],
+        direction: [f32; 2],
+        max_distance: f32,
+        tiles: &TileRegistry,
+    ) -> Ray {
+        let length = (direction[0] * direction[0] + direction[1] * direction[1]).sqrt();
+        if length == 0.0 || !length.is_finite() || !origin.iter().all(|x| x.is_finite()) {
+            return Ray::Unknown;
+        }
+        let direction = [direction[0] / length, direction[1] / length];
+        let chunk = |id: OptChunkId| ChunkId::new(id).and_then(|id| self.chunk(id));
+
+        let size = self.chunk_size as i32;
+        let Some(mut current) = self.chunk(chunk_id) else {
+            return Ray::Unknown;
+        };
+        let mut cell = [origin[0].floor() as i32, origin[1].floor() as i32];
+        // Bring the origin into the chunk
+        for (axis, negative, positive) in [
+            (0, Direction::Left, Direction::Right),
+            (1, Direction::Top, Direction::Bottom),
+        ] {
+            while cell[axis] < 0 {
+                let Some(next) = chunk(current.related[negative.to_number()]) else {
+                    return Ray::Unknown;
+                };
+                current = next;
+                cell[axis] += size;
+            }
+            while cell[axis] >= size {
+                let Some(next) = chunk(current.related[positive.to_number()]) else {
+                    return Ray::Unknown;
+                };
+                current = next;
+                cell[axis] -= size;
+            }
+        }
+
+        // Distance along the ray to the next tile boundary on each axis, and between boundaries
+        let mut next = [0.0; 2];
+        let mut delta = [0.0; 2];
+        let mut step = [0; 2];
+        for axis in 0..2 {
+            let fract = origin[axis] - origin[axis].floor();
+            if direction[axis] > 0.0 {
+                step[axis] = 1;
+                next[axis] = (1.0 - fract) / direction[axis];
+            } else if direction[axis] < 0.0 {
+                step[axis] = -1;
+                next[axis] = fract / -direction[axis];
+            } else {
+                next[axis] = f32::INFINITY;
+            }
+            delta[axis] = 1.0 / direction[axis].abs();
+        }
+
+        let mut distance = 0.0;
+        for _ in 0..MAX_RAY_STEPS {
+            let index = (cell[1] * size + cell[0]) as usize;
+            for layer in Layer::ALL {
+                let tile = current.layer(layer)[index];
+                if tiles.is_solid(tile) {
+                    return Ray::Hit(RayHit {
+                        chunk_id: current.id,
+                        position: [cell[0] as usize, cell[1] as usize],
+                        layer,
+                        tile,
+                        distance,
+                    });
+                }
+            }
+
+            let axis = if next[0] < next[1] { 0 } else { 1 };
+            distance = next[axis];
+            if distance > max_distance {
+                return Ray::Clear;
+            }
+            next[axis] += delta[axis];
+            cell[axis] += step[axis];
+
+            let direction = match (axis, step[axis]) {
+                (0, -1) => Direction::Left,
+                (0, _) => Direction::Right,
+                (_, -1) => Direction::Top,
+                _ => Direction::Bottom,
+            };
+            if cell[axis] < 0 || cell[axis] >= size {
+                let Some(next) = chunk(current.related[direction.to_number()]) else {
+                    return Ray::Unknown;
+                };
+                current = next;
+                cell[axis] = cell[axis].rem_euclid(size);
+            }
+        }
+        Ray::Unknown
+    }
+
+    // Whether no solid tile lies between the two points, false if the ray crosses unknown chunks
+    pub fn line_of_sight(
+        &self,
+        a: (ChunkId, [f32; 2]),
+        b: (ChunkId, [f32; 2]),
+        tiles: &TileRegistry,
+    ) -> bool {
+        let Some(b) = self.position_in(a.0, b.0, b.1, a.1) else {
+            return false;
+        };
+        let direction = [b[0] - a.1[0], b[1] - a.1[1]];
+        let distance = (direction[0] * direction[0] + direction[1] * direction[1]).sqrt();
+        if distance == 0.0 {
+            return true;
+        }
+        matches!(self.cast(a.0, a.1, direction, distance, tiles), Ray::Clear)
+    }
+
+    fn view_with<T: Copy + Default>(
+        &self,
+        chunk_id: ChunkId,
+        rect: [i32; 4],
+        layer: impl Fn(&Chunk) -> &[T],
+    ) -> Vec<T> {
+        let size = self.chunk_size as i32;
+        let mut view =
+            vec![T::default(); (rect[2] - rect[0]) as usize * (rect[3] - rect[1]) as usize];
+        for cy in rect[1].div_euclid(size)..=rect[3].div_euclid(size) {
             let mut chunk_id = Some(chunk_id);
             if cy < 0 {
                 for _ in 0..-cy {
@@ -194,8 +760,7 @@ impl Field {
                     });
                 }
             }
-            for cx in rect[0].div_euclid(CHUNK_SIZE as i32)..=rect[2].div_euclid(CHUNK_SIZE as i32)
-            {
+            for cx in rect[0].div_euclid(size)..=rect[2].div_euclid(size) {
                 let mut chunk_id = chunk_id;
                 if cx < 0 {
                     for _ in 0..-cx {
@@ -211,16 +776,16 @@ impl Field {
                     }
                 }
                 if let Some(chunk) = chunk_id.and_then(|id| self.chunk(id)) {
-                    for dy in 0..CHUNK_SIZE as i32 {
-                        for dx in 0..CHUNK_SIZE as i32 {
-                            let x = cx * CHUNK_SIZE as i32 + dx;
-                            let y = cy * CHUNK_SIZE as i32 + dy;
+                    let data = layer(chunk);
+                    for dy in 0..size {
+                        for dx in 0..size {
+                            let x = cx * size + dx;
+                            let y = cy * size + dy;
                             if x < rect[0] || x >= rect[2] || y < rect[1] || y >= rect[3] {
                                 continue;
                             }
                             view[(y - rect[1]) as usize * (rect[2] - rect[0]) as usize
-                                + (x - rect[0]) as usize] =
-                                chunk.data[dy as usize * CHUNK_SIZE + dx as usize];
+                                + (x - rect[0]) as usize] = data[(dy * size + dx) as usize];
                         }
                     }
                 }
@@ -229,3 +794,186 @@ impl Field {
         view
     }
 }
+
+#[test]
+fn test_portal() {
+    let mut field = Field::new();
+    let a = ChunkId::MIN;
+    let b = field.generate_chunk(a, Direction::Right).unwrap();
+    let c = field.generate_chunk(a, Direction::Bottom).unwrap();
+    assert_eq!(
+        field.chunk(a).unwrap().related[Direction::Right.to_number()],
+        b.get()
+    );
+
+    // Walking right from `a` leads to `c` and left from `c` leads back to `a`
+    let modified = field.link_portal(a, Direction::Right, c).unwrap();
+    assert_eq!(modified, vec![a, b, c]);
+    assert_eq!(
+        field.chunk(a).unwrap().related[Direction::Right.to_number()],
+        c.get()
+    );
+    assert_eq!(
+        field.chunk(c).unwrap().related[Direction::Left.to_number()],
+        a.get()
+    );
+    assert_eq!(
+        field.chunk(b).unwrap().related[Direction::Left.to_number()],
+        0
+    );
+
+    // Generating chunks around doesn't overwrite the portal
+    let d = field.generate_chunk(c, Direction::Right).unwrap();
+    assert_eq!(
+        field.chunk(a).unwrap().related[Direction::Right.to_number()],
+        c.get()
+    );
+    assert_eq!(
+        field.chunk(c).unwrap().related[Direction::Left.to_number()],
+        a.get()
+    );
+    assert_eq!(
+        field.chunk(d).unwrap().related[Direction::Left.to_number()],
+        c.get()
+    );
+
+    let size = field.chunk_size() as i32;
+    let view = field.view(a, Layer::Ground, [size, 0, size + 1, 1]);
+    assert_eq!(view[0], field.chunk(c).unwrap().ground[0]);
+
+    let modified = field.unlink_portal(a, Direction::Right).unwrap();
+    assert_eq!(modified, vec![a, c]);
+    assert_eq!(
+        field.chunk(a).unwrap().related[Direction::Right.to_number()],
+        0
+    );
+    assert!(field.unlink_portal(a, Direction::Right).is_none());
+}
+
+#[test]
+fn test_chunk_size() {
+    let mut field = Field::with_chunk_size(5);
+    let a = ChunkId::MIN;
+    let b = field.generate_chunk(a, Direction::Bottom).unwrap();
+    assert_eq!(field.chunk(b).unwrap().ground.len(), 25);
+
+    field.chunks.get_mut(&b).unwrap().meta[2] = 1000;
+    assert_eq!(field.view_meta(a, [0, 4, 3, 6])[5], 1000);
+
+    field.chunks.get_mut(&a).unwrap().walls.fill(tile::VOID);
+    field.chunks.get_mut(&b).unwrap().walls.fill(tile::VOID);
+    field.chunks.get_mut(&b).unwrap().walls[0] = tile::WALL;
+    let tiles = TileRegistry::new(vec![tile::TileKind {
+        id: tile::WALL,
+        name: "wall".to_string(),
+        solid: true,
+        ..Default::default()
+    }]);
+    assert_eq!(
+        field.solid_view(a, [0, 4, 2, 6], &tiles),
+        vec![false, false, true, false]
+    );
+}
+
+#[test]
+fn test_history() {
+    let mut field = Field::new();
+    field.keep_history(3);
+    let a = ChunkId::MIN;
+    let copy = field.chunk(a).unwrap().clone();
+
+    assert!(field.set_tile(a, Layer::Decoration, [1, 2], 7));
+    assert!(!field.set_tile(a, Layer::Decoration, [1, 2], 7));
+    assert!(!field.set_tile(a, Layer::Decoration, [100, 2], 7));
+    let b = field.generate_chunk(a, Direction::Left).unwrap();
+    assert_eq!(field.chunk(a).unwrap().version, 2);
+    assert_eq!(field.take_modified(), vec![(a, 0)]);
+    assert!(field.take_modified().is_empty());
+
+    // A copy catches up by applying the diff
+    let changes = field.diff(a, 0).unwrap();
+    assert_eq!(changes.len(), 2);
+    let mut client = Field::new();
+    client.set_existed_chunk(copy, false);
+    assert!(client.apply_diff(a, 0, 2, &changes));
+    assert!(!client.apply_diff(a, 0, 2, &changes));
+    let chunk = client.chunk(a).unwrap();
+    assert_eq!(chunk.version, 2);
+    assert_eq!(chunk.decoration[2 * client.chunk_size() + 1], 7);
+    assert_eq!(chunk.related[Direction::Left.to_number()], b.get());
+
+    // Changes older than the history can't be diffed
+    for i in 0..3 {
+        field.set_tile(a, Layer::Ground, [i, 0], 100);
+    }
+    assert_eq!(field.diff(a, 5).unwrap(), vec![]);
+    assert_eq!(field.diff(a, 2).unwrap().len(), 3);
+    assert!(field.diff(a, 1).is_none());
+    assert!(field.diff(a, 6).is_none());
+    assert!(!client.apply_diff(a, 1, 5, &[]));
+
+    // A new chunk linked to more than the chunk it was generated from is still at its first version
+    let c = field.generate_chunk(a, Direction::Top).unwrap();
+    field.take_modified();
+    let d = field.generate_chunk(b, Direction::Top).unwrap();
+    assert_eq!(
+        field.chunk(d).unwrap().related[Direction::Right.to_number()],
+        c.get()
+    );
+    assert_eq!(field.chunk(d).unwrap().version, 0);
+    let modified = field.take_modified();
+    assert!(modified.iter().all(|(id, _)| *id != d));
+    assert!(modified.iter().any(|(id, _)| *id == c));
+}
+
+#[test]
+fn test_raycast() {
+    let mut field = Field::new();
+    let a = ChunkId::MIN;
+    let b = field.generate_chunk(a, Direction::Right).unwrap();
+    for chunk in field.chunks.values_mut() {
+        chunk.walls.fill(tile::VOID);
+    }
+    field.set_tile(b, Layer::Walls, [3, 5], tile::WALL);
+    let tiles = TileRegistry::new(vec![tile::TileKind {
+        id: tile::WALL,
+        name: "wall".to_string(),
+        solid: true,
+        ..Default::default()
+    }]);
+
+    let hit = field
+        .raycast(a, [10.0, 5.5], [1.0, 0.0], 20.0, &tiles)
+        .unwrap();
+    assert_eq!(hit.chunk_id, b);
+    assert_eq!(hit.position, [3, 5]);
+    assert_eq!(hit.layer, Layer::Walls);
+    assert_eq!(hit.tile, tile::WALL);
+    assert!((hit.distance - 9.0).abs() < 1e-5);
+
+    // Diagonal ray from the other chunk
+    let hit = field
+        .raycast(b, [0.5, 2.5], [1.0, 1.0], 20.0, &tiles)
+        .unwrap();
+    assert_eq!((hit.chunk_id, hit.position), (b, [3, 5]));
+
+    assert_eq!(field.raycast(a, [10.0, 5.5], [1.0, 0.0], 8.0, &tiles), None);
+    // Leaves the known chunks
+    assert_eq!(
+        field.raycast(a, [10.0, 5.5], [-1.0, 0.0], 20.0, &tiles),
+        None
+    );
+
+    assert!(!field.line_of_sight((a, [10.0, 5.5]), (b, [6.0, 5.5]), &tiles));
+    assert!(field.line_of_sight((a, [10.0, 5.5]), (b, [6.0, 7.5]), &tiles));
+    assert!(field.line_of_sight((b, [6.0, 7.5]), (a, [10.0, 5.5]), &tiles));
+    // Passes above b, where no chunk is known
+    assert!(!field.line_of_sight((a, [10.0, 5.5]), (b, [6.0, -1.0]), &tiles));
+
+    // A chunk portalled to itself never ends the ray
+    field.chunks.get_mut(&a).unwrap().related[Direction::Left.to_number()] = a.get();
+    assert_eq!(
+        field.raycast(a, [10.0, 5.5], [-1.0, 0.0], f32::INFINITY, &tiles),
+        None
+    );
+}
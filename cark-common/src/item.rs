@@ -0,0 +1,64 @@
+use crate::{field::Layer, tile::TileId};
+
+pub type ItemId = u32;
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+pub struct ItemKind {
+    pub id: ItemId,
+    pub name: String,
+    // Column and row of the chip in the sprite sheet
+    #[serde(default)]
+    pub sprite: [u32; 2],
+    // Number placed in each chunk when it is generated
+    #[serde(default)]
+    pub scatter: u32,
+    // What happens when the item is used, it can't be used if None
+    #[serde(default)]
+    pub on_use: Option<ItemUse>,
+}
+
+// Externally tagged, as postcard can't decode internally tagged enums
+// e.g. on_use = { place_tile = { layer = "Walls", tile = 1 } }
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ItemUse {
+    // Places the tile under the user and uses the item up
+    PlaceTile { layer: Layer, tile: TileId },
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
+pub struct ItemRegistry {
+    pub items: Vec<ItemKind>,
+}
+
+impl ItemRegistry {
+    pub fn new(items: Vec<ItemKind>) -> Self {
+        Self { items }
+    }
+
+    pub fn get(&self, id: ItemId) -> Option<&ItemKind> {
+        self.items.iter().find(|item| item.id == id)
+    }
+
+    pub fn by_name(&self, name: &str) -> Option<&ItemKind> {
+        self.items.iter().find(|item| item.name == name)
+    }
+}
+
+#[test]
+fn test() {
+    let items = ItemRegistry::new(vec![ItemKind {
+        id: 1,
+        name: "brick".to_string(),
+        sprite: [1, 2],
+        scatter: 1,
+        on_use: Some(ItemUse::PlaceTile {
+            layer: Layer::Walls,
+            tile: 1,
+        }),
+    }]);
+    // Sent to clients in `Joined`
+    let buf = postcard::to_allocvec(&items).unwrap();
+    let decoded: ItemRegistry = postcard::from_bytes(&buf).unwrap();
+    assert_eq!(decoded.items, items.items);
+}
@@ -1,6 +1,13 @@
+pub mod codec;
 pub mod direction;
+pub mod entity;
 pub mod field;
+pub mod item;
 pub mod model;
+pub mod path;
+pub mod physics;
+pub mod tile;
+pub mod transport;
 pub mod udp_stat;
 
 pub use postcard::to_io as write;
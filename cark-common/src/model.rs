@@ -1,6 +1,11 @@
+use std::collections::BTreeMap;
+
 use crate::{
     direction::Direction,
-    field::{Chunk, ChunkId},
+    entity::{Entity, EntityId, Inventory},
+    field::{Chunk, ChunkChange, ChunkId},
+    item::{ItemId, ItemRegistry},
+    tile::TileRegistry,
     udp_stat::Sequence,
 };
 
@@ -16,18 +21,41 @@ pub struct Character {
     pub name: String,
     pub chunk_id: ChunkId,
     pub position: [f32; 2],
+    // Hosted by the server rather than a connected player
+    pub npc: bool,
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
 pub struct Join {
     pub name: String,
+    // The account is created with this password on the first join
+    pub password: String,
+}
+
+// Keep the password out of the logs
+impl std::fmt::Debug for Join {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Join")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub struct Joined {
     pub user_id: u64,
+    // Changes every time the server starts with a new world
+    pub world_id: u64,
+    pub chunk_size: usize,
     pub chunk: Chunk,
     pub characters: Vec<JoinedCharacter>,
+    pub entities: Vec<Entity>,
+    pub tiles: TileRegistry,
+    pub items: ItemRegistry,
+    // Saved by `ClientMessage::Settings`
+    pub settings: BTreeMap<String, String>,
+    // Identifies the UDP session in every datagram, so that it survives address changes
+    pub udp_token: u64,
 }
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub struct JoinedCharacter {
@@ -35,6 +63,7 @@ pub struct JoinedCharacter {
     pub name: String,
     pub chunk_id: ChunkId,
     pub position: [f32; 2],
+    pub npc: bool,
 }
 
 // #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
@@ -59,21 +88,78 @@ pub enum ClientMessage {
         velocity: [f32; 2],
     },
     Leave,
+    // Replace the settings saved with the account
+    Settings {
+        settings: BTreeMap<String, String>,
+    },
+    // Request the chunk next to `id` in `direction`, generating it if necessary
     RequestChunk {
         id: ChunkId,
         direction: Direction,
     },
+    // Request the changes of a chunk the client already has a copy of
+    SyncChunk {
+        id: ChunkId,
+        known_version: u64,
+    },
+    DropItem {
+        item: ItemId,
+        count: u32,
+    },
+    UseItem {
+        item: ItemId,
+    },
+    // Hand items to a player nearby
+    GiveItem {
+        to: u64,
+        item: ItemId,
+        count: u32,
+    },
+    // Reply to `ServerMessage::Ping` over UDP
+    Pong {
+        nonce: u64,
+    },
+    // Over TCP, whether the server should send what goes over UDP by TCP instead
+    UdpFallback {
+        enabled: bool,
+    },
+}
+
+impl ClientMessage {
+    // Name of the variant, for logs and metrics
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Join(_) => "Join",
+            Self::PublicChatMessage(_) => "PublicChatMessage",
+            Self::Position { .. } => "Position",
+            Self::Leave => "Leave",
+            Self::Settings { .. } => "Settings",
+            Self::RequestChunk { .. } => "RequestChunk",
+            Self::SyncChunk { .. } => "SyncChunk",
+            Self::DropItem { .. } => "DropItem",
+            Self::UseItem { .. } => "UseItem",
+            Self::GiveItem { .. } => "GiveItem",
+            Self::Pong { .. } => "Pong",
+            Self::UdpFallback { .. } => "UdpFallback",
+        }
+    }
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub enum ServerMessage {
-    Joined(Joined),
+    // Boxed, it is much larger than the other messages
+    Joined(Box<Joined>),
+    // Reply to `Join` when the name is taken, the password is wrong and so on
+    JoinRejected {
+        reason: String,
+    },
     // UpdateField(UpdateField),
     PlayerJoined {
         id: u64,
         name: String,
         chunk_id: ChunkId,
         position: [f32; 2],
+        npc: bool,
     },
     PlayerLeft {
         user_id: u64,
@@ -87,14 +173,71 @@ pub enum ServerMessage {
     Chunk {
         chunk: Chunk,
     },
+    // Reply to `SyncChunk` when the client's copy is up to date
+    ChunkUnchanged {
+        id: ChunkId,
+    },
+    // Sent on every change of a chunk and in reply to `SyncChunk`
+    ChunkDiff {
+        id: ChunkId,
+        from_version: u64,
+        to_version: u64,
+        changes: Vec<ChunkChange>,
+    },
+    EntitySpawned {
+        entity: Entity,
+    },
+    EntityDespawned {
+        id: EntityId,
+    },
+    // The whole inventory of the player, sent on every change
+    Inventory {
+        inventory: Inventory,
+    },
+    // Sent over UDP to measure the round trip time
+    Ping {
+        nonce: u64,
+    },
+    // Text from the server operators
+    Notice {
+        text: String,
+    },
+    // Move the player's own character
+    Teleport {
+        chunk_id: ChunkId,
+        position: [f32; 2],
+    },
+}
+
+impl ServerMessage {
+    // Name of the variant, for logs and metrics
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Joined(_) => "Joined",
+            Self::JoinRejected { .. } => "JoinRejected",
+            Self::PlayerJoined { .. } => "PlayerJoined",
+            Self::PlayerLeft { .. } => "PlayerLeft",
+            Self::Position { .. } => "Position",
+            Self::Chunk { .. } => "Chunk",
+            Self::ChunkUnchanged { .. } => "ChunkUnchanged",
+            Self::ChunkDiff { .. } => "ChunkDiff",
+            Self::EntitySpawned { .. } => "EntitySpawned",
+            Self::EntityDespawned { .. } => "EntityDespawned",
+            Self::Inventory { .. } => "Inventory",
+            Self::Ping { .. } => "Ping",
+            Self::Notice { .. } => "Notice",
+            Self::Teleport { .. } => "Teleport",
+        }
+    }
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub enum ClientUdpMessage {
     Init {
-        id: u64,
+        token: u64,
     },
     Message {
+        token: u64,
         sequence: Sequence,
         message: ClientMessage,
     },
@@ -102,6 +245,7 @@ pub enum ClientUdpMessage {
 
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub enum ServerUdpMessage {
+    // Acknowledges `ClientUdpMessage::Init`
     Init,
     Message {
         sequence: Sequence,
@@ -0,0 +1,244 @@
+// A* pathfinding over the tiles of the field, crossing chunks by their related links.
+
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet, VecDeque},
+};
+
+use crate::{
+    direction::Direction,
+    field::{ChunkId, Field, Layer},
+    tile::TileRegistry,
+};
+
+// Costs of a straight and a diagonal move, scaled to stay in integers
+const STRAIGHT: u32 = 10;
+const DIAGONAL: u32 = 14;
+
+type Node = (ChunkId, [i32; 2]);
+
+// Tile centers to walk through from `from` to `to`, ending with `to` itself.
+// Gives up after visiting `max_nodes` tiles or if `to` can't be reached through the known chunks.
+pub fn find_path(
+    field: &Field,
+    tiles: &TileRegistry,
+    from: (ChunkId, [f32; 2]),
+    to: (ChunkId, [f32; 2]),
+    max_nodes: usize,
+) -> Option<Vec<(ChunkId, [f32; 2])>> {
+    let start = node(field, from)?;
+    let goal = node(field, to)?;
+    if is_blocked(field, tiles, goal) {
+        return None;
+    }
+
+    let mut open = BinaryHeap::new();
+    let mut costs: HashMap<Node, u32> = HashMap::new();
+    let mut came_from: HashMap<Node, Node> = HashMap::new();
+    costs.insert(start, 0);
+    open.push(Reverse((heuristic(field, start, goal), 0, start)));
+
+    let mut visited = 0;
+    while let Some(Reverse((_, cost, current))) = open.pop() {
+        if current == goal {
+            let mut path = vec![to];
+            let mut node = current;
+            while let Some(&prev) = came_from.get(&node) {
+                if prev != start {
+                    path.push(center(prev));
+                }
+                node = prev;
+            }
+            path.reverse();
+            return Some(path);
+        }
+        if cost > costs[&current] {
+            continue;
+        }
+        visited += 1;
+        if visited > max_nodes {
+            return None;
+        }
+
+        for dy in -1..=1 {
+            for dx in -1..=1 {
+                if dx == 0 && dy == 0 {
+                    continue;
+                }
+                let Some(next) = neighbor(field, current, [dx, dy]) else {
+                    continue;
+                };
+                if is_blocked(field, tiles, next) {
+                    continue;
+                }
+                let step = if dx != 0 && dy != 0 {
+                    // Don't cut corners
+                    let blocked =
+                        |d| neighbor(field, current, d).is_none_or(|n| is_blocked(field, tiles, n));
+                    if blocked([dx, 0]) || blocked([0, dy]) {
+                        continue;
+                    }
+                    DIAGONAL
+                } else {
+                    STRAIGHT
+                };
+                let cost = cost + step;
+                if costs.get(&next).is_none_or(|c| cost < *c) {
+                    costs.insert(next, cost);
+                    came_from.insert(next, current);
+                    open.push(Reverse((cost + heuristic(field, next, goal), cost, next)));
+                }
+            }
+        }
+    }
+    None
+}
+
+// The center of the nearest walkable tile around `from` that `accept` agrees with.
+// The search goes through solid tiles too, so that it also gets out of walls.
+pub fn nearest_free_tile(
+    field: &Field,
+    tiles: &TileRegistry,
+    from: (ChunkId, [f32; 2]),
+    max_nodes: usize,
+    accept: impl Fn((ChunkId, [f32; 2])) -> bool,
+) -> Option<(ChunkId, [f32; 2])> {
+    let start = node(field, from)?;
+    let mut visited = HashSet::from([start]);
+    let mut open = VecDeque::from([start]);
+    while let Some(current) = open.pop_front() {
+        if !is_blocked(field, tiles, current) && accept(center(current)) {
+            return Some(center(current));
+        }
+        for d in [
+            [1, 0],
+            [-1, 0],
+            [0, 1],
+            [0, -1],
+            [1, 1],
+            [-1, 1],
+            [1, -1],
+            [-1, -1],
+        ] {
+            if visited.len() >= max_nodes {
+                break;
+            }
+            if let Some(next) = neighbor(field, current, d) {
+                if visited.insert(next) {
+                    open.push_back(next);
+                }
+            }
+        }
+    }
+    None
+}
+
+fn node(field: &Field, (chunk_id, position): (ChunkId, [f32; 2])) -> Option<Node> {
+    field.chunk(chunk_id)?;
+    let size = field.chunk_size() as i32;
+    let cell = [position[0].floor() as i32, position[1].floor() as i32];
+    if cell.iter().any(|c| *c < 0 || *c >= size) {
+        return None;
+    }
+    Some((chunk_id, cell))
+}
+
+fn center((chunk_id, cell): Node) -> (ChunkId, [f32; 2]) {
+    (chunk_id, [cell[0] as f32 + 0.5, cell[1] as f32 + 0.5])
+}
+
+// The tile at `d` from `node`, moving along x first
+fn neighbor(field: &Field, (mut chunk_id, mut cell): Node, d: [i32; 2]) -> Option<Node> {
+    let size = field.chunk_size() as i32;
+    for (axis, negative, positive) in [
+        (0, Direction::Left, Direction::Right),
+        (1, Direction::Top, Direction::Bottom),
+    ] {
+        cell[axis] += d[axis];
+        let direction = if cell[axis] < 0 {
+            negative
+        } else if cell[axis] >= size {
+            positive
+        } else {
+            continue;
+        };
+        chunk_id = ChunkId::new(field.chunk(chunk_id)?.related[direction.to_number()])?;
+        field.chunk(chunk_id)?;
+        cell[axis] = cell[axis].rem_euclid(size);
+    }
+    Some((chunk_id, cell))
+}
+
+fn is_blocked(field: &Field, tiles: &TileRegistry, (chunk_id, cell): Node) -> bool {
+    let Some(chunk) = field.chunk(chunk_id) else {
+        return true;
+    };
+    let index = cell[1] as usize * chunk.size + cell[0] as usize;
+    Layer::ALL
+        .iter()
+        .any(|layer| tiles.is_solid(chunk.layer(*layer)[index]))
+}
+
+// Octile distance to the goal if it's in a chunk around, otherwise nothing is known
+fn heuristic(field: &Field, node: Node, goal: Node) -> u32 {
+    let (_, position) = center(node);
+    let Some(goal) = field.position_in(node.0, goal.0, center(goal).1, position) else {
+        return 0;
+    };
+    let dx = (goal[0] - position[0]).abs() as u32;
+    let dy = (goal[1] - position[1]).abs() as u32;
+    STRAIGHT * dx.max(dy) + (DIAGONAL - STRAIGHT) * dx.min(dy)
+}
+
+#[test]
+fn test() {
+    use crate::tile::{self, TileKind};
+
+    let mut field = Field::new();
+    let a = ChunkId::MIN;
+    let b = field.generate_chunk(a, Direction::Right).unwrap();
+    for chunk in field.chunks.values_mut() {
+        chunk.walls.fill(tile::VOID);
+    }
+    let tiles = TileRegistry::new(vec![TileKind {
+        id: tile::WALL,
+        name: "wall".to_string(),
+        solid: true,
+        ..Default::default()
+    }]);
+    // A wall across the right chunk with a gap at the bottom
+    for y in 0..15 {
+        field.set_tile(b, Layer::Walls, [4, y], tile::WALL);
+    }
+
+    let path = find_path(&field, &tiles, (a, [8.5, 2.5]), (b, [8.5, 2.5]), 10000).unwrap();
+    assert_eq!(*path.last().unwrap(), (b, [8.5, 2.5]));
+    assert!(path.contains(&(b, [4.5, 15.5])));
+    // Every step moves to an adjacent tile
+    for w in path.windows(2) {
+        let p = field.position_in(w[0].0, w[1].0, w[1].1, w[0].1).unwrap();
+        assert!((p[0] - w[0].1[0]).abs() <= 1.0 && (p[1] - w[0].1[1]).abs() <= 1.0);
+    }
+
+    // Walled in
+    field.set_tile(b, Layer::Walls, [4, 15], tile::WALL);
+    assert_eq!(
+        find_path(&field, &tiles, (a, [8.5, 2.5]), (b, [8.5, 2.5]), 10000),
+        None
+    );
+    assert_eq!(
+        find_path(&field, &tiles, (a, [8.5, 2.5]), (b, [4.5, 2.5]), 10000),
+        None
+    );
+
+    // Out of the wall, to either side of it
+    let free = nearest_free_tile(&field, &tiles, (b, [4.5, 2.5]), 100, |_| true).unwrap();
+    assert!(free == (b, [3.5, 2.5]) || free == (b, [5.5, 2.5]));
+    // Across the chunk edge when the tiles nearby are not accepted
+    let free = nearest_free_tile(&field, &tiles, (a, [15.5, 2.5]), 100, |(id, _)| id == b);
+    assert_eq!(free, Some((b, [0.5, 2.5])));
+    assert_eq!(
+        nearest_free_tile(&field, &tiles, (a, [8.5, 2.5]), 100, |_| false),
+        None
+    );
+}
@@ -0,0 +1,725 @@
+// Movement of characters on the field.
+//
+// `step` is a pure function of its arguments so that the client, the server
+// and tests get the same result for the same inputs. Run it through
+// `FixedStep` to make the result independent of the frame rate.
+
+use crate::{
+    direction::Direction,
+    field::{ChunkId, Field, Layer},
+    tile::TileRegistry,
+};
+
+// Distance kept from a wall after an impact so that the next cast doesn't start touching it
+const SKIN: f32 = 1e-3;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PhysicsParams {
+    // Acceleration by the input, in tiles per second squared
+    pub acceleration: f32,
+    // Fraction of the approaching speed kept when two characters collide
+    pub restitution: f32,
+    pub radius: f32,
+    // Duration of a single step of `FixedStep`
+    pub timestep: f32,
+    // Upper bound of the steps run by a single `FixedStep::advance`
+    pub max_steps: usize,
+    // Each step is split into this many sub-steps
+    pub sub_steps: usize,
+    // Wall impacts resolved in a sub-step before the body is stopped for the rest of it
+    pub max_iterations: usize,
+}
+
+impl Default for PhysicsParams {
+    fn default() -> Self {
+        Self {
+            acceleration: 60.0,
+            restitution: 0.5,
+            radius: 0.5,
+            timestep: 1.0 / 120.0,
+            max_steps: 16,
+            sub_steps: 2,
+            max_iterations: 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Body {
+    pub chunk_id: ChunkId,
+    pub position: [f32; 2],
+    pub velocity: [f32; 2],
+    // `f32::INFINITY` for bodies that can't be pushed
+    pub mass: f32,
+}
+
+impl Body {
+    pub fn new(chunk_id: ChunkId, position: [f32; 2], velocity: [f32; 2]) -> Self {
+        Self {
+            chunk_id,
+            position,
+            velocity,
+            mass: 1.0,
+        }
+    }
+
+    fn inverse_mass(&self) -> f32 {
+        1.0 / self.mass
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MoveInput {
+    // Each axis in -1.0..=1.0
+    pub direction: [f32; 2],
+}
+
+impl MoveInput {
+    pub fn from_keys(up: bool, down: bool, left: bool, right: bool) -> Self {
+        let axis = |negative: bool, positive: bool| positive as i32 as f32 - negative as i32 as f32;
+        Self {
+            direction: [axis(left, right), axis(up, down)],
+        }
+    }
+}
+
+// Advance `body` by `dt` seconds.
+// `others` are the bodies of the other characters. They push this one away
+// but are left as they are, use `step_bodies` to move them together.
+pub fn step(
+    params: &PhysicsParams,
+    body: &Body,
+    input: &MoveInput,
+    field: &Field,
+    tiles: &TileRegistry,
+    others: &[Body],
+    dt: f32,
+) -> Body {
+    let mut body = *body;
+    let dt = dt / params.sub_steps.max(1) as f32;
+    for _ in 0..params.sub_steps.max(1) {
+        accelerate(params, &mut body, input, field, tiles, dt);
+        for other in others {
+            collide(params, &mut body, &mut other.clone(), field, dt);
+        }
+        move_against_walls(params, &mut body, field, tiles, dt);
+        cross_chunk(&mut body, field);
+    }
+    body
+}
+
+// Advance all `bodies` by `dt` seconds, resolving the collisions between them.
+// `inputs` are matched to `bodies` by index, missing ones are treated as no input.
+pub fn step_bodies(
+    params: &PhysicsParams,
+    bodies: &mut [Body],
+    inputs: &[MoveInput],
+    field: &Field,
+    tiles: &TileRegistry,
+    dt: f32,
+) {
+    let dt = dt / params.sub_steps.max(1) as f32;
+    for _ in 0..params.sub_steps.max(1) {
+        for (i, body) in bodies.iter_mut().enumerate() {
+            let input = inputs.get(i).copied().unwrap_or_default();
+            accelerate(params, body, &input, field, tiles, dt);
+        }
+        for i in 0..bodies.len() {
+            let (head, tail) = bodies.split_at_mut(i + 1);
+            for other in tail {
+                collide(params, &mut head[i], other, field, dt);
+            }
+        }
+        for body in bodies.iter_mut() {
+            move_against_walls(params, body, field, tiles, dt);
+            cross_chunk(body, field);
+        }
+    }
+}
+
+// Apply the input and the friction of the tile under the body
+fn accelerate(
+    params: &PhysicsParams,
+    body: &mut Body,
+    input: &MoveInput,
+    field: &Field,
+    tiles: &TileRegistry,
+    dt: f32,
+) {
+    let tile = field.tile(
+        body.chunk_id,
+        Layer::Ground,
+        [
+            body.position[0].floor() as i32,
+            body.position[1].floor() as i32,
+        ],
+    );
+    let friction = tiles.get(tile).friction.powf(dt);
+    for i in 0..2 {
+        body.velocity[i] =
+            body.velocity[i] * friction + input.direction[i] * params.acceleration * dt;
+    }
+}
+
+// Move the body along its velocity against solid tiles.
+// Depending on the restitution of the tile hit, the body slides along it or bounces off it.
+fn move_against_walls(
+    params: &PhysicsParams,
+    body: &mut Body,
+    field: &Field,
+    tiles: &TileRegistry,
+    mut dt: f32,
+) {
+    for _ in 0..params.max_iterations {
+        let Some((toi, normal, restitution)) = wall_impact(params, body, field, tiles, dt) else {
+            body.position[0] += body.velocity[0] * dt;
+            body.position[1] += body.velocity[1] * dt;
+            return;
+        };
+        for (i, n) in normal.iter().enumerate() {
+            body.position[i] += body.velocity[i] * toi + n * SKIN;
+        }
+        let approaching = dot(body.velocity, normal);
+        for (v, n) in body.velocity.iter_mut().zip(normal) {
+            *v -= (1.0 + restitution) * approaching * n;
+        }
+        dt -= toi;
+    }
+    // Still hitting walls, most likely stuck in a corner. Stay there for the rest of the sub-step.
+}
+
+// The earliest impact with the solid tiles swept by the body within `dt`,
+// with the normal and the restitution of the tile
+fn wall_impact(
+    params: &PhysicsParams,
+    body: &Body,
+    field: &Field,
+    tiles: &TileRegistry,
+    dt: f32,
+) -> Option<(f32, [f32; 2], f32)> {
+    let rect = swept_rect(body, params.radius, dt);
+    let width = rect[2] - rect[0];
+    let views: Vec<_> = Layer::ALL
+        .iter()
+        .map(|layer| field.view(body.chunk_id, *layer, rect))
+        .collect();
+    (0..views[0].len())
+        .filter_map(|i| {
+            let tile = views
+                .iter()
+                .map(|view| tiles.get(view[i]))
+                .find(|tile| tile.solid)?;
+            let x = (i as i32 % width + rect[0]) as f32;
+            let y = (i as i32 / width + rect[1]) as f32;
+            let (toi, normal) = cast_circle_box(
+                body.position,
+                body.velocity,
+                params.radius,
+                [x, y, x + 1.0, y + 1.0],
+            )?;
+            Some((toi, normal, tile.restitution))
+        })
+        .filter(|(toi, _, _)| *toi <= dt)
+        .min_by(|a, b| a.0.total_cmp(&b.0))
+}
+
+// Tiles that the body may touch while moving for `dt`
+fn swept_rect(body: &Body, radius: f32, dt: f32) -> [i32; 4] {
+    let mut rect = [0; 4];
+    for i in 0..2 {
+        let end = body.position[i] + body.velocity[i] * dt;
+        rect[i] = (body.position[i].min(end) - radius).floor() as i32;
+        rect[i + 2] = (body.position[i].max(end) + radius).floor() as i32 + 1;
+    }
+    rect
+}
+
+// Time of impact of a moving circle with an axis-aligned box [x0, y0, x1, y1].
+// Returns the time and the normal of the box at the contact, or None if the
+// circle doesn't approach the box or already overlaps it.
+pub fn cast_circle_box(
+    position: [f32; 2],
+    velocity: [f32; 2],
+    radius: f32,
+    rect: [f32; 4],
+) -> Option<(f32, [f32; 2])> {
+    // The circle hits the box when its center hits the box grown by the radius,
+    // which is made of the faces moved out by the radius and a circle at each corner.
+    let mut best: Option<(f32, [f32; 2])> = None;
+    let mut hit = |toi: f32, normal: [f32; 2]| {
+        if toi >= 0.0 && best.is_none_or(|(t, _)| toi < t) {
+            best = Some((toi, normal));
+        }
+    };
+
+    for axis in 0..2 {
+        let other = 1 - axis;
+        let v = velocity[axis];
+        let (plane, normal) = if v > 0.0 {
+            (rect[axis] - radius, -1.0)
+        } else if v < 0.0 {
+            (rect[axis + 2] + radius, 1.0)
+        } else {
+            continue;
+        };
+        let toi = (plane - position[axis]) / v;
+        let p = position[other] + velocity[other] * toi;
+        if rect[other] <= p && p <= rect[other + 2] {
+            let mut n = [0.0; 2];
+            n[axis] = normal;
+            hit(toi, n);
+        }
+    }
+
+    if velocity != [0.0, 0.0] {
+        for corner in [
+            [rect[0], rect[1]],
+            [rect[2], rect[1]],
+            [rect[0], rect[3]],
+            [rect[2], rect[3]],
+        ] {
+            let d = [position[0] - corner[0], position[1] - corner[1]];
+            if let Some(toi) = cast_point(d, velocity, radius) {
+                let p = [d[0] + velocity[0] * toi, d[1] + velocity[1] * toi];
+                let len = length(p);
+                hit(toi, [p[0] / len, p[1] / len]);
+            }
+        }
+    }
+
+    best
+}
+
+// Time when a point at `d` moving by `velocity` comes within `radius` of the origin.
+// None if it doesn't approach or is already that close.
+fn cast_point(d: [f32; 2], velocity: [f32; 2], radius: f32) -> Option<f32> {
+    let a = dot(velocity, velocity);
+    let b = dot(d, velocity);
+    let c = dot(d, d) - radius * radius;
+    let disc = b * b - a * c;
+    if c < 0.0 || b >= 0.0 || disc < 0.0 {
+        return None;
+    }
+    Some((-b - disc.sqrt()) / a)
+}
+
+// Resolve the collision of two characters within `dt`, pushing both by their masses
+fn collide(params: &PhysicsParams, a: &mut Body, b: &mut Body, field: &Field, dt: f32) {
+    let Some(b_position) = field.position_in(a.chunk_id, b.chunk_id, b.position, a.position) else {
+        return;
+    };
+    let inverse_mass = a.inverse_mass() + b.inverse_mass();
+    if inverse_mass == 0.0 {
+        return;
+    }
+    let d = [a.position[0] - b_position[0], a.position[1] - b_position[1]];
+    let v = [a.velocity[0] - b.velocity[0], a.velocity[1] - b.velocity[1]];
+    let contact = params.radius * 2.0;
+    let distance = length(d);
+
+    let (toi, normal) = if distance < contact {
+        // Already overlapping, separate them first
+        let normal = if distance > 0.0 {
+            [d[0] / distance, d[1] / distance]
+        } else {
+            [1.0, 0.0]
+        };
+        let depth = contact - distance;
+        for (i, n) in normal.iter().enumerate() {
+            a.position[i] += n * depth * a.inverse_mass() / inverse_mass;
+            b.position[i] -= n * depth * b.inverse_mass() / inverse_mass;
+        }
+        (0.0, normal)
+    } else {
+        let Some(toi) = cast_point(d, v, contact).filter(|toi| *toi <= dt) else {
+            return;
+        };
+        let p = [d[0] + v[0] * toi, d[1] + v[1] * toi];
+        let len = length(p);
+        (toi, [p[0] / len, p[1] / len])
+    };
+
+    let approaching = dot(v, normal);
+    if approaching >= 0.0 {
+        return;
+    }
+    let impulse = -(1.0 + params.restitution) * approaching / inverse_mass;
+    for (i, n) in normal.iter().enumerate() {
+        let dva = n * impulse * a.inverse_mass();
+        let dvb = -n * impulse * b.inverse_mass();
+        a.velocity[i] += dva;
+        b.velocity[i] += dvb;
+        // Moving with the new velocity for the whole step then ends up where
+        // the bodies would be if they had changed the velocity at the impact
+        a.position[i] -= dva * toi;
+        b.position[i] -= dvb * toi;
+    }
+}
+
+// Whether two circles overlap, seen from the chunk of `a`
+pub fn touching(
+    field: &Field,
+    a: (ChunkId, [f32; 2]),
+    a_radius: f32,
+    b: (ChunkId, [f32; 2]),
+    b_radius: f32,
+) -> bool {
+    let Some(b_position) = field.position_in(a.0, b.0, b.1, a.1) else {
+        return false;
+    };
+    let d = [a.1[0] - b_position[0], a.1[1] - b_position[1]];
+    length(d) < a_radius + b_radius
+}
+
+// Move the body to the related chunk when it leaves its own, or stop it at the edge
+fn cross_chunk(body: &mut Body, field: &Field) {
+    let chunk_size = field.chunk_size() as f32;
+    for (axis, direction, beyond) in [
+        (0, Direction::Left, -1.0),
+        (0, Direction::Right, 1.0),
+        (1, Direction::Top, -1.0),
+        (1, Direction::Bottom, 1.0),
+    ] {
+        let outside = if beyond < 0.0 {
+            body.position[axis] < 0.0
+        } else {
+            body.position[axis] >= chunk_size
+        };
+        if !outside {
+            continue;
+        }
+        if let Some(chunk_id) = field
+            .chunk(body.chunk_id)
+            .and_then(|c| ChunkId::new(c.related[direction.to_number()]))
+        {
+            body.chunk_id = chunk_id;
+            body.position[axis] -= beyond * chunk_size;
+        } else {
+            body.position[axis] = if beyond < 0.0 { 0.0 } else { chunk_size - 0.1 };
+            body.velocity[axis] = 0.0;
+        }
+    }
+}
+
+fn dot(a: [f32; 2], b: [f32; 2]) -> f32 {
+    a[0] * b[0] + a[1] * b[1]
+}
+
+fn length(a: [f32; 2]) -> f32 {
+    dot(a, a).sqrt()
+}
+
+// Splits variable frame times into steps of `PhysicsParams::timestep`
+#[derive(Debug, Clone, Default)]
+pub struct FixedStep {
+    accumulator: f32,
+}
+
+impl FixedStep {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Number of steps to run for a frame of `dt` seconds
+    pub fn advance(&mut self, params: &PhysicsParams, dt: f32) -> usize {
+        self.accumulator += dt;
+        let steps = (self.accumulator / params.timestep) as usize;
+        self.accumulator -= steps as f32 * params.timestep;
+        if steps > params.max_steps {
+            // Too far behind, drop the time we can't catch up with
+            self.accumulator = 0.0;
+            return params.max_steps;
+        }
+        steps
+    }
+}
+
+#[cfg(test)]
+const BUMPER: crate::tile::TileId = 100;
+
+#[cfg(test)]
+fn test_world() -> (Field, TileRegistry) {
+    use crate::tile::{self, TileKind};
+
+    let mut field = Field::new();
+    for chunk in field.chunks.values_mut() {
+        chunk.walls.fill(tile::VOID);
+    }
+    let tiles = TileRegistry::new(vec![
+        TileKind {
+            id: tile::WALL,
+            name: "wall".to_string(),
+            solid: true,
+            ..Default::default()
+        },
+        TileKind {
+            id: tile::FLOOR,
+            name: "floor".to_string(),
+            ..Default::default()
+        },
+        TileKind {
+            id: BUMPER,
+            name: "bumper".to_string(),
+            solid: true,
+            restitution: 1.0,
+            ..Default::default()
+        },
+    ]);
+    (field, tiles)
+}
+
+#[test]
+fn test_cast_circle_box() {
+    let rect = [2.0, 0.0, 3.0, 1.0];
+    let (toi, normal) = cast_circle_box([0.0, 0.5], [1.0, 0.0], 0.5, rect).unwrap();
+    assert!((toi - 1.5).abs() < 1e-6);
+    assert_eq!(normal, [-1.0, 0.0]);
+
+    // Grazing the corner
+    let (toi, normal) = cast_circle_box([0.0, 1.3], [1.0, 0.0], 0.5, rect).unwrap();
+    assert!(toi > 1.5 && toi < 2.0);
+    assert!(normal[0] < 0.0 && normal[1] > 0.0);
+
+    assert!(cast_circle_box([0.0, 2.0], [1.0, 0.0], 0.5, rect).is_none());
+    assert!(cast_circle_box([0.0, 0.5], [-1.0, 0.0], 0.5, rect).is_none());
+}
+
+#[test]
+fn test_step() {
+    use crate::tile;
+
+    let (mut field, tiles) = test_world();
+    let params = PhysicsParams::default();
+    let id = ChunkId::MIN;
+    for y in 0..field.chunk_size() {
+        field.set_tile(id, Layer::Walls, [8, y], tile::WALL);
+    }
+
+    // Run into the wall with different frame times
+    let run = |frames: &[f32]| {
+        let mut fixed = FixedStep::new();
+        let mut body = Body::new(id, [4.0, 4.0], [0.0, 0.0]);
+        let input = MoveInput::from_keys(false, false, false, true);
+        let mut max_x = 0.0f32;
+        for frame in frames.iter().cycle().take(240) {
+            for _ in 0..fixed.advance(&params, *frame) {
+                body = step(&params, &body, &input, &field, &tiles, &[], params.timestep);
+                max_x = max_x.max(body.position[0]);
+            }
+        }
+        (body, max_x)
+    };
+    let (a, max_x) = run(&[1.0 / 60.0]);
+    assert_eq!(a.chunk_id, id);
+    assert!(max_x < 8.0 - params.radius);
+    assert!(a.position[0] > 6.0);
+
+    // Same steps, same result
+    assert_eq!(run(&[1.0 / 60.0]).0, a);
+    let (b, _) = run(&[1.0 / 30.0, 0.0]);
+    assert_eq!(b, a);
+}
+
+#[test]
+fn test_cross_chunk() {
+    let (mut field, tiles) = test_world();
+    let params = PhysicsParams::default();
+    let id = ChunkId::MIN;
+    let right = field.generate_chunk(id, Direction::Right).unwrap();
+    field
+        .chunks
+        .get_mut(&right)
+        .unwrap()
+        .walls
+        .fill(crate::tile::VOID);
+    let size = field.chunk_size() as f32;
+
+    let body = Body::new(id, [size - 0.01, 4.5], [2.0, 0.0]);
+    let body = step(
+        &params,
+        &body,
+        &MoveInput::default(),
+        &field,
+        &tiles,
+        &[],
+        0.1,
+    );
+    assert_eq!(body.chunk_id, right);
+    assert!(body.position[0] < 1.0);
+
+    // No chunk above
+    let body = Body::new(id, [4.5, 0.01], [0.0, -2.0]);
+    let body = step(
+        &params,
+        &body,
+        &MoveInput::default(),
+        &field,
+        &tiles,
+        &[],
+        0.1,
+    );
+    assert_eq!(body.chunk_id, id);
+    assert_eq!(body.position[1], 0.0);
+    assert_eq!(body.velocity[1], 0.0);
+}
+
+#[test]
+fn test_collide() {
+    let (mut field, tiles) = test_world();
+    let params = PhysicsParams {
+        restitution: 1.0,
+        ..Default::default()
+    };
+    let id = ChunkId::MIN;
+
+    // Equal masses swap their velocities
+    let mut bodies = [
+        Body::new(id, [4.0, 4.0], [1.0, 0.0]),
+        Body::new(id, [5.1, 4.0], [-1.0, 0.0]),
+    ];
+    step_bodies(&params, &mut bodies, &[], &field, &tiles, 0.1);
+    assert!(bodies[0].velocity[0] < 0.0 && bodies[1].velocity[0] > 0.0);
+    assert!((bodies[0].velocity[0] + bodies[1].velocity[0]).abs() < 1e-4);
+    assert!(bodies[1].position[0] - bodies[0].position[0] >= params.radius * 2.0);
+
+    // Too fast to overlap at the end of any step, but still collides
+    let mut bodies = [
+        Body::new(id, [1.0, 8.0], [150.0, 0.0]),
+        Body::new(id, [8.0, 8.0], [0.0, 0.0]),
+    ];
+    step_bodies(&params, &mut bodies, &[], &field, &tiles, 0.1);
+    assert!(bodies[0].position[0] < bodies[1].position[0]);
+    assert!(bodies[1].velocity[0] > 0.0);
+
+    // A heavy body is barely pushed
+    let mut bodies = [
+        Body::new(id, [4.0, 4.0], [2.0, 0.0]),
+        Body {
+            mass: 100.0,
+            ..Body::new(id, [5.05, 4.0], [0.0, 0.0])
+        },
+    ];
+    step_bodies(&params, &mut bodies, &[], &field, &tiles, 0.1);
+    assert!(bodies[0].velocity[0] < -1.3);
+    assert!(bodies[1].velocity[0] < 0.1);
+
+    // Across a chunk boundary and a portal
+    let size = field.chunk_size() as f32;
+    let right = field.generate_chunk(id, Direction::Right).unwrap();
+    let far = field.generate_chunk(right, Direction::Right).unwrap();
+    field.link_portal(id, Direction::Top, far).unwrap();
+    for chunk in field.chunks.values_mut() {
+        chunk.walls.fill(crate::tile::VOID);
+    }
+    for (position, other, other_position) in [
+        ([size - 0.6, 8.0], right, [0.2, 8.0]),
+        ([8.0, 0.6], far, [8.0, size - 0.2]),
+    ] {
+        let a = Body::new(id, position, [0.0, 0.0]);
+        let mut bodies = [a, Body::new(other, other_position, [0.0, 0.0])];
+        step_bodies(&params, &mut bodies, &[], &field, &tiles, 0.01);
+        let moved = [
+            bodies[0].position[0] - a.position[0],
+            bodies[0].position[1] - a.position[1],
+        ];
+        assert!(length(moved) > 0.05, "{:?}", bodies);
+        assert_ne!(bodies[1].position, other_position);
+    }
+}
+
+#[test]
+fn test_slide_and_bounce() {
+    use crate::tile;
+
+    let (mut field, tiles) = test_world();
+    let params = PhysicsParams::default();
+    let id = ChunkId::MIN;
+    for x in 0..field.chunk_size() {
+        field.set_tile(id, Layer::Walls, [x, 2], tile::WALL);
+        field.set_tile(id, Layer::Walls, [x, 13], BUMPER);
+    }
+
+    // Slides along the wall keeping the speed along it
+    let body = Body::new(id, [4.0, 4.0], [4.0, -20.0]);
+    let body = step(
+        &params,
+        &body,
+        &MoveInput::default(),
+        &field,
+        &tiles,
+        &[],
+        0.1,
+    );
+    assert!(body.position[1] >= 3.0 + params.radius);
+    assert!(body.velocity[1].abs() < 1e-3);
+    assert!(body.velocity[0] > 2.0);
+    assert!(body.position[0] > 4.2);
+
+    // Bounces off the bumper
+    let body = Body::new(id, [4.0, 11.0], [0.0, 20.0]);
+    let body = step(
+        &params,
+        &body,
+        &MoveInput::default(),
+        &field,
+        &tiles,
+        &[],
+        0.1,
+    );
+    assert!(body.position[1] <= 13.0 - params.radius);
+    assert!(body.velocity[1] < -10.0);
+}
+
+#[test]
+fn test_corner_and_high_speed() {
+    use crate::tile;
+
+    let (mut field, tiles) = test_world();
+    let id = ChunkId::MIN;
+    // A room of 4..12 on both axes
+    for i in 3..13 {
+        for [x, y] in [[i, 3], [i, 12], [3, i], [12, i]] {
+            field.set_tile(id, Layer::Walls, [x, y], tile::WALL);
+        }
+    }
+    let inside = |body: &Body| {
+        let min = 4.0 + 0.5 - 1e-3;
+        let max = 12.0 - 0.5 + 1e-3;
+        body.chunk_id == id
+            && (min..=max).contains(&body.position[0])
+            && (min..=max).contains(&body.position[1])
+    };
+
+    for sub_steps in [1, 4] {
+        let params = PhysicsParams {
+            sub_steps,
+            ..Default::default()
+        };
+        // Pushed into a corner
+        let mut body = Body::new(id, [8.0, 8.0], [0.0, 0.0]);
+        let input = MoveInput::from_keys(false, true, false, true);
+        for _ in 0..600 {
+            body = step(&params, &body, &input, &field, &tiles, &[], params.timestep);
+            assert!(inside(&body), "{:?}", body);
+        }
+        assert!(body.position[0] > 11.0 && body.position[1] > 11.0);
+
+        // Far faster than a tile per step, in every direction
+        for i in 0..32 {
+            let angle = i as f32 / 32.0 * std::f32::consts::TAU;
+            let mut body = Body::new(id, [8.0, 8.0], [angle.cos() * 500.0, angle.sin() * 500.0]);
+            for _ in 0..10 {
+                body = step(
+                    &params,
+                    &body,
+                    &MoveInput::default(),
+                    &field,
+                    &tiles,
+                    &[],
+                    0.1,
+                );
+                assert!(inside(&body), "{:?}", body);
+            }
+        }
+    }
+}
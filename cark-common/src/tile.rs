@@ -0,0 +1,84 @@
+pub type TileId = u8;
+
+// Tile ids used by the chunk generator
+pub const VOID: TileId = 0;
+pub const WALL: TileId = 1;
+pub const FLOOR: TileId = 2;
+pub const FLOOR_A: TileId = 3;
+pub const FLOOR_B: TileId = 4;
+pub const ID_BIT: TileId = 5;
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+pub struct TileKind {
+    pub id: TileId,
+    pub name: String,
+    #[serde(default)]
+    pub solid: bool,
+    // Fraction of the velocity kept after one second on this tile
+    #[serde(default = "default_friction")]
+    pub friction: f32,
+    // For solid tiles, 0.0 makes characters slide along the tile and 1.0 bounce off it
+    #[serde(default)]
+    pub restitution: f32,
+    #[serde(default)]
+    pub footstep: Option<Footstep>,
+    // Index into the tile sheet
+    #[serde(default)]
+    pub sprite: u32,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+pub struct Footstep {
+    pub volume: f32,
+    pub pitch: f32,
+}
+
+fn default_friction() -> f32 {
+    0.04
+}
+
+impl Default for TileKind {
+    fn default() -> Self {
+        Self {
+            id: VOID,
+            name: "unknown".to_string(),
+            solid: false,
+            friction: default_friction(),
+            restitution: 0.0,
+            footstep: None,
+            sprite: 0,
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
+pub struct TileRegistry {
+    pub tiles: Vec<TileKind>,
+    // Returned for ids that are not registered
+    #[serde(skip)]
+    unknown: TileKind,
+}
+
+impl TileRegistry {
+    pub fn new(tiles: Vec<TileKind>) -> Self {
+        Self {
+            tiles,
+            unknown: TileKind::default(),
+        }
+    }
+
+    pub fn get(&self, id: TileId) -> &TileKind {
+        self.tiles
+            .iter()
+            .find(|t| t.id == id)
+            .unwrap_or(&self.unknown)
+    }
+
+    pub fn by_name(&self, name: &str) -> Option<&TileKind> {
+        self.tiles.iter().find(|t| t.name == name)
+    }
+
+    pub fn is_solid(&self, id: TileId) -> bool {
+        self.get(id).solid
+    }
+}
@@ -0,0 +1,595 @@
+// What the client and the server talk over: the real sockets, or an in-process network for tests.
+// Everything is nonblocking, reporting WouldBlock when there is nothing to accept or receive.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket},
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+pub trait Transport {
+    fn connect(&self, addr: SocketAddr) -> io::Result<Box<dyn Stream>>;
+    fn listen(&self, addr: SocketAddr) -> io::Result<Box<dyn Listener>>;
+    fn bind(&self, addr: SocketAddr) -> io::Result<Box<dyn Datagram>>;
+}
+
+// A reliable and ordered byte stream, like TCP
+pub trait Stream: Read + Write + Send {
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+    // Both directions, reading gives 0 bytes afterwards
+    fn shutdown(&self) -> io::Result<()>;
+    // Unique among the open streams
+    fn id(&self) -> u64;
+}
+
+pub trait Listener: Send {
+    fn accept(&self) -> io::Result<(Box<dyn Stream>, SocketAddr)>;
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+// Unreliable datagrams, like UDP
+pub trait Datagram: Send {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize>;
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+    // Clock of the timers on the datagrams, such as retries and pings
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+pub fn resolve(addr: &str) -> io::Result<SocketAddr> {
+    addr.to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No address"))
+}
+
+// The real sockets
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Net;
+
+impl Transport for Net {
+    fn connect(&self, addr: SocketAddr) -> io::Result<Box<dyn Stream>> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nonblocking(true)?;
+        Ok(Box::new(stream))
+    }
+
+    fn listen(&self, addr: SocketAddr) -> io::Result<Box<dyn Listener>> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(Box::new(listener))
+    }
+
+    fn bind(&self, addr: SocketAddr) -> io::Result<Box<dyn Datagram>> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(Box::new(socket))
+    }
+}
+
+impl Stream for TcpStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, std::net::Shutdown::Both)
+    }
+
+    fn id(&self) -> u64 {
+        #[cfg(any(unix, target_os = "wasi"))]
+        {
+            use std::os::fd::AsRawFd;
+
+            self.as_raw_fd() as u64
+        }
+        #[cfg(windows)]
+        {
+            use std::os::windows::io::AsRawSocket;
+
+            self.as_raw_socket() as u64
+        }
+    }
+}
+
+impl Listener for TcpListener {
+    fn accept(&self) -> io::Result<(Box<dyn Stream>, SocketAddr)> {
+        let (stream, addr) = TcpListener::accept(self)?;
+        stream.set_nonblocking(true)?;
+        Ok((Box::new(stream), addr))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpListener::local_addr(self)
+    }
+}
+
+impl Datagram for UdpSocket {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, buf, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buf)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+}
+
+// How the loopback mistreats the traffic. Streams only get the latency as TCP hides the rest.
+#[derive(Debug, Clone, Default)]
+pub struct Impairment {
+    pub latency: Duration,
+    // Up to this much more latency for each datagram, which also reorders them
+    pub jitter: Duration,
+    // Probabilities for each datagram
+    pub loss: f64,
+    pub duplication: f64,
+    // Held back by one more latency, arriving after the ones sent later
+    pub reordering: f64,
+}
+
+// An in-process network on a virtual clock, which moves only by `advance`, so that the same seed
+// gives the same run. Addresses are told apart by the port and unspecified IPs are 127.0.0.1.
+#[derive(Clone)]
+pub struct Loopback {
+    network: Arc<Mutex<Network>>,
+}
+
+struct Network {
+    epoch: Instant,
+    elapsed: Duration,
+    impairment: Impairment,
+    rng: StdRng,
+    next_port: u16,
+    next_id: u64,
+    // Datagrams on their way to each bound port
+    inboxes: HashMap<u16, Vec<Packet>>,
+    // Streams waiting to be accepted on each listening port
+    backlogs: HashMap<u16, VecDeque<StreamEnd>>,
+    pipes: HashMap<u64, Pipe>,
+    // Keeps the datagrams due at the same time in the order sent
+    order: u64,
+}
+
+struct Packet {
+    due: Duration,
+    order: u64,
+    from: SocketAddr,
+    data: Vec<u8>,
+}
+
+// One direction of a stream
+#[derive(Default)]
+struct Pipe {
+    chunks: VecDeque<(Duration, Vec<u8>)>,
+    closed: bool,
+}
+
+struct StreamEnd {
+    id: u64,
+    peer: SocketAddr,
+    read: u64,
+    write: u64,
+}
+
+impl Loopback {
+    pub fn new(impairment: Impairment, seed: u64) -> Self {
+        Self {
+            network: Arc::new(Mutex::new(Network {
+                epoch: Instant::now(),
+                elapsed: Duration::ZERO,
+                impairment,
+                rng: StdRng::seed_from_u64(seed),
+                next_port: 49152,
+                next_id: 1,
+                inboxes: HashMap::new(),
+                backlogs: HashMap::new(),
+                pipes: HashMap::new(),
+                order: 0,
+            })),
+        }
+    }
+
+    pub fn set_impairment(&self, impairment: Impairment) {
+        self.lock().impairment = impairment;
+    }
+
+    // Move the clock, delivering what is due by then
+    pub fn advance(&self, dt: Duration) {
+        self.lock().elapsed += dt;
+    }
+
+    pub fn now(&self) -> Instant {
+        self.lock().now()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Network> {
+        self.network.lock().unwrap()
+    }
+}
+
+impl Network {
+    fn now(&self) -> Instant {
+        self.epoch + self.elapsed
+    }
+
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+
+    // Assign a port if it's 0
+    fn local_addr(&mut self, addr: SocketAddr) -> SocketAddr {
+        let ip = if addr.ip().is_unspecified() {
+            IpAddr::V4(Ipv4Addr::LOCALHOST)
+        } else {
+            addr.ip()
+        };
+        let port = match addr.port() {
+            0 => loop {
+                let port = self.next_port;
+                self.next_port = self.next_port.checked_add(1).unwrap_or(49152);
+                if !self.inboxes.contains_key(&port) && !self.backlogs.contains_key(&port) {
+                    break port;
+                }
+            },
+            port => port,
+        };
+        SocketAddr::new(ip, port)
+    }
+
+    fn new_pipe(&mut self) -> u64 {
+        let id = self.next_id();
+        self.pipes.insert(id, Pipe::default());
+        id
+    }
+
+    fn send_datagram(&mut self, from: SocketAddr, to: SocketAddr, data: &[u8]) {
+        let impairment = self.impairment.clone();
+        if self.rng.gen_bool(impairment.loss.clamp(0.0, 1.0)) {
+            return;
+        }
+        let copies = if self.rng.gen_bool(impairment.duplication.clamp(0.0, 1.0)) {
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let mut due = self.elapsed + impairment.latency;
+            if !impairment.jitter.is_zero() {
+                due += impairment.jitter.mul_f64(self.rng.gen());
+            }
+            if self.rng.gen_bool(impairment.reordering.clamp(0.0, 1.0)) {
+                due += impairment.latency;
+            }
+            self.order += 1;
+            let packet = Packet {
+                due,
+                order: self.order,
+                from,
+                data: data.to_vec(),
+            };
+            // Nobody listening, gone like a real datagram
+            if let Some(inbox) = self.inboxes.get_mut(&to.port()) {
+                inbox.push(packet);
+            }
+        }
+    }
+}
+
+impl Transport for Loopback {
+    fn connect(&self, addr: SocketAddr) -> io::Result<Box<dyn Stream>> {
+        let mut network = self.lock();
+        if !network.backlogs.contains_key(&addr.port()) {
+            return Err(io::ErrorKind::ConnectionRefused.into());
+        }
+        let local = network.local_addr(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)));
+        let peer = network.local_addr(addr);
+        let (up, down) = (network.new_pipe(), network.new_pipe());
+        let accepted = StreamEnd {
+            id: network.next_id(),
+            peer: local,
+            read: up,
+            write: down,
+        };
+        network
+            .backlogs
+            .get_mut(&addr.port())
+            .unwrap()
+            .push_back(accepted);
+        let end = StreamEnd {
+            id: network.next_id(),
+            peer,
+            read: down,
+            write: up,
+        };
+        drop(network);
+        Ok(Box::new(LoopbackStream::new(self, end)))
+    }
+
+    fn listen(&self, addr: SocketAddr) -> io::Result<Box<dyn Listener>> {
+        let mut network = self.lock();
+        let addr = network.local_addr(addr);
+        if network.backlogs.contains_key(&addr.port()) {
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+        network.backlogs.insert(addr.port(), VecDeque::new());
+        Ok(Box::new(LoopbackListener {
+            loopback: self.clone(),
+            addr,
+        }))
+    }
+
+    fn bind(&self, addr: SocketAddr) -> io::Result<Box<dyn Datagram>> {
+        let mut network = self.lock();
+        let addr = network.local_addr(addr);
+        if network.inboxes.contains_key(&addr.port()) {
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+        network.inboxes.insert(addr.port(), vec![]);
+        Ok(Box::new(LoopbackDatagram {
+            loopback: self.clone(),
+            addr,
+        }))
+    }
+}
+
+struct LoopbackStream {
+    loopback: Loopback,
+    end: StreamEnd,
+}
+
+impl LoopbackStream {
+    fn new(loopback: &Loopback, end: StreamEnd) -> Self {
+        Self {
+            loopback: loopback.clone(),
+            end,
+        }
+    }
+}
+
+impl Read for LoopbackStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut network = self.loopback.lock();
+        let now = network.elapsed;
+        let pipe = network.pipes.get_mut(&self.end.read).unwrap();
+        let mut len = 0;
+        while len < buf.len() {
+            let Some((due, chunk)) = pipe.chunks.front_mut() else {
+                break;
+            };
+            if *due > now {
+                break;
+            }
+            let n = chunk.len().min(buf.len() - len);
+            buf[len..len + n].copy_from_slice(&chunk[..n]);
+            chunk.drain(..n);
+            if chunk.is_empty() {
+                pipe.chunks.pop_front();
+            }
+            len += n;
+        }
+        if len == 0 && !(pipe.closed && pipe.chunks.is_empty()) && !buf.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        Ok(len)
+    }
+}
+
+impl Write for LoopbackStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut network = self.loopback.lock();
+        let due = network.elapsed + network.impairment.latency;
+        let Some(pipe) = network.pipes.get_mut(&self.end.write) else {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        };
+        if pipe.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        // Never before the earlier writes even if the latency has been lowered
+        let due = pipe.chunks.back().map_or(due, |(last, _)| due.max(*last));
+        pipe.chunks.push_back((due, buf.to_vec()));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Stream for LoopbackStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.end.peer)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        let mut network = self.loopback.lock();
+        for pipe in [self.end.read, self.end.write] {
+            if let Some(pipe) = network.pipes.get_mut(&pipe) {
+                pipe.closed = true;
+            }
+        }
+        Ok(())
+    }
+
+    fn id(&self) -> u64 {
+        self.end.id
+    }
+}
+
+impl Drop for LoopbackStream {
+    fn drop(&mut self) {
+        let mut network = self.loopback.lock();
+        network.pipes.remove(&self.end.read);
+        if let Some(pipe) = network.pipes.get_mut(&self.end.write) {
+            pipe.closed = true;
+        }
+    }
+}
+
+struct LoopbackListener {
+    loopback: Loopback,
+    addr: SocketAddr,
+}
+
+impl Listener for LoopbackListener {
+    fn accept(&self) -> io::Result<(Box<dyn Stream>, SocketAddr)> {
+        let end = self
+            .loopback
+            .lock()
+            .backlogs
+            .get_mut(&self.addr.port())
+            .and_then(|backlog| backlog.pop_front())
+            .ok_or(io::ErrorKind::WouldBlock)?;
+        let peer = end.peer;
+        Ok((Box::new(LoopbackStream::new(&self.loopback, end)), peer))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+impl Drop for LoopbackListener {
+    fn drop(&mut self) {
+        let mut network = self.loopback.lock();
+        // The streams never accepted are reset
+        for end in network
+            .backlogs
+            .remove(&self.addr.port())
+            .unwrap_or_default()
+        {
+            network.pipes.remove(&end.read);
+            if let Some(pipe) = network.pipes.get_mut(&end.write) {
+                pipe.closed = true;
+            }
+        }
+    }
+}
+
+struct LoopbackDatagram {
+    loopback: Loopback,
+    addr: SocketAddr,
+}
+
+impl Datagram for LoopbackDatagram {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.loopback.lock().send_datagram(self.addr, addr, buf);
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut network = self.loopback.lock();
+        let now = network.elapsed;
+        let inbox = network.inboxes.get_mut(&self.addr.port()).unwrap();
+        let next = inbox
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| p.due <= now)
+            .min_by_key(|(_, p)| (p.due, p.order))
+            .map(|(i, _)| i)
+            .ok_or(io::ErrorKind::WouldBlock)?;
+        let packet = inbox.swap_remove(next);
+        // Truncated like a real datagram that doesn't fit
+        let len = packet.data.len().min(buf.len());
+        buf[..len].copy_from_slice(&packet.data[..len]);
+        Ok((len, packet.from))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+
+    fn now(&self) -> Instant {
+        self.loopback.now()
+    }
+}
+
+impl Drop for LoopbackDatagram {
+    fn drop(&mut self) {
+        self.loopback.lock().inboxes.remove(&self.addr.port());
+    }
+}
+
+#[test]
+fn test_loopback() {
+    let loopback = Loopback::new(
+        Impairment {
+            latency: Duration::from_millis(50),
+            ..Default::default()
+        },
+        0,
+    );
+    let ms = Duration::from_millis;
+
+    // Streams arrive in order after the latency
+    let listener = loopback.listen(resolve("0.0.0.0:8080").unwrap()).unwrap();
+    assert!(listener.accept().is_err());
+    let mut client = loopback
+        .connect(resolve("127.0.0.1:8080").unwrap())
+        .unwrap();
+    let (mut server, addr) = listener.accept().unwrap();
+    assert_eq!(client.peer_addr().unwrap(), listener.local_addr().unwrap());
+    assert_eq!(server.peer_addr().unwrap(), addr);
+    assert_ne!(client.id(), server.id());
+    client.write_all(b"hello").unwrap();
+    loopback.advance(ms(20));
+    client.write_all(b" world").unwrap();
+    let mut buf = vec![];
+    assert!(server.read_to_end(&mut buf).is_err());
+    loopback.advance(ms(30));
+    assert!(server.read_to_end(&mut buf).is_err());
+    assert_eq!(buf, b"hello");
+    loopback.advance(ms(20));
+    assert!(server.read_to_end(&mut buf).is_err());
+    assert_eq!(buf, b"hello world");
+    drop(client);
+    assert_eq!(server.read(&mut [0; 8]).unwrap(), 0);
+    assert!(loopback.connect(resolve("127.0.0.1:9").unwrap()).is_err());
+
+    // Datagrams are lost, duplicated and reordered, the same way for the same seed
+    let run = |seed| {
+        let loopback = Loopback::new(
+            Impairment {
+                latency: ms(50),
+                jitter: ms(30),
+                loss: 0.2,
+                duplication: 0.1,
+                reordering: 0.1,
+            },
+            seed,
+        );
+        let a = loopback.bind(resolve("127.0.0.1:0").unwrap()).unwrap();
+        let b = loopback.bind(resolve("127.0.0.1:0").unwrap()).unwrap();
+        for i in 0..200u8 {
+            a.send_to(&[i], b.local_addr().unwrap()).unwrap();
+            loopback.advance(ms(1));
+        }
+        loopback.advance(ms(200));
+        let mut received = vec![];
+        let mut buf = [0; 8];
+        while let Ok((len, from)) = b.recv_from(&mut buf) {
+            assert_eq!(len, 1);
+            assert_eq!(from, a.local_addr().unwrap());
+            received.push(buf[0]);
+        }
+        received
+    };
+    let received = run(1);
+    assert_eq!(received, run(1));
+    assert_ne!(received, run(2));
+    let mut unique = received.clone();
+    unique.sort();
+    unique.dedup();
+    assert!(unique.len() < 200);
+    assert!(unique.len() < received.len());
+    assert!(received.windows(2).any(|w| w[0] > w[1]));
+}
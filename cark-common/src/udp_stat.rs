@@ -1,13 +1,94 @@
+use std::collections::BTreeMap;
+
 // Sequence is used to identify the order of messages
 pub type Sequence = u16;
 
+// Largest datagram sent or accepted, small enough not to be fragmented on most links
+pub const MAX_DATAGRAM_SIZE: usize = 1200;
+
+#[derive(Debug)]
+pub enum DatagramError {
+    TooLarge(usize),
+    Malformed(postcard::Error),
+    TrailingBytes(usize),
+}
+
+impl DatagramError {
+    // Short name for logs and counters
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Self::TooLarge(_) => "too_large",
+            Self::Malformed(_) => "malformed",
+            Self::TrailingBytes(_) => "trailing_bytes",
+        }
+    }
+}
+
+impl std::fmt::Display for DatagramError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TooLarge(size) => write!(f, "Datagram too large: size = {}", size),
+            Self::Malformed(e) => write!(f, "Malformed datagram: {}", e),
+            Self::TrailingBytes(len) => write!(f, "Trailing bytes in datagram: len = {}", len),
+        }
+    }
+}
+
+// Decode a whole datagram, which must be one message and nothing else
+pub fn read_datagram<T: serde::de::DeserializeOwned>(buf: &[u8]) -> Result<T, DatagramError> {
+    if buf.len() > MAX_DATAGRAM_SIZE {
+        return Err(DatagramError::TooLarge(buf.len()));
+    }
+    let (message, rest) = postcard::take_from_bytes(buf).map_err(DatagramError::Malformed)?;
+    if !rest.is_empty() {
+        return Err(DatagramError::TrailingBytes(rest.len()));
+    }
+    Ok(message)
+}
+
+// Encode a message into the buffer, failing if it doesn't fit in a datagram
+pub fn write_datagram<'a, T: serde::Serialize>(
+    message: &T,
+    buf: &'a mut [u8],
+) -> Result<&'a mut [u8], DatagramError> {
+    let len = buf.len().min(MAX_DATAGRAM_SIZE);
+    postcard::to_slice(message, &mut buf[..len]).map_err(|e| match e {
+        postcard::Error::SerializeBufferFull => DatagramError::TooLarge(len + 1),
+        e => DatagramError::Malformed(e),
+    })
+}
+
+// Datagrams dropped by the receiver, by reason
+#[derive(Debug, Default, Clone)]
+pub struct DropStat {
+    counts: BTreeMap<&'static str, u64>,
+}
+
+impl DropStat {
+    pub fn record(&mut self, reason: &'static str) {
+        *self.counts.entry(reason).or_default() += 1;
+    }
+
+    pub fn get(&self, reason: &str) -> u64 {
+        self.counts.get(reason).copied().unwrap_or(0)
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.values().sum()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, u64)> + '_ {
+        self.counts.iter().map(|(reason, count)| (*reason, *count))
+    }
+}
+
 #[derive(Debug)]
 pub struct SequenceGen {
     sequence: Sequence,
 }
 
 impl SequenceGen {
-    pub fn next(&mut self) -> Sequence {
+    pub fn next_sequence(&mut self) -> Sequence {
         self.sequence = self.sequence.overflowing_add(1).0;
         self.sequence
     }
@@ -63,6 +144,12 @@ impl UdpStat {
     }
 }
 
+impl Default for UdpStat {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[test]
 fn test() {
     let mut stat = UdpStat::new();
@@ -91,3 +178,41 @@ fn test() {
     stat.update(Sequence::MAX - 2);
     assert_eq!(stat.dropped(), 3);
 }
+
+#[test]
+fn test_datagram() {
+    use crate::model::{ClientMessage, ClientUdpMessage, PublicChatMessage};
+
+    let mut buf = [0; MAX_DATAGRAM_SIZE + 1];
+    let message = ClientUdpMessage::Message {
+        token: 1,
+        sequence: 3,
+        message: ClientMessage::Pong { nonce: 9 },
+    };
+    let len = write_datagram(&message, &mut buf).unwrap().len();
+    assert!(matches!(
+        read_datagram(&buf[..len]),
+        Ok(ClientUdpMessage::Message { sequence: 3, .. })
+    ));
+    let e = read_datagram::<ClientUdpMessage>(&buf[..len + 1]).unwrap_err();
+    assert_eq!(e.reason(), "trailing_bytes");
+    let e = read_datagram::<ClientUdpMessage>(&buf[..len - 1]).unwrap_err();
+    assert_eq!(e.reason(), "malformed");
+    let e = read_datagram::<ClientUdpMessage>(&buf).unwrap_err();
+    assert_eq!(e.reason(), "too_large");
+
+    let message = ClientMessage::PublicChatMessage(PublicChatMessage {
+        text: "a".repeat(MAX_DATAGRAM_SIZE),
+    });
+    assert_eq!(
+        write_datagram(&message, &mut buf).unwrap_err().reason(),
+        "too_large"
+    );
+
+    let mut drops = DropStat::default();
+    drops.record("malformed");
+    drops.record("malformed");
+    drops.record("too_large");
+    assert_eq!(drops.get("malformed"), 2);
+    assert_eq!(drops.total(), 3);
+}
@@ -0,0 +1,399 @@
+// Admission control of the connections: connection limits, bans and the allowlist.
+// Bans and the allowlist are kept in text files, one rule per line: an IP, a CIDR or `account:<name>`.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+const DEFAULT_MAX_CONNECTIONS: usize = 256;
+const DEFAULT_MAX_CONNECTIONS_PER_IP: usize = 8;
+// Each join costs a password hash, so they are limited per address
+const DEFAULT_MAX_JOINS_PER_MINUTE: u32 = 10;
+// Addresses tracked by the join limit before the ones that have waited long enough are forgotten
+const MAX_TRACKED_ADDRESSES: usize = 4096;
+
+// Load the rules from the files at `BANS` and `ALLOWLIST`, or `bans.txt` and `allowlist.txt`,
+// and the limits from `MAX_CONNECTIONS`, `MAX_CONNECTIONS_PER_IP` and `MAX_JOINS_PER_MINUTE`
+pub fn load_access() -> Access {
+    let bans = std::env::var("BANS").unwrap_or("bans.txt".to_string());
+    let allowlist = std::env::var("ALLOWLIST").unwrap_or("allowlist.txt".to_string());
+    Access {
+        max_connections: std::env::var("MAX_CONNECTIONS")
+            .map(|s| s.parse().expect("Invalid MAX_CONNECTIONS"))
+            .unwrap_or(DEFAULT_MAX_CONNECTIONS),
+        max_connections_per_ip: std::env::var("MAX_CONNECTIONS_PER_IP")
+            .map(|s| s.parse().expect("Invalid MAX_CONNECTIONS_PER_IP"))
+            .unwrap_or(DEFAULT_MAX_CONNECTIONS_PER_IP),
+        joins: RateLimit::new(
+            std::env::var("MAX_JOINS_PER_MINUTE")
+                .map(|s| s.parse().expect("Invalid MAX_JOINS_PER_MINUTE"))
+                .unwrap_or(DEFAULT_MAX_JOINS_PER_MINUTE),
+        ),
+        bans: RuleList::open(bans),
+        allowlist: RuleList::open(allowlist),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_eq(&net.octets(), &ip.octets(), self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_eq(&net.octets(), &ip.octets(), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_eq(a: &[u8], b: &[u8], prefix: u8) -> bool {
+    let (bytes, bits) = (prefix as usize / 8, prefix % 8);
+    if a[..bytes] != b[..bytes] {
+        return false;
+    }
+    bits == 0 || (a[bytes] ^ b[bytes]) >> (8 - bits) == 0
+}
+
+impl std::str::FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = s.split_once('/').unwrap_or((s, ""));
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("Invalid address: {}", s))?;
+        let addr = addr.to_canonical();
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = if prefix.is_empty() {
+            max
+        } else {
+            prefix
+                .parse()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("Invalid prefix: {}", s))?
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl std::fmt::Display for IpNet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let max = if self.addr.is_ipv4() { 32 } else { 128 };
+        if self.prefix == max {
+            write!(f, "{}", self.addr)
+        } else {
+            write!(f, "{}/{}", self.addr, self.prefix)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rule {
+    Net(IpNet),
+    Account(String),
+}
+
+impl std::str::FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("account:") {
+            Some(name) if !name.is_empty() => Ok(Self::Account(name.to_string())),
+            Some(_) => Err(format!("Missing account name: {}", s)),
+            None => s.parse().map(Self::Net),
+        }
+    }
+}
+
+impl std::fmt::Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Net(net) => write!(f, "{}", net),
+            Self::Account(name) => write!(f, "account:{}", name),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RuleList {
+    // Kept in memory only if None
+    path: Option<PathBuf>,
+    rules: Vec<Rule>,
+}
+
+impl RuleList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn open(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let rules = match std::fs::read_to_string(&path) {
+            Ok(data) => parse_rules(&data, &path),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => panic!("Failed to read rules: path = {:?}, {}", path, e),
+        };
+        log::info!("Rules loaded: path = {:?}, count = {}", path, rules.len());
+        Self {
+            path: Some(path),
+            rules,
+        }
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    // Returns false if the rule is already there
+    pub fn add(&mut self, rule: Rule) -> bool {
+        if self.rules.contains(&rule) {
+            return false;
+        }
+        self.rules.push(rule);
+        self.save();
+        true
+    }
+
+    pub fn remove(&mut self, rule: &Rule) -> bool {
+        let len = self.rules.len();
+        self.rules.retain(|r| r != rule);
+        if self.rules.len() == len {
+            return false;
+        }
+        self.save();
+        true
+    }
+
+    pub fn matches_ip(&self, ip: IpAddr) -> bool {
+        self.rules
+            .iter()
+            .any(|rule| matches!(rule, Rule::Net(net) if net.contains(ip)))
+    }
+
+    pub fn matches_account(&self, name: &str) -> bool {
+        self.rules
+            .iter()
+            .any(|rule| matches!(rule, Rule::Account(n) if n == name))
+    }
+
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let data: String = self
+            .rules
+            .iter()
+            .map(|rule| format!("{}\n", rule))
+            .collect();
+        // Write to a temporary file first not to lose the rules on a crash
+        let tmp = path.with_extension("tmp");
+        let result = std::fs::write(&tmp, data).and_then(|_| std::fs::rename(&tmp, path));
+        if let Err(e) = result {
+            log::error!("Failed to save rules: path = {:?}, {}", path, e);
+        }
+    }
+}
+
+// Lines that fail to parse are skipped with a warning, empty lines and `#` comments are ignored
+fn parse_rules(data: &str, path: &Path) -> Vec<Rule> {
+    data.lines()
+        .map(|line| line.split('#').next().unwrap().trim())
+        .filter(|line| !line.is_empty())
+        .filter_map(|line| match line.parse() {
+            Ok(rule) => Some(rule),
+            Err(e) => {
+                log::warn!("Invalid rule: path = {:?}, {}", path, e);
+                None
+            }
+        })
+        .collect()
+}
+
+// Up to `per_minute` at once from an address, refilled evenly over a minute
+#[derive(Debug)]
+pub struct RateLimit {
+    per_minute: u32,
+    // Tokens left and when they were counted
+    buckets: HashMap<IpAddr, (f32, Instant)>,
+}
+
+impl RateLimit {
+    pub fn new(per_minute: u32) -> Self {
+        Self {
+            per_minute,
+            buckets: HashMap::new(),
+        }
+    }
+
+    pub fn per_minute(&self) -> u32 {
+        self.per_minute
+    }
+
+    // Take a token if there is one left for the address
+    pub fn take(&mut self, ip: IpAddr, now: Instant) -> bool {
+        let max = self.per_minute as f32;
+        let refill = |(tokens, last): (f32, Instant)| {
+            (tokens + now.duration_since(last).as_secs_f32() * max / 60.0).min(max)
+        };
+        if self.buckets.len() >= MAX_TRACKED_ADDRESSES {
+            self.buckets.retain(|_, bucket| refill(*bucket) < max);
+        }
+        let bucket = self.buckets.entry(ip.to_canonical()).or_insert((max, now));
+        let tokens = refill(*bucket);
+        if tokens < 1.0 {
+            return false;
+        }
+        *bucket = (tokens - 1.0, now);
+        true
+    }
+}
+
+#[derive(Debug)]
+pub struct Access {
+    pub max_connections: usize,
+    pub max_connections_per_ip: usize,
+    pub joins: RateLimit,
+    pub bans: RuleList,
+    // Everyone is allowed if empty, otherwise the address or the account has to be listed
+    pub allowlist: RuleList,
+}
+
+impl Default for Access {
+    fn default() -> Self {
+        Self {
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_connections_per_ip: DEFAULT_MAX_CONNECTIONS_PER_IP,
+            joins: RateLimit::new(DEFAULT_MAX_JOINS_PER_MINUTE),
+            bans: RuleList::new(),
+            allowlist: RuleList::new(),
+        }
+    }
+}
+
+impl Access {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Check a new connection given the numbers of the connections open, in total and from the address
+    pub fn admit_connection(&self, ip: IpAddr, total: usize, from_ip: usize) -> Result<(), String> {
+        if self.bans.matches_ip(ip) {
+            return Err("Banned".to_string());
+        }
+        if total >= self.max_connections {
+            return Err("Server full".to_string());
+        }
+        if from_ip >= self.max_connections_per_ip {
+            return Err("Too many connections from the address".to_string());
+        }
+        Ok(())
+    }
+
+    // Check a join before it reaches the game
+    pub fn admit_join(&mut self, ip: IpAddr, name: &str, now: Instant) -> Result<(), String> {
+        if self.bans.matches_account(name) {
+            return Err("Banned".to_string());
+        }
+        if !self.allowlist.is_empty()
+            && !self.allowlist.matches_ip(ip)
+            && !self.allowlist.matches_account(name)
+        {
+            return Err("Not in the allowlist".to_string());
+        }
+        if !self.joins.take(ip, now) {
+            return Err("Too many joins from the address, try again later".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test() {
+    let net: IpNet = "10.1.0.0/16".parse().unwrap();
+    assert!(net.contains("10.1.200.3".parse().unwrap()));
+    assert!(!net.contains("10.2.0.1".parse().unwrap()));
+    assert!(net.contains("::ffff:10.1.0.1".parse().unwrap()));
+    assert!(!net.contains("::1".parse().unwrap()));
+    let net: IpNet = "192.168.1.128/25".parse().unwrap();
+    assert!(net.contains("192.168.1.200".parse().unwrap()));
+    assert!(!net.contains("192.168.1.100".parse().unwrap()));
+    assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+    assert_eq!(
+        "2001:db8::/32".parse::<IpNet>().unwrap().to_string(),
+        "2001:db8::/32"
+    );
+    assert_eq!(
+        "127.0.0.1".parse::<Rule>().unwrap().to_string(),
+        "127.0.0.1"
+    );
+    assert_eq!(
+        "account:bob".parse::<Rule>(),
+        Ok(Rule::Account("bob".to_string()))
+    );
+    assert!("bob".parse::<Rule>().is_err());
+
+    let path = std::env::temp_dir().join(format!("cark-bans-{}.txt", std::process::id()));
+    std::fs::write(
+        &path,
+        "# comment\n10.0.0.0/8\nnonsense\naccount:mallory # griefing\n",
+    )
+    .unwrap();
+    let mut access = Access {
+        max_connections: 3,
+        max_connections_per_ip: 2,
+        joins: RateLimit::new(60),
+        bans: RuleList::open(&path),
+        allowlist: RuleList::new(),
+    };
+    assert_eq!(access.bans.rules().len(), 2);
+    let local: IpAddr = "127.0.0.1".parse().unwrap();
+    let now = Instant::now();
+    assert!(access
+        .admit_connection("10.9.9.9".parse().unwrap(), 0, 0)
+        .is_err());
+    assert!(access.admit_connection(local, 2, 1).is_ok());
+    assert!(access.admit_connection(local, 3, 0).is_err());
+    assert!(access.admit_connection(local, 2, 2).is_err());
+    assert!(access.admit_join(local, "mallory", now).is_err());
+    assert!(access.admit_join(local, "alice", now).is_ok());
+
+    // Listed by either the address or the account
+    access.allowlist.add("account:alice".parse().unwrap());
+    assert!(access.admit_join(local, "bob", now).is_err());
+    access.allowlist.add("127.0.0.0/8".parse().unwrap());
+    assert!(access.admit_join(local, "bob", now).is_ok());
+    assert!(access
+        .admit_join("192.0.2.1".parse().unwrap(), "alice", now)
+        .is_ok());
+
+    assert!(access.bans.remove(&Rule::Account("mallory".to_string())));
+    assert!(!access.bans.add("10.0.0.0/8".parse().unwrap()));
+    assert_eq!(RuleList::open(&path).rules().len(), 1);
+    std::fs::remove_file(&path).unwrap();
+
+    // Two a minute, one comes back every 30 seconds
+    let mut joins = RateLimit::new(2);
+    let other: IpAddr = "192.0.2.1".parse().unwrap();
+    assert!(joins.take(local, now));
+    assert!(joins.take(local, now));
+    assert!(!joins.take(local, now));
+    assert!(joins.take(other, now));
+    assert!(!joins.take(local, now + std::time::Duration::from_secs(10)));
+    assert!(joins.take(local, now + std::time::Duration::from_secs(30)));
+}
@@ -0,0 +1,389 @@
+// Accounts of the players, kept in a JSON file so that they survive restarts
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    path::PathBuf,
+    sync::mpsc::{self, Receiver, Sender},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use cark_common::entity::Inventory;
+use rand::RngCore;
+use sha2::Sha256;
+
+const SALT_SIZE: usize = 16;
+// For the new hashes, OWASP recommends at least 600,000 for PBKDF2-HMAC-SHA256
+#[cfg(not(test))]
+const PBKDF2_ITERATIONS: u32 = 600_000;
+// The tests log in on every join
+#[cfg(test)]
+const PBKDF2_ITERATIONS: u32 = 1_000;
+// Logins waiting for their password to be checked, the rest are refused
+const MAX_PENDING_LOGINS: usize = 16;
+const MAX_NEW_ACCOUNTS_PER_MINUTE: usize = 30;
+
+// Load the accounts from the file at `ACCOUNTS`, or `accounts.json`
+pub fn load_accounts() -> Accounts {
+    let path = std::env::var("ACCOUNTS").unwrap_or("accounts.json".to_string());
+    Accounts::open(path)
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct Account {
+    pub name: String,
+    // Hex encoded
+    salt: String,
+    // Hex encoded, derived from the salt and the password by `kdf`
+    password_hash: String,
+    kdf: Kdf,
+    #[serde(default)]
+    pub state: Option<SavedState>,
+}
+
+// How the password hash was derived, kept with the hash so that it can be strengthened later
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(tag = "algorithm", rename_all = "kebab-case")]
+pub enum Kdf {
+    Pbkdf2Sha256 { iterations: u32 },
+}
+
+impl Kdf {
+    fn current() -> Self {
+        Self::Pbkdf2Sha256 {
+            iterations: PBKDF2_ITERATIONS,
+        }
+    }
+}
+
+// What is restored on the next join
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SavedState {
+    // World the chunk belongs to, chunk ids are not shared between worlds
+    #[serde(default)]
+    pub world_id: u64,
+    pub chunk_id: u32,
+    pub position: [f32; 2],
+    pub inventory: Inventory,
+    pub settings: BTreeMap<String, String>,
+}
+
+pub struct Accounts {
+    accounts: HashMap<String, Account>,
+    // Hashing takes long enough to stall the game, so the passwords are checked on a thread of their own
+    logins: Sender<LoginJob>,
+    results: Receiver<LoginResult>,
+    // User ids of the logins being checked
+    pending: HashSet<u64>,
+    // When the recent accounts were created
+    creations: VecDeque<Instant>,
+    // Writes the file on a thread of its own, kept in memory only if None
+    writer: Option<(Sender<String>, JoinHandle<()>)>,
+}
+
+struct LoginJob {
+    user_id: u64,
+    name: String,
+    password: String,
+    // None to create the account
+    account: Option<Account>,
+}
+
+struct LoginResult {
+    user_id: u64,
+    name: String,
+    // The account if it was created
+    result: Result<Option<Account>, String>,
+}
+
+impl Default for Accounts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Accounts {
+    pub fn new() -> Self {
+        let (logins, jobs) = mpsc::channel();
+        let (done, results) = mpsc::channel();
+        std::thread::spawn(move || check_logins(jobs, done));
+        Self {
+            accounts: HashMap::new(),
+            logins,
+            results,
+            pending: HashSet::new(),
+            creations: VecDeque::new(),
+            writer: None,
+        }
+    }
+
+    pub fn open(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let accounts = match std::fs::read_to_string(&path) {
+            Ok(data) => {
+                let accounts: Vec<Account> = serde_json::from_str(&data).unwrap_or_else(|e| {
+                    panic!("Failed to parse accounts: path = {:?}, {}", path, e)
+                });
+                accounts.into_iter().map(|a| (a.name.clone(), a)).collect()
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => panic!("Failed to read accounts: path = {:?}, {}", path, e),
+        };
+        log::info!(
+            "Accounts loaded: path = {:?}, count = {}",
+            path,
+            accounts.len()
+        );
+        let (writer, data) = mpsc::channel();
+        let handle = std::thread::spawn(move || write_accounts(path, data));
+        let mut new = Self::new();
+        new.accounts = accounts;
+        new.writer = Some((writer, handle));
+        new
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Account> {
+        self.accounts.get(name)
+    }
+
+    // Start checking the password, the account is created on the first login.
+    // The result comes from `finish_logins` on a later call.
+    pub fn start_login(&mut self, user_id: u64, name: &str, password: &str) -> Result<(), String> {
+        if self.pending.contains(&user_id) {
+            return Err("Join in progress".to_string());
+        }
+        if self.pending.len() >= MAX_PENDING_LOGINS {
+            return Err("Server busy, try again later".to_string());
+        }
+        let account = self.accounts.get(name).cloned();
+        if account.is_none() {
+            let now = Instant::now();
+            while self
+                .creations
+                .front()
+                .is_some_and(|t| now.duration_since(*t) >= Duration::from_secs(60))
+            {
+                self.creations.pop_front();
+            }
+            if self.creations.len() >= MAX_NEW_ACCOUNTS_PER_MINUTE {
+                return Err("Too many new accounts, try again later".to_string());
+            }
+            self.creations.push_back(now);
+        }
+        self.logins
+            .send(LoginJob {
+                user_id,
+                name: name.to_string(),
+                password: password.to_string(),
+                account,
+            })
+            .map_err(|_| "Accounts unavailable".to_string())?;
+        self.pending.insert(user_id);
+        Ok(())
+    }
+
+    pub fn pending_logins(&self) -> usize {
+        self.pending.len()
+    }
+
+    // Forget the login of a client that left before it was checked
+    pub fn cancel_login(&mut self, user_id: u64) {
+        self.pending.remove(&user_id);
+    }
+
+    // The logins checked since the last call as the user ids, the names and the results
+    pub fn finish_logins(&mut self) -> Vec<(u64, String, Result<(), String>)> {
+        let mut finished = vec![];
+        while let Ok(LoginResult {
+            user_id,
+            name,
+            result,
+        }) = self.results.try_recv()
+        {
+            if !self.pending.remove(&user_id) {
+                continue;
+            }
+            let result = match result {
+                Ok(Some(_)) if self.accounts.contains_key(&name) => {
+                    // Created by another login while this one was checked
+                    Err("Name already in use".to_string())
+                }
+                Ok(Some(account)) => {
+                    self.accounts.insert(name.clone(), account);
+                    self.save();
+                    log::info!("Account created: name = {}", name);
+                    Ok(())
+                }
+                Ok(None) => Ok(()),
+                Err(e) => Err(e),
+            };
+            finished.push((user_id, name, result));
+        }
+        finished
+    }
+
+    pub fn save_state(&mut self, name: &str, state: SavedState) {
+        let Some(account) = self.accounts.get_mut(name) else {
+            return;
+        };
+        account.state = Some(state);
+        self.save();
+    }
+
+    fn save(&self) {
+        let Some((writer, _)) = &self.writer else {
+            return;
+        };
+        let mut accounts: Vec<_> = self.accounts.values().collect();
+        accounts.sort_by(|a, b| a.name.cmp(&b.name));
+        let _ = writer.send(serde_json::to_string_pretty(&accounts).unwrap());
+    }
+}
+
+impl Drop for Accounts {
+    // Wait for the last write not to lose it
+    fn drop(&mut self) {
+        if let Some((writer, handle)) = self.writer.take() {
+            drop(writer);
+            let _ = handle.join();
+        }
+    }
+}
+
+fn check_logins(jobs: Receiver<LoginJob>, results: Sender<LoginResult>) {
+    for job in jobs {
+        let result = match &job.account {
+            Some(account) => {
+                if constant_time_eq(
+                    hash_password(account.kdf, &account.salt, &job.password).as_bytes(),
+                    account.password_hash.as_bytes(),
+                ) {
+                    Ok(None)
+                } else {
+                    Err("Wrong password".to_string())
+                }
+            }
+            None => {
+                let mut salt = [0; SALT_SIZE];
+                rand::thread_rng().fill_bytes(&mut salt);
+                let salt = to_hex(&salt);
+                let kdf = Kdf::current();
+                Ok(Some(Account {
+                    name: job.name.clone(),
+                    password_hash: hash_password(kdf, &salt, &job.password),
+                    kdf,
+                    salt,
+                    state: None,
+                }))
+            }
+        };
+        let result = LoginResult {
+            user_id: job.user_id,
+            name: job.name,
+            result,
+        };
+        if results.send(result).is_err() {
+            break;
+        }
+    }
+}
+
+// Only the latest of the queued contents is written
+fn write_accounts(path: PathBuf, data: Receiver<String>) {
+    while let Ok(mut latest) = data.recv() {
+        while let Ok(newer) = data.try_recv() {
+            latest = newer;
+        }
+        // Write to a temporary file first not to lose the accounts on a crash
+        let tmp = path.with_extension("tmp");
+        let result = std::fs::write(&tmp, latest).and_then(|_| std::fs::rename(&tmp, &path));
+        if let Err(e) = result {
+            log::error!("Failed to save accounts: path = {:?}, {}", path, e);
+        }
+    }
+}
+
+fn hash_password(kdf: Kdf, salt: &str, password: &str) -> String {
+    match kdf {
+        Kdf::Pbkdf2Sha256 { iterations } => {
+            let mut hash = [0; 32];
+            pbkdf2::pbkdf2_hmac::<Sha256>(
+                password.as_bytes(),
+                salt.as_bytes(),
+                iterations,
+                &mut hash,
+            );
+            to_hex(&hash)
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Wait for the login to be checked
+#[cfg(test)]
+fn login(accounts: &mut Accounts, name: &str, password: &str) -> Result<(), String> {
+    accounts.start_login(0, name, password)?;
+    loop {
+        if let Some((_, _, result)) = accounts.finish_logins().pop() {
+            return result;
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+#[test]
+fn test() {
+    let path = std::env::temp_dir().join(format!("cark-accounts-{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let mut accounts = Accounts::open(&path);
+    login(&mut accounts, "alice", "secret").unwrap();
+    let salt = accounts.get("alice").unwrap().salt.clone();
+    assert!(login(&mut accounts, "alice", "wrong").is_err());
+    accounts.save_state(
+        "alice",
+        SavedState {
+            chunk_id: 1,
+            position: [3.0, 4.0],
+            ..Default::default()
+        },
+    );
+
+    // One login at a time for each user
+    accounts.start_login(1, "alice", "secret").unwrap();
+    assert!(accounts.start_login(1, "alice", "secret").is_err());
+    accounts.cancel_login(1);
+
+    // Salts differ between accounts
+    login(&mut accounts, "bob", "secret").unwrap();
+    assert_ne!(accounts.get("bob").unwrap().salt, salt);
+    // The file is written on drop at the latest
+    drop(accounts);
+
+    // The password is never stored as is
+    let data = std::fs::read_to_string(&path).unwrap();
+    assert!(!data.contains("secret"));
+
+    let mut accounts = Accounts::open(&path);
+    login(&mut accounts, "alice", "secret").unwrap();
+    let account = accounts.get("alice").unwrap();
+    assert_eq!(account.state.as_ref().unwrap().position, [3.0, 4.0]);
+    assert_eq!(account.kdf, Kdf::current());
+    drop(accounts);
+    std::fs::remove_file(&path).unwrap();
+
+    // New accounts are limited
+    let mut accounts = Accounts::new();
+    for i in 0..MAX_NEW_ACCOUNTS_PER_MINUTE {
+        login(&mut accounts, &i.to_string(), "secret").unwrap();
+    }
+    assert!(accounts.start_login(0, "late", "secret").is_err());
+    assert!(login(&mut accounts, "0", "secret").is_ok());
+}
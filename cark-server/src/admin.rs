@@ -0,0 +1,482 @@
+// Admin console reading commands from stdin while the server runs.
+// Besides its own commands, it accepts the chat commands of `Command` without the slash.
+
+use std::sync::mpsc::{self, Receiver};
+
+use cark_common::{
+    direction::Direction,
+    field::{ChunkId, Layer},
+    model::ServerMessage,
+};
+
+use crate::{
+    access::Rule,
+    command::{parse_arg, Command},
+    tcp::Tcp,
+    udp::Udp,
+    Global, OutgoingEvent,
+};
+
+const HELP: &str = "\
+list                                 connections with their UDP loss and RTT
+kick <id|name>                       disconnect a player
+ban <id|name|rule>                   disconnect a player and refuse its address, or add a ban rule
+unban <rule>                         remove a ban rule
+allow <rule>                         add a rule to the allowlist
+disallow <rule>                      remove a rule from the allowlist
+access                               connection limits, bans and the allowlist
+broadcast <text>                     send a notice to everyone
+teleport <id|name> <chunk> <x> <y>   move a character
+generate <chunk> <direction>         generate the chunk next to a chunk
+chunk <chunk>                        inspect a chunk
+stats                                field and server stats
+save                                 save the world and the players joined to their accounts
+loglevel <off|error|warn|info|debug|trace>
+portal <chunk> <direction> <chunk>   link the edge of a chunk to another chunk
+unportal <chunk> <direction>         remove a portal
+settile <chunk> <layer> <x> <y> <tile>
+A rule is an IP, a CIDR such as 10.0.0.0/8, or account:<name>";
+
+// Lines typed into stdin, read on a thread of their own so that the main loop never blocks
+pub fn spawn_stdin() -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AdminCommand {
+    Help,
+    List,
+    Kick {
+        target: String,
+    },
+    Ban {
+        target: String,
+    },
+    Unban {
+        rule: Rule,
+    },
+    Allow {
+        rule: Rule,
+    },
+    Disallow {
+        rule: Rule,
+    },
+    Access,
+    Broadcast {
+        text: String,
+    },
+    Teleport {
+        target: String,
+        chunk_id: ChunkId,
+        position: [f32; 2],
+    },
+    Generate {
+        id: ChunkId,
+        direction: Direction,
+    },
+    Chunk {
+        id: ChunkId,
+    },
+    Stats,
+    Save,
+    LogLevel {
+        level: log::LevelFilter,
+    },
+    Game(Command),
+}
+
+impl AdminCommand {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let line = line.trim();
+        let (name, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let mut args = rest.split_whitespace();
+        let command = match name {
+            "help" => Self::Help,
+            "list" => Self::List,
+            "kick" => Self::Kick {
+                target: parse_arg(args.next(), "target")?,
+            },
+            "ban" => Self::Ban {
+                target: parse_arg(args.next(), "target")?,
+            },
+            "unban" => Self::Unban {
+                rule: parse_arg(args.next(), "rule")?,
+            },
+            "allow" => Self::Allow {
+                rule: parse_arg(args.next(), "rule")?,
+            },
+            "disallow" => Self::Disallow {
+                rule: parse_arg(args.next(), "rule")?,
+            },
+            "access" => Self::Access,
+            "broadcast" => {
+                if rest.trim().is_empty() {
+                    return Err("Missing argument: text".to_string());
+                }
+                return Ok(Self::Broadcast {
+                    text: rest.trim().to_string(),
+                });
+            }
+            "teleport" => Self::Teleport {
+                target: parse_arg(args.next(), "target")?,
+                chunk_id: parse_arg(args.next(), "chunk")?,
+                position: [parse_arg(args.next(), "x")?, parse_arg(args.next(), "y")?],
+            },
+            "generate" => Self::Generate {
+                id: parse_arg(args.next(), "chunk")?,
+                direction: parse_arg(args.next(), "direction")?,
+            },
+            "chunk" => Self::Chunk {
+                id: parse_arg(args.next(), "chunk")?,
+            },
+            "stats" => Self::Stats,
+            "save" => Self::Save,
+            "loglevel" => Self::LogLevel {
+                level: parse_arg(args.next(), "level")?,
+            },
+            _ => return Command::parse(line).map(Self::Game),
+        };
+        if let Some(arg) = args.next() {
+            return Err(format!("Unexpected argument: {}", arg));
+        }
+        Ok(command)
+    }
+}
+
+pub fn execute(
+    command: AdminCommand,
+    global: &mut Global,
+    tcp: &mut Tcp,
+    udp: &Udp,
+) -> Result<String, String> {
+    match command {
+        AdminCommand::Help => Ok(HELP.to_string()),
+        AdminCommand::List => {
+            let mut lines = vec![format!(
+                "{:<8} {:<16} {:<22} {:>8} {:>8}",
+                "id", "name", "addr", "loss", "rtt"
+            )];
+            for connection in tcp.connections() {
+                let id = connection.id();
+                let name = global
+                    .characters
+                    .iter()
+                    .find(|c| c.id == id)
+                    .map_or("-", |c| c.name.as_str());
+                let addr = connection
+                    .stream
+                    .peer_addr()
+                    .map_or("-".to_string(), |a| a.to_string());
+                let udp = udp.connections().iter().find(|c| c.id() == id);
+                let loss = udp.map_or("-".to_string(), |c| {
+                    format!("{:.2}%", c.stat().loss_rate() * 100.0)
+                });
+                let rtt = udp
+                    .and_then(|c| c.rtt())
+                    .map_or("-".to_string(), |rtt| format!("{}ms", rtt.as_millis()));
+                lines.push(format!(
+                    "{:<8} {:<16} {:<22} {:>8} {:>8}",
+                    id, name, addr, loss, rtt
+                ));
+            }
+            Ok(lines.join("\n"))
+        }
+        AdminCommand::Kick { target } => {
+            // The state is saved when the connection is gone
+            let id = global.resolve_player(&target)?;
+            if !tcp.kick(id, "Kicked by the server") {
+                return Err(format!("Connection not found: id = {}", id));
+            }
+            Ok(format!("Kicked: id = {}", id))
+        }
+        AdminCommand::Ban { target } => {
+            let rule: Rule = match global.resolve_player(&target) {
+                Ok(id) => tcp
+                    .connections()
+                    .iter()
+                    .find(|c| c.id() == id)
+                    .and_then(|c| c.ip())
+                    .ok_or_else(|| format!("Connection not found: id = {}", id))?
+                    .to_string()
+                    .parse()?,
+                Err(e) => target.parse().map_err(|_| e)?,
+            };
+            let kicked = tcp.ban(rule.clone());
+            Ok(format!("Banned: {}, kicked = {}", rule, kicked))
+        }
+        AdminCommand::Unban { rule } => {
+            if !tcp.unban(&rule) {
+                return Err(format!("Not banned: {}", rule));
+            }
+            Ok(format!("Unbanned: {}", rule))
+        }
+        AdminCommand::Allow { rule } => {
+            if !tcp.allow(rule.clone()) {
+                return Err(format!("Already allowed: {}", rule));
+            }
+            Ok(format!("Allowed: {}", rule))
+        }
+        AdminCommand::Disallow { rule } => {
+            if !tcp.disallow(&rule) {
+                return Err(format!("Not in the allowlist: {}", rule));
+            }
+            Ok(format!("Disallowed: {}", rule))
+        }
+        AdminCommand::Access => {
+            let access = tcp.access();
+            let list = |rules: &[Rule]| {
+                if rules.is_empty() {
+                    "none".to_string()
+                } else {
+                    rules
+                        .iter()
+                        .map(|r| r.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                }
+            };
+            Ok(format!(
+                "Max connections: {}, per IP: {}, joins per IP per minute: {}\nBans: {}\nAllowlist: {}",
+                access.max_connections,
+                access.max_connections_per_ip,
+                access.joins.per_minute(),
+                list(access.bans.rules()),
+                list(access.allowlist.rules()),
+            ))
+        }
+        AdminCommand::Broadcast { text } => {
+            tcp.push_event(OutgoingEvent {
+                connection_id: None,
+                message: ServerMessage::Notice { text: text.clone() },
+            });
+            Ok(format!("Broadcast: {}", text))
+        }
+        AdminCommand::Teleport {
+            target,
+            chunk_id,
+            position,
+        } => {
+            let id = global.resolve_character(&target)?;
+            global.teleport(id, chunk_id, position, |e| tcp.push_event(e))?;
+            Ok(format!(
+                "Teleported: id = {}, chunk = {}, position = {:?}",
+                id, chunk_id, position
+            ))
+        }
+        AdminCommand::Generate { id, direction } => {
+            if global.field.chunk(id).is_none() {
+                return Err(format!("Chunk not found: id = {}", id));
+            }
+            let generated = global
+                .field
+                .generate_chunk(id, direction)
+                .ok_or_else(|| format!("Already generated: id = {}, {:?}", id, direction))?;
+            crate::metrics::inc("cark_chunks_generated_total", &[], 1);
+            Ok(format!("Chunk generated: id = {}", generated))
+        }
+        AdminCommand::Chunk { id } => {
+            let chunk = global
+                .field
+                .chunk(id)
+                .ok_or_else(|| format!("Chunk not found: id = {}", id))?;
+            let solid = Layer::ALL
+                .iter()
+                .map(|layer| {
+                    chunk
+                        .layer(*layer)
+                        .iter()
+                        .filter(|t| global.tiles.is_solid(**t))
+                        .count()
+                })
+                .sum::<usize>();
+            let characters: Vec<_> = global
+                .characters
+                .iter()
+                .filter(|c| c.chunk_id == id)
+                .map(|c| c.name.as_str())
+                .collect();
+            let entities = global
+                .entities
+                .iter()
+                .filter(|e| e.transform.is_some_and(|t| t.chunk_id == id))
+                .count();
+            Ok(format!(
+                "Chunk: id = {}, version = {}, related = {:?}, portals = {:?}, solid tiles = {}, characters = {:?}, entities = {}",
+                id, chunk.version, chunk.related, chunk.portals, solid, characters, entities
+            ))
+        }
+        AdminCommand::Stats => {
+            let npcs = global.characters.iter().filter(|c| c.npc).count();
+            Ok(format!(
+                "Field: chunks = {}, chunk size = {}\nCharacters: players = {}, npcs = {}\nEntities: {}\nConnections: tcp = {}, udp = {}",
+                global.field.chunks.len(),
+                global.field.chunk_size(),
+                global.characters.len() - npcs,
+                npcs,
+                global.entities.len(),
+                tcp.connections().len(),
+                udp.connections().len(),
+            ))
+        }
+        AdminCommand::Save => {
+            let ids: Vec<_> = global
+                .characters
+                .iter()
+                .filter(|c| !c.npc)
+                .map(|c| c.id)
+                .collect();
+            for id in &ids {
+                global.save_player(*id);
+            }
+            global.save_world();
+            Ok(format!(
+                "Saved: players = {}, chunks = {}",
+                ids.len(),
+                global.field.chunks.len()
+            ))
+        }
+        AdminCommand::LogLevel { level } => {
+            log::set_max_level(level);
+            Ok(format!("Log level: {}", level))
+        }
+        AdminCommand::Game(command) => global.execute_command(command, |e| tcp.push_event(e)),
+    }
+}
+
+impl Global {
+    // Id of the player joined with the id or the name
+    fn resolve_player(&self, target: &str) -> Result<u64, String> {
+        let id = self.resolve_character(target)?;
+        if self.characters.iter().any(|c| c.id == id && c.npc) {
+            return Err(format!("Not a player: {}", target));
+        }
+        Ok(id)
+    }
+
+    fn resolve_character(&self, target: &str) -> Result<u64, String> {
+        self.characters
+            .iter()
+            .find(|c| c.name == target || c.id.to_string() == target)
+            .map(|c| c.id)
+            .ok_or_else(|| format!("Character not found: {}", target))
+    }
+
+    // Move a character to the position in the chunk, sending the chunk to players first
+    pub fn teleport(
+        &mut self,
+        id: u64,
+        chunk_id: ChunkId,
+        position: [f32; 2],
+        mut push_tcp_event: impl FnMut(OutgoingEvent),
+    ) -> Result<(), String> {
+        let chunk = self
+            .field
+            .chunk(chunk_id)
+            .ok_or_else(|| format!("Chunk not found: id = {}", chunk_id))?;
+        let size = self.field.chunk_size() as f32;
+        if !(0.0..size).contains(&position[0]) || !(0.0..size).contains(&position[1]) {
+            return Err(format!("Position out of the chunk: {:?}", position));
+        }
+        let character = self
+            .characters
+            .iter_mut()
+            .find(|c| c.id == id)
+            .ok_or_else(|| format!("Character not found: id = {}", id))?;
+        character.chunk_id = chunk_id;
+        character.position = position;
+
+        if character.npc {
+            if let Some(npc) = self.npcs.get_mut(id) {
+                npc.body.chunk_id = chunk_id;
+                npc.body.position = position;
+                npc.body.velocity = [0.0, 0.0];
+            }
+        } else {
+            push_tcp_event(OutgoingEvent {
+                connection_id: Some(id),
+                message: ServerMessage::Chunk {
+                    chunk: chunk.clone(),
+                },
+            });
+            push_tcp_event(OutgoingEvent {
+                connection_id: Some(id),
+                message: ServerMessage::Teleport { chunk_id, position },
+            });
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test() {
+    assert_eq!(AdminCommand::parse("list"), Ok(AdminCommand::List));
+    assert_eq!(
+        AdminCommand::parse(" broadcast  Server restarts in 5 minutes "),
+        Ok(AdminCommand::Broadcast {
+            text: "Server restarts in 5 minutes".to_string()
+        })
+    );
+    assert_eq!(
+        AdminCommand::parse("teleport alice 2 3.5 4.5"),
+        Ok(AdminCommand::Teleport {
+            target: "alice".to_string(),
+            chunk_id: ChunkId::new(2).unwrap(),
+            position: [3.5, 4.5],
+        })
+    );
+    assert_eq!(
+        AdminCommand::parse("loglevel debug"),
+        Ok(AdminCommand::LogLevel {
+            level: log::LevelFilter::Debug
+        })
+    );
+    assert!(matches!(
+        AdminCommand::parse("settile 1 walls 3 4 1"),
+        Ok(AdminCommand::Game(Command::SetTile { .. }))
+    ));
+    assert_eq!(
+        AdminCommand::parse("unban account:bob"),
+        Ok(AdminCommand::Unban {
+            rule: Rule::Account("bob".to_string())
+        })
+    );
+    assert!(AdminCommand::parse("allow 10.0.0.0/40").is_err());
+    assert!(AdminCommand::parse("kick").is_err());
+    assert!(AdminCommand::parse("stats now").is_err());
+    assert!(AdminCommand::parse("jump").is_err());
+
+    let tiles = crate::tiles::parse_tiles(include_str!("../assets/tiles.toml"));
+    let mut global = Global::new(tiles, 16);
+    global.characters.push(cark_common::model::Character {
+        id: 7,
+        name: "alice".to_string(),
+        chunk_id: ChunkId::MIN,
+        position: [2.5, 2.5],
+        npc: false,
+    });
+    let mut events = vec![];
+    global
+        .teleport(7, ChunkId::MIN, [8.5, 9.5], |e| events.push(e))
+        .unwrap();
+    assert_eq!(global.characters[0].position, [8.5, 9.5]);
+    assert!(matches!(
+        events.last().unwrap().message,
+        ServerMessage::Teleport { .. }
+    ));
+    assert!(global
+        .teleport(7, ChunkId::MIN, [18.0, 0.0], |_| {})
+        .is_err());
+    assert_eq!(global.resolve_player("7"), Ok(7));
+    assert!(global.resolve_player("bob").is_err());
+}
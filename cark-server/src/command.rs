@@ -0,0 +1,91 @@
+use cark_common::{
+    direction::Direction,
+    field::{ChunkId, Layer},
+    tile::TileId,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Portal {
+        from: ChunkId,
+        direction: Direction,
+        to: ChunkId,
+    },
+    Unportal {
+        id: ChunkId,
+        direction: Direction,
+    },
+    SetTile {
+        id: ChunkId,
+        layer: Layer,
+        position: [usize; 2],
+        tile: TileId,
+    },
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let mut args = line.trim().trim_start_matches('/').split_whitespace();
+        let name = args.next().ok_or("Empty command")?;
+        let command = match name {
+            "portal" => Command::Portal {
+                from: parse_arg(args.next(), "from")?,
+                direction: parse_arg(args.next(), "direction")?,
+                to: parse_arg(args.next(), "to")?,
+            },
+            "unportal" => Command::Unportal {
+                id: parse_arg(args.next(), "id")?,
+                direction: parse_arg(args.next(), "direction")?,
+            },
+            "settile" => Command::SetTile {
+                id: parse_arg(args.next(), "id")?,
+                layer: parse_arg(args.next(), "layer")?,
+                position: [parse_arg(args.next(), "x")?, parse_arg(args.next(), "y")?],
+                tile: parse_arg(args.next(), "tile")?,
+            },
+            _ => return Err(format!("Unknown command: {}", name)),
+        };
+        if let Some(arg) = args.next() {
+            return Err(format!("Unexpected argument: {}", arg));
+        }
+        Ok(command)
+    }
+}
+
+pub(crate) fn parse_arg<T: std::str::FromStr>(arg: Option<&str>, name: &str) -> Result<T, String> {
+    let arg = arg.ok_or_else(|| format!("Missing argument: {}", name))?;
+    arg.parse()
+        .map_err(|_| format!("Invalid argument: {} = {}", name, arg))
+}
+
+#[test]
+fn test() {
+    assert_eq!(
+        Command::parse("/portal 1 right 3"),
+        Ok(Command::Portal {
+            from: ChunkId::new(1).unwrap(),
+            direction: Direction::Right,
+            to: ChunkId::new(3).unwrap(),
+        })
+    );
+    assert_eq!(
+        Command::parse("unportal 2 top"),
+        Ok(Command::Unportal {
+            id: ChunkId::new(2).unwrap(),
+            direction: Direction::Top,
+        })
+    );
+    assert_eq!(
+        Command::parse("/settile 2 walls 3 4 1"),
+        Ok(Command::SetTile {
+            id: ChunkId::new(2).unwrap(),
+            layer: Layer::Walls,
+            position: [3, 4],
+            tile: 1,
+        })
+    );
+    assert!(Command::parse("/portal 0 right 3").is_err());
+    assert!(Command::parse("/portal 1 up 3").is_err());
+    assert!(Command::parse("/unportal 1 left 3").is_err());
+    assert!(Command::parse("/jump").is_err());
+}
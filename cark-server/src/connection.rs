@@ -1,46 +1,56 @@
 use std::io::{Read, Write};
-use std::net::TcpStream;
 
-use crate::{IncomingEvent, OutgoingEvent};
+use cark_common::transport::Stream;
+
+use crate::{metrics, IncomingEvent, OutgoingEvent};
 
 pub struct Connection {
-    pub stream: TcpStream,
+    pub stream: Box<dyn Stream>,
     pub buf: Vec<u8>,
     pub closed: bool,
+    // Name of the last join admitted on the connection
+    pub(crate) name: Option<String>,
 }
 
 impl Connection {
-    pub fn new(stream: TcpStream) -> std::io::Result<Self> {
-        log::info!("Client connected: {:?}", stream);
-
-        stream.set_nonblocking(true)?;
-        Ok(Self {
+    pub fn new(stream: Box<dyn Stream>) -> Self {
+        let connection = Self {
             stream,
             buf: vec![],
             closed: false,
-        })
+            name: None,
+        };
+        log::info!("Client connected: {}", connection);
+        connection
     }
 
     pub fn id(&self) -> u64 {
-        #[cfg(any(unix, target_os = "wasi"))]
-        {
-            use std::os::fd::AsRawFd;
-
-            self.stream.as_raw_fd() as u64
-        }
-        #[cfg(windows)]
-        {
-            use std::os::windows::io::AsRawSocket;
-
-            self.stream.as_raw_socket() as u64
-        }
+        self.stream.id()
     }
 
-    fn write(&mut self, message: &cark_common::model::ServerMessage) -> std::io::Result<()> {
-        match cark_common::write(message, &mut self.stream) {
-            Ok(_) => {}
+    pub(crate) fn write(
+        &mut self,
+        message: &cark_common::model::ServerMessage,
+    ) -> std::io::Result<()> {
+        let mut writer = CountingWriter {
+            inner: &mut self.stream,
+            count: 0,
+        };
+        match cark_common::write(message, &mut writer) {
+            Ok(writer) => {
+                metrics::inc(
+                    "cark_bytes_sent_total",
+                    &[("transport", "tcp")],
+                    writer.count,
+                );
+                metrics::inc(
+                    "cark_messages_sent_total",
+                    &[("transport", "tcp"), ("message", message.name())],
+                    1,
+                );
+            }
             Err(cark_common::PostcardError::SerializeBufferFull) => {
-                log::info!("Client disconnected: {:?}", self.stream);
+                log::info!("Client disconnected: {}", self);
                 self.closed = true;
                 return Ok(());
             }
@@ -66,8 +76,13 @@ impl Connection {
 
         match self.stream.read_to_end(&mut self.buf) {
             Ok(len) => {
+                metrics::inc(
+                    "cark_bytes_received_total",
+                    &[("transport", "tcp")],
+                    len as u64,
+                );
                 if len == 0 {
-                    log::info!("Client disconnected: {:?}", self.stream);
+                    log::info!("Client disconnected: {}", self);
                     self.closed = true;
                     return Ok(());
                 }
@@ -84,6 +99,11 @@ impl Connection {
                 Err(cark_common::PostcardError::DeserializeUnexpectedEnd) => break,
                 Err(e) => panic!("{:?}", e),
             };
+            metrics::inc(
+                "cark_messages_received_total",
+                &[("transport", "tcp"), ("message", message.name())],
+                1,
+            );
             push_incoming_event(IncomingEvent {
                 connection_id: self.id(),
                 sequence: 0,
@@ -94,7 +114,45 @@ impl Connection {
         Ok(())
     }
 
+    pub fn ip(&self) -> Option<std::net::IpAddr> {
+        self.stream.peer_addr().ok().map(|addr| addr.ip())
+    }
+
     pub fn is_closed(&self) -> bool {
         self.closed
     }
+
+    // Disconnect the client, the connection is removed on the next process
+    pub fn close(&mut self) {
+        log::info!("Client closed: {}", self);
+        let _ = self.stream.shutdown();
+        self.closed = true;
+    }
+}
+
+impl std::fmt::Display for Connection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.stream.peer_addr() {
+            Ok(addr) => write!(f, "id = {}, addr = {}", self.id(), addr),
+            Err(_) => write!(f, "id = {}", self.id()),
+        }
+    }
+}
+
+// Counts the bytes written through it
+struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.count += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
 }
@@ -0,0 +1,310 @@
+// Items lying in the world and the inventories of the players
+
+use cark_common::{
+    entity::{Collider, Component, EntityId, EntityKind, Inventory, ItemStack, Sprite, Transform},
+    field::ChunkId,
+    item::{ItemId, ItemRegistry, ItemUse},
+    model::ServerMessage,
+    physics,
+};
+
+use crate::{npc::free_position, Global, OutgoingEvent};
+
+// Radius of the items lying in the world
+const ITEM_RADIUS: f32 = 0.3;
+// Players can hand items to each other within this distance
+const GIVE_RANGE: f32 = 3.0;
+
+impl Global {
+    pub fn set_items(&mut self, items: ItemRegistry) {
+        self.items = items;
+    }
+
+    pub fn inventory(&self, user_id: u64) -> Option<&Inventory> {
+        self.inventories.get(&user_id)
+    }
+
+    // Put a stack of items in the world, returns the id of its entity
+    pub fn place_item(
+        &mut self,
+        chunk_id: ChunkId,
+        position: [f32; 2],
+        stack: ItemStack,
+        push_tcp_event: impl FnMut(OutgoingEvent),
+    ) -> EntityId {
+        let chip = self
+            .items
+            .get(stack.item)
+            .map_or([0, 0], |item| item.sprite);
+        self.spawn_entity(
+            EntityKind::Item,
+            vec![
+                Component::Transform(Transform { chunk_id, position }),
+                Component::Sprite(Sprite { chip, scale: 0.5 }),
+                Component::Collider(Collider {
+                    radius: ITEM_RADIUS,
+                    solid: false,
+                }),
+                Component::Inventory(Inventory { items: vec![stack] }),
+            ],
+            push_tcp_event,
+        )
+    }
+
+    // Place the items of the generation in the chunks that haven't had them yet
+    pub(crate) fn scatter_items(&mut self, mut push_tcp_event: impl FnMut(OutgoingEvent)) {
+        let mut chunk_ids: Vec<_> = self
+            .field
+            .chunks
+            .keys()
+            .copied()
+            .filter(|id| !self.scattered.contains(id))
+            .collect();
+        chunk_ids.sort();
+        for chunk_id in chunk_ids {
+            self.scattered.insert(chunk_id);
+            let scatter: Vec<_> = self
+                .items
+                .items
+                .iter()
+                .map(|item| (item.id, item.scatter))
+                .collect();
+            for (item, count) in scatter {
+                for _ in 0..count {
+                    let Some(position) =
+                        free_position(&self.field, &self.tiles, chunk_id, &mut self.rng)
+                    else {
+                        continue;
+                    };
+                    let stack = ItemStack { item, count: 1 };
+                    self.place_item(chunk_id, position, stack, &mut push_tcp_event);
+                }
+            }
+        }
+    }
+
+    // Players touching items pick them up
+    pub(crate) fn pick_up_items(&mut self, mut push_tcp_event: impl FnMut(OutgoingEvent)) {
+        let mut picked: Vec<(u64, EntityId)> = vec![];
+        let mut released = vec![];
+        for entity in self.entities.iter() {
+            let (Some(transform), Some(collider)) = (entity.transform, entity.collider) else {
+                continue;
+            };
+            if entity.kind != EntityKind::Item {
+                continue;
+            }
+            let touching = |character: &cark_common::model::Character| {
+                physics::touching(
+                    &self.field,
+                    (character.chunk_id, character.position),
+                    self.physics.radius,
+                    (transform.chunk_id, transform.position),
+                    collider.radius,
+                )
+            };
+            // The player who dropped the item has to step away before picking it up again
+            if let Some(owner) = self.no_pickup.get(&entity.id) {
+                if !self
+                    .characters
+                    .iter()
+                    .any(|c| c.id == *owner && touching(c))
+                {
+                    released.push(entity.id);
+                }
+            }
+            if let Some(character) = self
+                .characters
+                .iter()
+                .find(|c| !c.npc && self.no_pickup.get(&entity.id) != Some(&c.id) && touching(c))
+            {
+                picked.push((character.id, entity.id));
+            }
+        }
+
+        for id in released {
+            self.no_pickup.remove(&id);
+        }
+        for (user_id, id) in picked {
+            let Some(entity) = self.entities.get(id) else {
+                continue;
+            };
+            let items = entity.inventory.clone().unwrap_or_default().items;
+            self.despawn_entity(id, &mut push_tcp_event);
+            self.no_pickup.remove(&id);
+            let inventory = self.inventories.entry(user_id).or_default();
+            for stack in items {
+                inventory.add(stack.item, stack.count);
+            }
+            log::info!("Item picked up: user_id = {}, entity = {}", user_id, id);
+            self.send_inventory(user_id, &mut push_tcp_event);
+        }
+    }
+
+    pub fn drop_item(
+        &mut self,
+        user_id: u64,
+        item: ItemId,
+        count: u32,
+        mut push_tcp_event: impl FnMut(OutgoingEvent),
+    ) -> Result<(), String> {
+        let character = self
+            .characters
+            .iter()
+            .find(|c| c.id == user_id)
+            .ok_or_else(|| format!("Character not found: id = {}", user_id))?;
+        let (chunk_id, position) = (character.chunk_id, character.position);
+        self.take_items(user_id, item, count)?;
+        let stack = ItemStack { item, count };
+        let id = self.place_item(chunk_id, position, stack, &mut push_tcp_event);
+        self.no_pickup.insert(id, user_id);
+        self.send_inventory(user_id, &mut push_tcp_event);
+        Ok(())
+    }
+
+    pub fn use_item(
+        &mut self,
+        user_id: u64,
+        item: ItemId,
+        mut push_tcp_event: impl FnMut(OutgoingEvent),
+    ) -> Result<(), String> {
+        let on_use = self
+            .items
+            .get(item)
+            .and_then(|kind| kind.on_use.clone())
+            .ok_or_else(|| format!("Item can't be used: item = {}", item))?;
+        let character = self
+            .characters
+            .iter()
+            .find(|c| c.id == user_id)
+            .ok_or_else(|| format!("Character not found: id = {}", user_id))?;
+        let (chunk_id, position) = (character.chunk_id, character.position);
+        if self.inventory(user_id).map_or(0, |i| i.count(item)) == 0 {
+            return Err(format!(
+                "Item not owned: user_id = {}, item = {}",
+                user_id, item
+            ));
+        }
+
+        match on_use {
+            ItemUse::PlaceTile { layer, tile } => {
+                let position = [position[0].floor() as usize, position[1].floor() as usize];
+                if !self.field.set_tile(chunk_id, layer, position, tile) {
+                    return Err(format!("Tile not placed: position = {:?}", position));
+                }
+                self.broadcast_field_changes(&mut push_tcp_event);
+            }
+        }
+        self.take_items(user_id, item, 1)?;
+        self.send_inventory(user_id, &mut push_tcp_event);
+        Ok(())
+    }
+
+    pub fn give_item(
+        &mut self,
+        from: u64,
+        to: u64,
+        item: ItemId,
+        count: u32,
+        mut push_tcp_event: impl FnMut(OutgoingEvent),
+    ) -> Result<(), String> {
+        let find = |id: u64| {
+            self.characters
+                .iter()
+                .find(|c| c.id == id && !c.npc)
+                .ok_or_else(|| format!("Player not found: id = {}", id))
+        };
+        let (giver, receiver) = (find(from)?, find(to)?);
+        if from == to
+            || !physics::touching(
+                &self.field,
+                (giver.chunk_id, giver.position),
+                GIVE_RANGE,
+                (receiver.chunk_id, receiver.position),
+                0.0,
+            )
+        {
+            return Err(format!("Too far to give: from = {}, to = {}", from, to));
+        }
+        self.take_items(from, item, count)?;
+        self.inventories.entry(to).or_default().add(item, count);
+        self.send_inventory(from, &mut push_tcp_event);
+        self.send_inventory(to, &mut push_tcp_event);
+        Ok(())
+    }
+
+    fn take_items(&mut self, user_id: u64, item: ItemId, count: u32) -> Result<(), String> {
+        let removed = count > 0
+            && self
+                .inventories
+                .get_mut(&user_id)
+                .is_some_and(|inventory| inventory.remove(item, count));
+        if !removed {
+            return Err(format!(
+                "Not enough items: user_id = {}, item = {}, count = {}",
+                user_id, item, count
+            ));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn send_inventory(
+        &self,
+        user_id: u64,
+        mut push_tcp_event: impl FnMut(OutgoingEvent),
+    ) {
+        push_tcp_event(OutgoingEvent {
+            connection_id: Some(user_id),
+            message: ServerMessage::Inventory {
+                inventory: self.inventories.get(&user_id).cloned().unwrap_or_default(),
+            },
+        });
+    }
+}
+
+#[test]
+fn test() {
+    use cark_common::model::Character;
+
+    let tiles = crate::tiles::parse_tiles(include_str!("../assets/tiles.toml"));
+    let mut global = Global::new(tiles, 16);
+    global.set_items(crate::items::parse_items(include_str!(
+        "../assets/items.toml"
+    )));
+    let chunk_id = ChunkId::MIN;
+    for (id, position) in [(1, [4.5, 4.5]), (2, [6.5, 4.5])] {
+        global.characters.push(Character {
+            id,
+            name: id.to_string(),
+            chunk_id,
+            position,
+            npc: false,
+        });
+    }
+    let gem = global.items.by_name("gem").unwrap().id;
+
+    let stack = ItemStack {
+        item: gem,
+        count: 2,
+    };
+    global.place_item(chunk_id, [4.6, 4.4], stack, |_| {});
+    global.pick_up_items(|_| {});
+    assert_eq!(global.inventory(1).unwrap().count(gem), 2);
+    assert!(global.entities.is_empty());
+
+    // A dropped item stays until the player steps away and comes back
+    global.drop_item(1, gem, 1, |_| {}).unwrap();
+    global.pick_up_items(|_| {});
+    assert_eq!(global.entities.len(), 1);
+    global.characters[0].position = [10.5, 10.5];
+    global.pick_up_items(|_| {});
+    global.characters[0].position = [4.5, 4.5];
+    global.pick_up_items(|_| {});
+    assert_eq!(global.inventory(1).unwrap().count(gem), 2);
+
+    assert!(global.drop_item(1, gem, 3, |_| {}).is_err());
+    assert!(global.use_item(1, gem, |_| {}).is_err());
+    global.give_item(1, 2, gem, 2, |_| {}).unwrap();
+    assert_eq!(global.inventory(1).unwrap().count(gem), 0);
+    assert_eq!(global.inventory(2).unwrap().count(gem), 2);
+}
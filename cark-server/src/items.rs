@@ -0,0 +1,33 @@
+use cark_common::item::ItemRegistry;
+
+const DEFAULT_ITEMS: &str = include_str!("../assets/items.toml");
+
+// Load the item registry from the file at `ITEMS`, or the bundled one
+pub fn load_items() -> ItemRegistry {
+    let items = match std::env::var("ITEMS") {
+        Ok(path) => std::fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("Failed to read items: path = {}, {}", path, e)),
+        Err(_) => DEFAULT_ITEMS.to_string(),
+    };
+    parse_items(&items)
+}
+
+pub fn parse_items(items: &str) -> ItemRegistry {
+    toml::from_str(items).unwrap()
+}
+
+#[test]
+fn test() {
+    use cark_common::{field::Layer, item::ItemUse};
+
+    let items = parse_items(DEFAULT_ITEMS);
+    assert_eq!(items.by_name("gem").unwrap().on_use, None);
+    assert_eq!(
+        items.by_name("brick").unwrap().on_use,
+        Some(ItemUse::PlaceTile {
+            layer: Layer::Walls,
+            tile: cark_common::tile::WALL
+        })
+    );
+    assert!(items.get(200).is_none());
+}
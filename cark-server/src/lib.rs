@@ -1,25 +1,309 @@
+pub mod access;
+pub mod accounts;
+pub mod admin;
+pub mod command;
 mod connection;
+mod inventory;
+pub mod items;
+pub mod metrics;
+pub mod npc;
+pub mod spawn;
 pub mod tcp;
+pub mod tiles;
 pub mod udp;
+pub mod world;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use accounts::{Accounts, SavedState};
 
 use cark_common::{
+    entity::{Component, Entities, Entity, EntityId, EntityKind, Inventory},
     field::{ChunkId, Field},
-    model::{Character, ClientMessage, JoinedCharacter, ServerMessage},
+    item::ItemRegistry,
+    model::{Character, ClientMessage, Join, JoinedCharacter, ServerMessage},
+    physics::{Body, FixedStep, PhysicsParams},
+    tile::TileRegistry,
     udp_stat::Sequence,
 };
+use command::Command;
+use npc::{Npcs, SpawnRule};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use spawn::SpawnConfig;
+
+// Number of recent changes kept per chunk to send diffs
+const CHUNK_HISTORY_SIZE: usize = 64;
+// Entity ids start here, above the ids of connections and NPCs
+const ENTITY_ID_BASE: EntityId = 1 << 49;
+const MAX_NAME_LENGTH: usize = 32;
+// Seconds between the position updates of NPCs
+const NPC_POSITION_INTERVAL: f32 = 0.05;
 
 pub struct Global {
     pub messages: Vec<String>,
+    world_id: u64,
     field: Field,
     characters: Vec<Character>,
+    tiles: TileRegistry,
+    npcs: Npcs,
+    physics: PhysicsParams,
+    fixed_step: FixedStep,
+    npc_position_timer: f32,
+    entities: Entities,
+    next_entity_id: EntityId,
+    items: ItemRegistry,
+    inventories: HashMap<u64, Inventory>,
+    // Chunks that have had their items placed
+    scattered: HashSet<ChunkId>,
+    // Dropped items that can't be picked up by the player who dropped them yet
+    no_pickup: HashMap<EntityId, u64>,
+    rng: StdRng,
+    accounts: Accounts,
+    spawns: SpawnConfig,
+    // Settings of the players joined, saved on leave
+    settings: HashMap<u64, BTreeMap<String, String>>,
+    // UDP session token => player id
+    udp_tokens: HashMap<u64, u64>,
+    // Players whose UDP traffic goes over TCP
+    udp_fallback: HashSet<u64>,
+    // Where the world is saved, kept in memory only if None
+    world_path: Option<std::path::PathBuf>,
 }
 
 impl Global {
-    pub fn new() -> Self {
+    pub fn new(tiles: TileRegistry, chunk_size: usize) -> Self {
+        let world_id = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64;
+        let mut field = Field::with_chunk_size(chunk_size);
+        field.keep_history(CHUNK_HISTORY_SIZE);
         Self {
             messages: vec![],
-            field: Field::new(),
+            world_id,
+            field,
             characters: vec![],
+            tiles,
+            npcs: Npcs::new(vec![]),
+            physics: PhysicsParams::default(),
+            fixed_step: FixedStep::new(),
+            npc_position_timer: 0.0,
+            entities: Entities::new(),
+            next_entity_id: ENTITY_ID_BASE,
+            items: ItemRegistry::default(),
+            inventories: HashMap::new(),
+            scattered: HashSet::new(),
+            no_pickup: HashMap::new(),
+            rng: StdRng::from_entropy(),
+            accounts: Accounts::new(),
+            spawns: SpawnConfig::default(),
+            settings: HashMap::new(),
+            udp_tokens: HashMap::new(),
+            udp_fallback: HashSet::new(),
+            world_path: None,
+        }
+    }
+
+    // Continue the world saved in the file, or start saving this one there
+    pub fn open_world(&mut self, path: impl Into<std::path::PathBuf>) {
+        let path = path.into();
+        if let Some(saved) = world::SavedWorld::read(&path) {
+            assert_eq!(
+                saved.chunk_size,
+                self.field.chunk_size(),
+                "CHUNK_SIZE differs from the world saved at {:?}",
+                path
+            );
+            log::info!(
+                "World loaded: path = {:?}, id = {:016x}, chunks = {}",
+                path,
+                saved.world_id,
+                saved.chunks.len()
+            );
+            self.world_id = saved.world_id;
+            self.field = Field::with_chunks(saved.chunk_size, saved.chunks);
+            self.field.keep_history(CHUNK_HISTORY_SIZE);
+        }
+        self.world_path = Some(path);
+        self.save_world();
+    }
+
+    pub fn save_world(&self) {
+        let Some(path) = &self.world_path else {
+            return;
+        };
+        let saved = world::SavedWorld {
+            world_id: self.world_id,
+            chunk_size: self.field.chunk_size(),
+            chunks: self.field.chunks.values().cloned().collect(),
+        };
+        saved.write(path);
+    }
+
+    pub fn set_accounts(&mut self, accounts: Accounts) {
+        self.accounts = accounts;
+    }
+
+    pub fn set_spawns(&mut self, spawns: SpawnConfig) {
+        self.spawns = spawns;
+    }
+
+    // Id of the player the UDP session token was given to
+    pub fn udp_session(&self, token: u64) -> Option<u64> {
+        self.udp_tokens.get(&token).copied()
+    }
+
+    // Pass an event meant for UDP on, over TCP to the players who can't use UDP
+    pub fn route_udp_event(
+        &self,
+        event: OutgoingEvent,
+        mut push_tcp_event: impl FnMut(OutgoingEvent),
+        mut push_udp_event: impl FnMut(OutgoingEvent),
+    ) {
+        match event.connection_id {
+            Some(id) if self.udp_fallback.contains(&id) => push_tcp_event(event),
+            Some(_) => push_udp_event(event),
+            None => {
+                for id in &self.udp_fallback {
+                    push_tcp_event(OutgoingEvent {
+                        connection_id: Some(*id),
+                        message: event.message.clone(),
+                    });
+                }
+                push_udp_event(event);
+            }
+        }
+    }
+
+    pub fn entities(&self) -> &Entities {
+        &self.entities
+    }
+
+    // Create an entity and tell everyone, returns its id
+    pub fn spawn_entity(
+        &mut self,
+        kind: EntityKind,
+        components: Vec<Component>,
+        mut push_tcp_event: impl FnMut(OutgoingEvent),
+    ) -> EntityId {
+        let id = self.next_entity_id;
+        self.next_entity_id += 1;
+        let entity = components
+            .into_iter()
+            .fold(Entity::new(id, kind), |entity, component| {
+                entity.with(component)
+            });
+        push_tcp_event(OutgoingEvent {
+            connection_id: None,
+            message: ServerMessage::EntitySpawned {
+                entity: entity.clone(),
+            },
+        });
+        self.entities.insert(entity);
+        id
+    }
+
+    pub fn despawn_entity(
+        &mut self,
+        id: EntityId,
+        mut push_tcp_event: impl FnMut(OutgoingEvent),
+    ) -> bool {
+        if self.entities.remove(id).is_none() {
+            return false;
+        }
+        push_tcp_event(OutgoingEvent {
+            connection_id: None,
+            message: ServerMessage::EntityDespawned { id },
+        });
+        true
+    }
+
+    // Set the gauges of the world
+    pub fn record_metrics(&self) {
+        metrics::set("cark_chunks", &[], self.field.chunks.len() as f64);
+    }
+
+    pub fn set_spawn_rules(&mut self, rules: Vec<SpawnRule>) {
+        self.npcs.set_rules(rules);
+    }
+
+    pub fn npcs_mut(&mut self) -> &mut Npcs {
+        &mut self.npcs
+    }
+
+    // Spawn and move the NPCs, to be called every loop with the elapsed time
+    pub fn update(
+        &mut self,
+        dt: f32,
+        mut push_tcp_event: impl FnMut(OutgoingEvent),
+        mut push_udp_event: impl FnMut(OutgoingEvent),
+    ) {
+        self.scatter_items(&mut push_tcp_event);
+        self.pick_up_items(&mut push_tcp_event);
+
+        for id in self.npcs.spawn(&self.field, &self.tiles, dt) {
+            let npc = self.npcs.npcs().iter().find(|npc| npc.id == id).unwrap();
+            log::info!("NPC spawned: id = {}, name = {}", id, npc.name);
+            self.characters.push(Character {
+                id,
+                name: npc.name.clone(),
+                chunk_id: npc.body.chunk_id,
+                position: npc.body.position,
+                npc: true,
+            });
+            push_tcp_event(OutgoingEvent {
+                connection_id: None,
+                message: ServerMessage::PlayerJoined {
+                    id,
+                    name: npc.name.clone(),
+                    chunk_id: npc.body.chunk_id,
+                    position: npc.body.position,
+                    npc: true,
+                },
+            });
+        }
+
+        let steps = self.fixed_step.advance(&self.physics, dt);
+        if steps == 0 {
+            return;
+        }
+        let players: Vec<_> = self
+            .characters
+            .iter()
+            .filter(|c| !c.npc)
+            .map(|c| Body::new(c.chunk_id, c.position, [0.0, 0.0]))
+            .collect();
+        for _ in 0..steps {
+            self.npcs.step(
+                &self.physics,
+                &self.field,
+                &self.tiles,
+                &players,
+                self.physics.timestep,
+            );
+        }
+
+        self.npc_position_timer -= dt;
+        let send_positions = self.npc_position_timer <= 0.0;
+        if send_positions {
+            self.npc_position_timer = NPC_POSITION_INTERVAL;
+        }
+        for npc in self.npcs.npcs() {
+            if let Some(character) = self.characters.iter_mut().find(|c| c.id == npc.id) {
+                character.chunk_id = npc.body.chunk_id;
+                character.position = npc.body.position;
+            }
+            if send_positions {
+                push_udp_event(OutgoingEvent {
+                    connection_id: None,
+                    message: ServerMessage::Position {
+                        user_id: npc.id,
+                        chunk_id: npc.body.chunk_id,
+                        position: npc.body.position,
+                        velocity: npc.body.velocity,
+                    },
+                });
+            }
         }
     }
 
@@ -29,53 +313,53 @@ impl Global {
         mut push_tcp_event: impl FnMut(OutgoingEvent),
         mut push_udp_event: impl FnMut(OutgoingEvent),
     ) {
+        for (user_id, name, result) in self.accounts.finish_logins() {
+            self.finish_join(user_id, name, result, &mut push_tcp_event);
+        }
+
         for event in incoming_events.drain(..) {
             log::debug!("{:?}", &event);
 
             match &event.message {
                 ClientMessage::Join(join) => {
-                    let user_id = event.connection_id;
-                    let chunk_id = ChunkId::MIN;
-                    let position = [2.0, 2.0];
-                    self.characters.push(Character {
-                        id: user_id,
-                        name: join.name.clone(),
-                        chunk_id,
-                        position,
-                    });
-                    push_tcp_event(OutgoingEvent {
-                        connection_id: Some(event.connection_id),
-                        message: ServerMessage::Joined(cark_common::model::Joined {
-                            user_id,
-                            chunk: self.field.chunk(chunk_id).unwrap().clone(),
-                            characters: self
-                                .characters
-                                .iter()
-                                .map(|c| JoinedCharacter {
-                                    id: c.id,
-                                    name: c.name.clone(),
-                                    chunk_id: c.chunk_id.clone(),
-                                    position: c.position,
-                                })
-                                .collect(),
-                        }),
-                    });
-                    push_tcp_event(OutgoingEvent {
-                        connection_id: None,
-                        message: ServerMessage::PlayerJoined {
-                            id: user_id,
-                            name: join.name.clone(),
-                            chunk_id: chunk_id,
-                            position,
-                        },
-                    });
+                    self.join(event.connection_id, join, &mut push_tcp_event);
                 }
                 ClientMessage::Leave => {
-                    self.characters.retain(|c| c.id != event.connection_id);
+                    self.leave(event.connection_id, &mut push_tcp_event);
+                }
+                // Handled by `Udp`
+                ClientMessage::Pong { .. } => {}
+                ClientMessage::UdpFallback { enabled } => {
+                    if !self.characters.iter().any(|c| c.id == event.connection_id) {
+                        continue;
+                    }
+                    log::info!(
+                        "UDP fallback: id = {}, enabled = {}",
+                        event.connection_id,
+                        enabled
+                    );
+                    if *enabled {
+                        self.udp_fallback.insert(event.connection_id);
+                    } else {
+                        self.udp_fallback.remove(&event.connection_id);
+                    }
+                }
+                ClientMessage::Settings { settings } => {
+                    if let Some(saved) = self.settings.get_mut(&event.connection_id) {
+                        *saved = settings.clone();
+                    }
+                }
+                // The commands rewrite the shared world, so they are only taken from the admin console
+                ClientMessage::PublicChatMessage(message) if message.text.starts_with('/') => {
+                    log::warn!(
+                        "Chat command rejected: id = {}, text = {:?}",
+                        event.connection_id,
+                        message.text
+                    );
                     push_tcp_event(OutgoingEvent {
-                        connection_id: None,
-                        message: ServerMessage::PlayerLeft {
-                            user_id: event.connection_id,
+                        connection_id: Some(event.connection_id),
+                        message: ServerMessage::Notice {
+                            text: "Commands are only available on the admin console".to_string(),
                         },
                     });
                 }
@@ -96,6 +380,14 @@ impl Global {
                     position,
                     velocity,
                 } => {
+                    if let Some(character) = self
+                        .characters
+                        .iter_mut()
+                        .find(|c| c.id == event.connection_id)
+                    {
+                        character.chunk_id = *chunk_id;
+                        character.position = *position;
+                    }
                     push_udp_event(OutgoingEvent {
                         connection_id: None,
                         message: ServerMessage::Position {
@@ -113,7 +405,9 @@ impl Global {
                     // }
                 }
                 ClientMessage::RequestChunk { id, direction } => {
-                    self.field.generate_chunk(*id, *direction);
+                    if self.field.generate_chunk(*id, *direction).is_some() {
+                        metrics::inc("cark_chunks_generated_total", &[], 1);
+                    }
 
                     if let Some(chunk) = self
                         .field
@@ -131,9 +425,301 @@ impl Global {
                         log::warn!("Chunk requested but not found: id = {:?}", id);
                     }
                 }
+                ClientMessage::SyncChunk { id, known_version } => {
+                    let Some(chunk) = self.field.chunk(*id) else {
+                        log::warn!("Chunk requested but not found: id = {:?}", id);
+                        continue;
+                    };
+                    let message = match self.field.diff(*id, *known_version) {
+                        Some(changes) if changes.is_empty() => {
+                            ServerMessage::ChunkUnchanged { id: *id }
+                        }
+                        Some(changes) => ServerMessage::ChunkDiff {
+                            id: *id,
+                            from_version: *known_version,
+                            to_version: chunk.version,
+                            changes,
+                        },
+                        None => ServerMessage::Chunk {
+                            chunk: chunk.clone(),
+                        },
+                    };
+                    push_tcp_event(OutgoingEvent {
+                        connection_id: Some(event.connection_id),
+                        message,
+                    });
+                }
+                ClientMessage::DropItem { item, count } => {
+                    if let Err(e) =
+                        self.drop_item(event.connection_id, *item, *count, &mut push_tcp_event)
+                    {
+                        log::warn!("Drop failed: {}", e);
+                    }
+                }
+                ClientMessage::UseItem { item } => {
+                    if let Err(e) = self.use_item(event.connection_id, *item, &mut push_tcp_event) {
+                        log::warn!("Use failed: {}", e);
+                    }
+                }
+                ClientMessage::GiveItem { to, item, count } => {
+                    if let Err(e) =
+                        self.give_item(event.connection_id, *to, *item, *count, &mut push_tcp_event)
+                    {
+                        log::warn!("Give failed: {}", e);
+                    }
+                }
             }
         }
+
+        self.broadcast_field_changes(&mut push_tcp_event);
+    }
+
+    // The player joins once the password is checked, see `finish_join`
+    fn join(&mut self, user_id: u64, join: &Join, mut push_tcp_event: impl FnMut(OutgoingEvent)) {
+        let result = self.admit(user_id, &join.name).and_then(|_| {
+            self.accounts
+                .start_login(user_id, &join.name, &join.password)
+        });
+        if let Err(reason) = result {
+            reject_join(user_id, &join.name, reason, &mut push_tcp_event);
+        }
     }
+
+    fn finish_join(
+        &mut self,
+        user_id: u64,
+        name: String,
+        login: Result<(), String>,
+        mut push_tcp_event: impl FnMut(OutgoingEvent),
+    ) {
+        // Others may have joined with the name while the password was checked
+        if let Err(reason) = login.and_then(|_| self.admit(user_id, &name)) {
+            reject_join(user_id, &name, reason, &mut push_tcp_event);
+            return;
+        }
+
+        let state = self
+            .accounts
+            .get(&name)
+            .and_then(|account| account.state.clone())
+            .unwrap_or_default();
+        // The last session may have been in another world
+        let last = ChunkId::new(state.chunk_id)
+            .filter(|id| state.world_id == self.world_id && self.field.chunk(*id).is_some())
+            .map(|id| (id, state.position));
+        let spawn = self
+            .spawns
+            .choose(last, &self.field, &self.characters, &mut self.rng);
+        let (chunk_id, position) = spawn::safe_position(
+            &self.field,
+            &self.tiles,
+            spawn,
+            &self.characters,
+            self.physics.radius,
+        );
+        self.inventories.insert(user_id, state.inventory);
+        self.settings.insert(user_id, state.settings.clone());
+        // Random so that nobody else can take over the session
+        let udp_token = loop {
+            let token = self.rng.gen();
+            if token != 0 && !self.udp_tokens.contains_key(&token) {
+                break token;
+            }
+        };
+        self.udp_tokens.insert(udp_token, user_id);
+        self.characters.push(Character {
+            id: user_id,
+            name: name.clone(),
+            chunk_id,
+            position,
+            npc: false,
+        });
+        push_tcp_event(OutgoingEvent {
+            connection_id: Some(user_id),
+            message: ServerMessage::Joined(Box::new(cark_common::model::Joined {
+                user_id,
+                world_id: self.world_id,
+                chunk_size: self.field.chunk_size(),
+                chunk: self.field.chunk(chunk_id).unwrap().clone(),
+                characters: self
+                    .characters
+                    .iter()
+                    .map(|c| JoinedCharacter {
+                        id: c.id,
+                        name: c.name.clone(),
+                        chunk_id: c.chunk_id,
+                        position: c.position,
+                        npc: c.npc,
+                    })
+                    .collect(),
+                entities: self.entities.iter().cloned().collect(),
+                tiles: self.tiles.clone(),
+                items: self.items.clone(),
+                settings: state.settings,
+                udp_token,
+            })),
+        });
+        self.send_inventory(user_id, &mut push_tcp_event);
+        metrics::inc("cark_joins_total", &[], 1);
+        push_tcp_event(OutgoingEvent {
+            connection_id: None,
+            message: ServerMessage::PlayerJoined {
+                id: user_id,
+                name,
+                chunk_id,
+                position,
+                npc: false,
+            },
+        });
+    }
+
+    // Check the name of a join, the password is checked by `Accounts`
+    fn admit(&self, user_id: u64, name: &str) -> Result<(), String> {
+        if self.characters.iter().any(|c| c.id == user_id) {
+            return Err("Already joined".to_string());
+        }
+        if name.trim().is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+            return Err(format!("Invalid name: {:?}", name));
+        }
+        if self.characters.iter().any(|c| c.name == name) {
+            return Err(format!("Name already in use: {}", name));
+        }
+        Ok(())
+    }
+
+    fn leave(&mut self, user_id: u64, mut push_tcp_event: impl FnMut(OutgoingEvent)) {
+        self.accounts.cancel_login(user_id);
+        let Some(index) = self.characters.iter().position(|c| c.id == user_id) else {
+            return;
+        };
+        self.save_player(user_id);
+        self.characters.remove(index);
+        self.inventories.remove(&user_id);
+        self.settings.remove(&user_id);
+        self.udp_tokens.retain(|_, id| *id != user_id);
+        self.udp_fallback.remove(&user_id);
+        self.no_pickup.retain(|_, id| *id != user_id);
+        metrics::inc("cark_leaves_total", &[], 1);
+        push_tcp_event(OutgoingEvent {
+            connection_id: None,
+            message: ServerMessage::PlayerLeft { user_id },
+        });
+    }
+
+    // Save the state of a player joined to the account
+    fn save_player(&mut self, user_id: u64) {
+        let Some(character) = self.characters.iter().find(|c| c.id == user_id && !c.npc) else {
+            return;
+        };
+        let state = SavedState {
+            world_id: self.world_id,
+            chunk_id: character.chunk_id.get(),
+            position: character.position,
+            inventory: self.inventories.get(&user_id).cloned().unwrap_or_default(),
+            settings: self.settings.get(&user_id).cloned().unwrap_or_default(),
+        };
+        self.accounts.save_state(&character.name.clone(), state);
+    }
+
+    pub fn execute_command(
+        &mut self,
+        command: Command,
+        mut push_tcp_event: impl FnMut(OutgoingEvent),
+    ) -> Result<String, String> {
+        match command {
+            Command::Portal {
+                from,
+                direction,
+                to,
+            } => {
+                self.field
+                    .link_portal(from, direction, to)
+                    .ok_or_else(|| format!("Chunk not found: from = {}, to = {}", from, to))?;
+                self.broadcast_field_changes(&mut push_tcp_event);
+                Ok(format!(
+                    "Portal linked: from = {}, direction = {:?}, to = {}",
+                    from, direction, to
+                ))
+            }
+            Command::Unportal { id, direction } => {
+                self.field.unlink_portal(id, direction).ok_or_else(|| {
+                    format!("Portal not found: id = {}, direction = {:?}", id, direction)
+                })?;
+                self.broadcast_field_changes(&mut push_tcp_event);
+                Ok(format!(
+                    "Portal unlinked: id = {}, direction = {:?}",
+                    id, direction
+                ))
+            }
+            Command::SetTile {
+                id,
+                layer,
+                position,
+                tile,
+            } => {
+                if self.field.chunk(id).is_none() {
+                    return Err(format!("Chunk not found: id = {}", id));
+                }
+                if !self.field.set_tile(id, layer, position, tile) {
+                    return Err(format!(
+                        "Tile not changed: id = {}, layer = {}, position = {:?}",
+                        id,
+                        layer.name(),
+                        position
+                    ));
+                }
+                self.broadcast_field_changes(&mut push_tcp_event);
+                Ok(format!(
+                    "Tile set: id = {}, layer = {}, position = {:?}, tile = {}",
+                    id,
+                    layer.name(),
+                    position,
+                    tile
+                ))
+            }
+        }
+    }
+
+    // Send the diffs of all chunks changed since the last call
+    fn broadcast_field_changes(&mut self, mut push_tcp_event: impl FnMut(OutgoingEvent)) {
+        for (id, from_version) in self.field.take_modified() {
+            let chunk = self.field.chunk(id).unwrap();
+            let message = match self.field.diff(id, from_version) {
+                Some(changes) => ServerMessage::ChunkDiff {
+                    id,
+                    from_version,
+                    to_version: chunk.version,
+                    changes,
+                },
+                None => ServerMessage::Chunk {
+                    chunk: chunk.clone(),
+                },
+            };
+            push_tcp_event(OutgoingEvent {
+                connection_id: None,
+                message,
+            });
+        }
+    }
+}
+
+fn reject_join(
+    user_id: u64,
+    name: &str,
+    reason: String,
+    mut push_tcp_event: impl FnMut(OutgoingEvent),
+) {
+    log::info!(
+        "Join rejected: id = {}, name = {}, {}",
+        user_id,
+        name,
+        reason
+    );
+    metrics::inc("cark_join_rejections_total", &[], 1);
+    push_tcp_event(OutgoingEvent {
+        connection_id: Some(user_id),
+        message: ServerMessage::JoinRejected { reason },
+    });
 }
 
 #[derive(Debug)]
@@ -148,3 +734,279 @@ pub struct OutgoingEvent {
     connection_id: Option<u64>,
     message: ServerMessage,
 }
+
+#[test]
+fn test_join() {
+    let path = std::env::temp_dir().join(format!("cark-join-{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let tiles = tiles::parse_tiles(include_str!("../assets/tiles.toml"));
+    let mut global = Global::new(tiles, 16);
+    global.set_accounts(Accounts::open(&path));
+    for chunk in global.field.chunks.values_mut() {
+        chunk.walls.fill(cark_common::tile::VOID);
+    }
+
+    let join = |connection_id, name: &str, password: &str| IncomingEvent {
+        connection_id,
+        sequence: 0,
+        message: ClientMessage::Join(Join {
+            name: name.to_string(),
+            password: password.to_string(),
+        }),
+    };
+    let mut replies = vec![];
+    // Until the password is checked on the other thread
+    let mut process = |global: &mut Global, event| {
+        let mut events = vec![event];
+        loop {
+            replies.clear();
+            global.process(&mut events, |e| replies.push(e), |_| {});
+            let reply = replies.iter().find_map(|e| match &e.message {
+                ServerMessage::Joined(joined) => Some(Ok(joined.user_id)),
+                ServerMessage::JoinRejected { reason } => Some(Err(reason.clone())),
+                _ => None,
+            });
+            if let Some(reply) = reply {
+                return reply;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+    };
+
+    assert_eq!(process(&mut global, join(1, "alice", "pw")), Ok(1));
+    let token = *global.udp_tokens.keys().next().unwrap();
+    assert_eq!(global.udp_session(token), Some(1));
+    // Names are unique among the players joined, and passwords are checked
+    assert!(process(&mut global, join(2, "alice", "pw")).is_err());
+    assert!(process(&mut global, join(2, "", "pw")).is_err());
+
+    // Players can't edit the world through the chat
+    let chunk_id = global.characters[0].chunk_id;
+    let mut replies = vec![];
+    global.process(
+        &mut vec![IncomingEvent {
+            connection_id: 1,
+            sequence: 0,
+            message: ClientMessage::PublicChatMessage(cark_common::model::PublicChatMessage {
+                text: format!("/portal {} right {}", chunk_id, chunk_id),
+            }),
+        }],
+        |e| replies.push(e),
+        |_| {},
+    );
+    assert_eq!(global.field.chunks[&chunk_id].portals, [false; 4]);
+    assert!(matches!(
+        replies[..],
+        [OutgoingEvent {
+            message: ServerMessage::Notice { .. },
+            ..
+        }]
+    ));
+
+    global.characters[0].position = [5.5, 6.5];
+    global.process(
+        &mut vec![IncomingEvent {
+            connection_id: 1,
+            sequence: 0,
+            message: ClientMessage::Leave,
+        }],
+        |_| {},
+        |_| {},
+    );
+    assert_eq!(global.udp_session(token), None);
+    assert!(process(&mut global, join(2, "alice", "wrong")).is_err());
+    assert_eq!(process(&mut global, join(2, "alice", "pw")), Ok(2));
+    assert_eq!(global.characters[0].position, [5.5, 6.5]);
+
+    // Positions saved in another world are not restored
+    let leave = |connection_id| IncomingEvent {
+        connection_id,
+        sequence: 0,
+        message: ClientMessage::Leave,
+    };
+    global.process(&mut vec![leave(2)], |_| {}, |_| {});
+    global.world_id += 1;
+    assert_eq!(process(&mut global, join(3, "alice", "pw")), Ok(3));
+    assert_ne!(global.characters[0].position, [5.5, 6.5]);
+
+    // Clients that leave before the password is checked never join
+    let mut replies = vec![];
+    global.process(
+        &mut vec![join(4, "bob", "pw"), join(4, "bob", "pw"), leave(4)],
+        |e| replies.push(e),
+        |_| {},
+    );
+    assert!(matches!(
+        replies[..],
+        [OutgoingEvent {
+            message: ServerMessage::JoinRejected { .. },
+            ..
+        }]
+    ));
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    global.process(&mut vec![], |_| {}, |_| {});
+    assert!(global.characters.iter().all(|c| c.id != 4));
+
+    // The accounts are written on drop at the latest
+    drop(global);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_route_udp_event() {
+    let tiles = tiles::parse_tiles(include_str!("../assets/tiles.toml"));
+    let mut global = Global::new(tiles, 16);
+    global.characters.push(Character {
+        id: 1,
+        name: "alice".to_string(),
+        chunk_id: ChunkId::MIN,
+        position: [2.5, 2.5],
+        npc: false,
+    });
+    global.process(
+        &mut vec![IncomingEvent {
+            connection_id: 1,
+            sequence: 0,
+            message: ClientMessage::UdpFallback { enabled: true },
+        }],
+        |_| {},
+        |_| {},
+    );
+
+    let (mut tcp, mut udp) = (vec![], vec![]);
+    for connection_id in [None, Some(1), Some(2)] {
+        global.route_udp_event(
+            OutgoingEvent {
+                connection_id,
+                message: ServerMessage::PlayerLeft { user_id: 3 },
+            },
+            |e| tcp.push(e.connection_id),
+            |e| udp.push(e.connection_id),
+        );
+    }
+    assert_eq!(tcp, [Some(1), Some(1)]);
+    assert_eq!(udp, [None, Some(2)]);
+}
+
+// One loop of main without the sleep
+#[cfg(test)]
+fn tick(global: &mut Global, tcp: &mut tcp::Tcp, udp: &mut udp::Udp) {
+    let mut incoming_events = vec![];
+    let mut udp_events = vec![];
+    udp.process(
+        |token| global.udp_session(token),
+        |e| incoming_events.push(e),
+    )
+    .unwrap();
+    tcp.process(|e| incoming_events.push(e)).unwrap();
+    global.process(
+        &mut incoming_events,
+        |e| tcp.push_event(e),
+        |e| udp_events.push(e),
+    );
+    // As if the passwords were checked within the tick
+    while global.accounts.pending_logins() > 0 {
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        global.process(&mut vec![], |e| tcp.push_event(e), |e| udp_events.push(e));
+    }
+    global.update(0.01, |e| tcp.push_event(e), |e| udp_events.push(e));
+    for event in udp_events.drain(..) {
+        global.route_udp_event(event, |e| tcp.push_event(e), |e| udp.push_event(e));
+    }
+}
+
+#[test]
+fn test_loopback() {
+    use cark_client::{
+        client::Client,
+        communication::{Communication, Route},
+        Input,
+    };
+    use cark_common::transport::{Impairment, Loopback};
+    use std::time::Duration;
+
+    let impairment = Impairment {
+        latency: Duration::from_millis(40),
+        jitter: Duration::from_millis(20),
+        loss: 0.1,
+        duplication: 0.05,
+        reordering: 0.05,
+    };
+    let loopback = Loopback::new(impairment.clone(), 0);
+    let mut tcp = tcp::Tcp::with_transport(&loopback, "0.0.0.0:8080").unwrap();
+    let mut udp = udp::Udp::with_transport(&loopback, "0.0.0.0:8081").unwrap();
+    let tiles = tiles::parse_tiles(include_str!("../assets/tiles.toml"));
+    let mut global = Global::new(tiles, 16);
+    let connect = |name: &str| {
+        let communication =
+            Communication::with_transport(&loopback, "127.0.0.1:8080", "127.0.0.1:8081", None)
+                .unwrap();
+        Client::new(
+            communication,
+            name.to_string(),
+            String::new(),
+            Default::default(),
+        )
+    };
+    let mut input = Input::new();
+    input.dt = 0.01;
+
+    // Joins and moves to UDP despite the loss
+    let mut alice = connect("alice");
+    for _ in 0..100 {
+        tick(&mut global, &mut tcp, &mut udp);
+        alice.process(&input);
+        loopback.advance(Duration::from_millis(10));
+    }
+    let alice_id = alice.game.player_id;
+    assert!(alice.game.player_character().is_some());
+    assert_eq!(alice.communication.transport(), Route::Udp);
+    assert_eq!(udp.connections().len(), 1);
+
+    // The positions reach the server over the lossy UDP
+    let position = |global: &Global, id| {
+        global
+            .characters
+            .iter()
+            .find(|c| c.id == id)
+            .unwrap()
+            .position
+    };
+    let start = position(&global, alice_id);
+    input.key_down[3] = true;
+    for i in 0..100 {
+        tick(&mut global, &mut tcp, &mut udp);
+        alice.process(&input);
+        input.reset();
+        if i == 50 {
+            input.key_up[3] = true;
+        }
+        loopback.advance(Duration::from_millis(10));
+    }
+    let client_position = alice.game.player_character().unwrap().position;
+    let server_position = position(&global, alice_id);
+    assert_ne!(server_position, start);
+    assert!((client_position[0] - server_position[0]).abs() < 0.5);
+    assert!((client_position[1] - server_position[1]).abs() < 0.5);
+    assert_eq!(udp.drops().total(), 0);
+
+    // With UDP blocked the next player falls back to TCP once Init times out
+    loopback.set_impairment(Impairment {
+        loss: 1.0,
+        ..impairment
+    });
+    let mut bob = connect("bob");
+    for _ in 0..600 {
+        tick(&mut global, &mut tcp, &mut udp);
+        alice.process(&input);
+        bob.process(&input);
+        loopback.advance(Duration::from_millis(10));
+    }
+    let bob_id = bob.game.player_id;
+    assert!(bob.game.player_character().is_some());
+    assert_eq!(bob.communication.transport(), Route::Tcp);
+    assert!(global.udp_fallback.contains(&bob_id));
+    assert!(!global.udp_fallback.contains(&alice_id));
+    // And still sees the others move, over TCP
+    assert!(bob.game.characters.iter().any(|c| c.id() == alice_id));
+}
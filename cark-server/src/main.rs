@@ -1,12 +1,27 @@
-use cark_server::{tcp::Tcp, udp::Udp};
+use cark_server::{metrics, tcp::Tcp, udp::Udp};
 
 fn main() -> std::io::Result<()> {
-    env_logger::init();
+    env_logger::Builder::new()
+        .filter_level(log::LevelFilter::Trace)
+        .parse_default_env()
+        .init();
+    // Everything passes the logger itself so that the admin console can change the level
+    log::set_max_level(match std::env::var("RUST_LOG") {
+        Ok(_) => log::LevelFilter::Trace,
+        Err(_) => log::LevelFilter::Error,
+    });
 
     let addr = std::env::var("ADDR").unwrap_or("0.0.0.0:8080".to_string());
     let udp_addr = std::env::var("UDP_ADDR").unwrap_or("0.0.0.0:8081".to_string());
+    let chunk_size = std::env::var("CHUNK_SIZE")
+        .map(|s| s.parse().expect("Invalid CHUNK_SIZE"))
+        .unwrap_or(cark_common::field::DEFAULT_CHUNK_SIZE);
+    assert!(chunk_size >= 3, "CHUNK_SIZE must be at least 3");
+
+    let metrics_addr = std::env::var("METRICS_ADDR").unwrap_or("127.0.0.1:8082".to_string());
 
     let mut tcp = Tcp::new(&addr)?;
+    tcp.set_access(cark_server::access::load_access());
     let mut udp = Udp::new(&udp_addr)?;
 
     log::info!(
@@ -15,22 +30,92 @@ fn main() -> std::io::Result<()> {
         udp.local_addr()?
     );
 
-    let mut global = cark_server::Global::new();
+    metrics::registry().describe_defaults();
+    metrics::serve(&metrics_addr)?;
+
+    let mut global = cark_server::Global::new(cark_server::tiles::load_tiles(), chunk_size);
+    global.open_world(cark_server::world::world_path());
+    global.set_accounts(cark_server::accounts::load_accounts());
+    global.set_spawns(cark_server::spawn::load_spawns());
+    global.set_items(cark_server::items::load_items());
+    global.set_spawn_rules(cark_server::npc::load_spawn_rules());
     let mut incoming_events = vec![];
+    let mut udp_events = vec![];
     let mut count = 0;
+    let mut last = std::time::Instant::now();
+    let admin = cark_server::admin::spawn_stdin();
 
     loop {
-        udp.process(|e| incoming_events.push(e)).or_else(map_err)?;
+        let tick = std::time::Instant::now();
+        udp.process(
+            |token| global.udp_session(token),
+            |e| incoming_events.push(e),
+        )
+        .or_else(map_err)?;
         tcp.process(|e| incoming_events.push(e))?;
+        metrics::set(
+            "cark_queue_depth",
+            &[("queue", "incoming")],
+            incoming_events.len() as f64,
+        );
 
         global.process(
             &mut incoming_events,
             |e| tcp.push_event(e),
-            |e| udp.push_event(e),
+            |e| udp_events.push(e),
+        );
+
+        let now = std::time::Instant::now();
+        global.update(
+            now.duration_since(last).as_secs_f32(),
+            |e| tcp.push_event(e),
+            |e| udp_events.push(e),
+        );
+        last = now;
+        for event in udp_events.drain(..) {
+            global.route_udp_event(event, |e| tcp.push_event(e), |e| udp.push_event(e));
+        }
+        metrics::set(
+            "cark_queue_depth",
+            &[("queue", "tcp_outgoing")],
+            tcp.pending_events() as f64,
+        );
+        metrics::set(
+            "cark_queue_depth",
+            &[("queue", "udp_outgoing")],
+            udp.pending_events() as f64,
+        );
+
+        while let Ok(line) = admin.try_recv() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match cark_server::admin::AdminCommand::parse(&line).and_then(|command| {
+                cark_server::admin::execute(command, &mut global, &mut tcp, &udp)
+            }) {
+                Ok(result) => println!("{}", result),
+                Err(e) => println!("Error: {}", e),
+            }
+        }
+
+        if count % 100 == 0 {
+            metrics::set(
+                "cark_connections",
+                &[("transport", "tcp")],
+                tcp.connections().len() as f64,
+            );
+            udp.record_metrics();
+            global.record_metrics();
+        }
+        metrics::observe(
+            "cark_tick_duration_seconds",
+            &[],
+            tick.elapsed().as_secs_f64(),
         );
 
         count = (count + 1) % 1000;
         if count == 0 {
+            global.save_world();
             log::info!("Connections: {}", tcp.connections().len());
             udp.log_stat();
         }
@@ -0,0 +1,353 @@
+// Counters, gauges and histograms of the server, served over HTTP in the Prometheus text format.
+// The registry is global so that the transports and `Global` can record without passing it around.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    sync::{Mutex, MutexGuard},
+    time::Duration,
+};
+
+// Requests are served one at a time, so a slow or silent client must not hold the thread
+const TIMEOUT: Duration = Duration::from_secs(5);
+// Longest request line read, the rest of the request is ignored
+const MAX_REQUEST_LINE: u64 = 1024;
+
+// Upper bounds of the buckets of the tick duration, in seconds
+const TICK_BUCKETS: [f64; 10] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0,
+];
+
+static REGISTRY: Mutex<Metrics> = Mutex::new(Metrics::new());
+
+pub fn registry() -> MutexGuard<'static, Metrics> {
+    REGISTRY.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+pub fn inc(name: &'static str, labels: &[(&'static str, &str)], by: u64) {
+    registry().inc(name, labels, by);
+}
+
+pub fn set(name: &'static str, labels: &[(&'static str, &str)], value: f64) {
+    registry().set(name, labels, value);
+}
+
+pub fn observe(name: &'static str, labels: &[(&'static str, &str)], value: f64) {
+    registry().observe(name, labels, value);
+}
+
+// Serve `/metrics` at the address on a thread of its own
+pub fn serve(addr: &str) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    log::info!("Serving metrics: addr={:?}", listener.local_addr()?);
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else {
+                continue;
+            };
+            if stream.set_read_timeout(Some(TIMEOUT)).is_err()
+                || stream.set_write_timeout(Some(TIMEOUT)).is_err()
+            {
+                continue;
+            }
+            let mut request_line = String::new();
+            if BufReader::new((&stream).take(MAX_REQUEST_LINE))
+                .read_line(&mut request_line)
+                .is_err()
+            {
+                continue;
+            }
+            let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+            let response = if path == "/metrics" {
+                let body = registry().render();
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+            } else {
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_string()
+            };
+            let _ = stream.write_all(response.as_bytes());
+        }
+    });
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+impl Kind {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Counter => "counter",
+            Self::Gauge => "gauge",
+            Self::Histogram => "histogram",
+        }
+    }
+}
+
+type Labels = Vec<(&'static str, String)>;
+
+#[derive(Debug, Clone)]
+struct Histogram {
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+struct Family {
+    kind: Kind,
+    help: &'static str,
+    series: BTreeMap<Labels, Series>,
+}
+
+enum Series {
+    Value(f64),
+    Histogram(Histogram),
+}
+
+pub struct Metrics {
+    families: BTreeMap<&'static str, Family>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    // Const for the global registry
+    pub const fn new() -> Self {
+        Self {
+            families: BTreeMap::new(),
+        }
+    }
+
+    // Register the help text of a metric, shown even before it is recorded
+    fn describe(&mut self, name: &'static str, kind: Kind, help: &'static str) {
+        self.families.entry(name).or_insert(Family {
+            kind,
+            help,
+            series: BTreeMap::new(),
+        });
+    }
+
+    pub fn describe_defaults(&mut self) {
+        use Kind::*;
+        for (name, kind, help) in [
+            ("cark_connections", Gauge, "Open connections by transport"),
+            (
+                "cark_connections_accepted_total",
+                Counter,
+                "TCP connections accepted",
+            ),
+            (
+                "cark_connections_refused_total",
+                Counter,
+                "TCP connections refused",
+            ),
+            ("cark_joins_total", Counter, "Players joined"),
+            ("cark_join_rejections_total", Counter, "Joins rejected"),
+            ("cark_leaves_total", Counter, "Players left"),
+            (
+                "cark_messages_received_total",
+                Counter,
+                "Messages received by transport and variant",
+            ),
+            (
+                "cark_messages_sent_total",
+                Counter,
+                "Messages sent by transport and variant",
+            ),
+            (
+                "cark_bytes_received_total",
+                Counter,
+                "Bytes received by transport",
+            ),
+            ("cark_bytes_sent_total", Counter, "Bytes sent by transport"),
+            (
+                "cark_udp_loss_ratio",
+                Gauge,
+                "Ratio of the UDP packets from the client that were lost",
+            ),
+            (
+                "cark_udp_dropped_total",
+                Counter,
+                "UDP datagrams dropped on receive by reason",
+            ),
+            (
+                "cark_udp_migrations_total",
+                Counter,
+                "UDP sessions that moved to another client address",
+            ),
+            ("cark_chunks", Gauge, "Chunks in the field"),
+            ("cark_chunks_generated_total", Counter, "Chunks generated"),
+            (
+                "cark_tick_duration_seconds",
+                Histogram,
+                "Time spent in a loop of the server, without sleeping",
+            ),
+            ("cark_queue_depth", Gauge, "Events waiting in the queues"),
+        ] {
+            self.describe(name, kind, help);
+        }
+    }
+
+    fn series(
+        &mut self,
+        name: &'static str,
+        kind: Kind,
+        labels: &[(&'static str, &str)],
+    ) -> &mut Series {
+        let family = self.families.entry(name).or_insert(Family {
+            kind,
+            help: "",
+            series: BTreeMap::new(),
+        });
+        debug_assert_eq!(family.kind, kind, "{} recorded as another kind", name);
+        let labels = labels.iter().map(|(k, v)| (*k, v.to_string())).collect();
+        family.series.entry(labels).or_insert_with(|| match kind {
+            Kind::Histogram => Series::Histogram(Histogram {
+                buckets: vec![0; TICK_BUCKETS.len()],
+                sum: 0.0,
+                count: 0,
+            }),
+            _ => Series::Value(0.0),
+        })
+    }
+
+    pub fn inc(&mut self, name: &'static str, labels: &[(&'static str, &str)], by: u64) {
+        if let Series::Value(value) = self.series(name, Kind::Counter, labels) {
+            *value += by as f64;
+        }
+    }
+
+    pub fn set(&mut self, name: &'static str, labels: &[(&'static str, &str)], value: f64) {
+        if let Series::Value(v) = self.series(name, Kind::Gauge, labels) {
+            *v = value;
+        }
+    }
+
+    // Forget all series of a gauge, for labels that may go away such as connections
+    pub fn clear(&mut self, name: &'static str) {
+        if let Some(family) = self.families.get_mut(name) {
+            family.series.clear();
+        }
+    }
+
+    pub fn observe(&mut self, name: &'static str, labels: &[(&'static str, &str)], value: f64) {
+        if let Series::Histogram(histogram) = self.series(name, Kind::Histogram, labels) {
+            for (bucket, bound) in histogram.buckets.iter_mut().zip(TICK_BUCKETS) {
+                if value <= bound {
+                    *bucket += 1;
+                }
+            }
+            histogram.sum += value;
+            histogram.count += 1;
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for (name, family) in &self.families {
+            if !family.help.is_empty() {
+                writeln!(out, "# HELP {} {}", name, family.help).unwrap();
+            }
+            writeln!(out, "# TYPE {} {}", name, family.kind.name()).unwrap();
+            for (labels, series) in &family.series {
+                match series {
+                    Series::Value(value) => {
+                        writeln!(out, "{}{} {}", name, format_labels(labels, None), value).unwrap();
+                    }
+                    Series::Histogram(histogram) => {
+                        for (count, bound) in histogram.buckets.iter().zip(TICK_BUCKETS) {
+                            let le = Some(bound.to_string());
+                            writeln!(
+                                out,
+                                "{}_bucket{} {}",
+                                name,
+                                format_labels(labels, le),
+                                count
+                            )
+                            .unwrap();
+                        }
+                        let le = Some("+Inf".to_string());
+                        writeln!(
+                            out,
+                            "{}_bucket{} {}",
+                            name,
+                            format_labels(labels, le),
+                            histogram.count
+                        )
+                        .unwrap();
+                        let labels = format_labels(labels, None);
+                        writeln!(out, "{}_sum{} {}", name, labels, histogram.sum).unwrap();
+                        writeln!(out, "{}_count{} {}", name, labels, histogram.count).unwrap();
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+fn format_labels(labels: &Labels, le: Option<String>) -> String {
+    let mut pairs: Vec<_> = labels
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+        .collect();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[test]
+fn test() {
+    let mut metrics = Metrics::new();
+    metrics.describe_defaults();
+    metrics.inc("cark_joins_total", &[], 1);
+    metrics.inc("cark_joins_total", &[], 2);
+    metrics.inc(
+        "cark_messages_received_total",
+        &[("transport", "tcp"), ("message", "Join")],
+        1,
+    );
+    metrics.set("cark_udp_loss_ratio", &[("connection", "a\"b")], 0.25);
+    metrics.observe("cark_tick_duration_seconds", &[], 0.003);
+    metrics.observe("cark_tick_duration_seconds", &[], 2.0);
+
+    let text = metrics.render();
+    assert!(text.contains("# HELP cark_joins_total Players joined\n# TYPE cark_joins_total counter\ncark_joins_total 3\n"));
+    assert!(text.contains("cark_messages_received_total{transport=\"tcp\",message=\"Join\"} 1\n"));
+    assert!(text.contains("cark_udp_loss_ratio{connection=\"a\\\"b\"} 0.25\n"));
+    assert!(text.contains("cark_tick_duration_seconds_bucket{le=\"0.0025\"} 0\n"));
+    assert!(text.contains("cark_tick_duration_seconds_bucket{le=\"0.005\"} 1\n"));
+    assert!(text.contains("cark_tick_duration_seconds_bucket{le=\"+Inf\"} 2\n"));
+    assert!(text.contains("cark_tick_duration_seconds_count 2\n"));
+    // Described but never recorded
+    assert!(text.contains("# TYPE cark_chunks gauge\n"));
+
+    metrics.clear("cark_udp_loss_ratio");
+    assert!(!metrics.render().contains("cark_udp_loss_ratio{"));
+}
@@ -0,0 +1,336 @@
+use std::collections::HashMap;
+
+use cark_common::{
+    field::{ChunkId, Field},
+    physics::{self, Body, MoveInput, PhysicsParams},
+    tile::TileRegistry,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+// NPC ids start here so that they never collide with connection ids
+pub const NPC_ID_BASE: u64 = 1 << 48;
+
+const DEFAULT_SPAWN_RULES: &str = include_str!("../assets/npcs.toml");
+
+// Load the spawn rules from the file at `NPCS`, or the bundled ones
+pub fn load_spawn_rules() -> Vec<SpawnRule> {
+    let rules = match std::env::var("NPCS") {
+        Ok(path) => std::fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("Failed to read NPCs: path = {}, {}", path, e)),
+        Err(_) => DEFAULT_SPAWN_RULES.to_string(),
+    };
+    parse_spawn_rules(&rules)
+}
+
+pub fn parse_spawn_rules(rules: &str) -> Vec<SpawnRule> {
+    #[derive(serde::Deserialize)]
+    struct File {
+        #[serde(default)]
+        rules: Vec<SpawnRule>,
+    }
+    toml::from_str::<File>(rules).unwrap().rules
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct SpawnRule {
+    pub name: String,
+    #[serde(default)]
+    pub ai: AiKind,
+    // Chunks to populate, all chunks if not given
+    #[serde(default)]
+    pub chunks: Option<Vec<u32>>,
+    #[serde(default = "default_per_chunk")]
+    pub per_chunk: usize,
+    // Seconds between spawns in a chunk
+    #[serde(default = "default_interval")]
+    pub interval: f32,
+}
+
+fn default_per_chunk() -> usize {
+    1
+}
+
+fn default_interval() -> f32 {
+    10.0
+}
+
+impl SpawnRule {
+    fn applies_to(&self, id: ChunkId) -> bool {
+        self.chunks
+            .as_ref()
+            .is_none_or(|chunks| chunks.contains(&id.get()))
+    }
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AiKind {
+    #[default]
+    Wander,
+    Still,
+}
+
+impl AiKind {
+    pub fn build(&self) -> Box<dyn Ai> {
+        match self {
+            Self::Wander => Box::new(Wander::new()),
+            Self::Still => Box::new(Still),
+        }
+    }
+}
+
+pub struct AiContext<'a> {
+    pub body: &'a Body,
+    pub field: &'a Field,
+    pub tiles: &'a TileRegistry,
+    // Everyone else, players and NPCs
+    pub others: &'a [Body],
+    pub dt: f32,
+}
+
+// Decides how an NPC moves, called once per physics step
+pub trait Ai: Send {
+    fn update(&mut self, context: &AiContext) -> MoveInput;
+}
+
+pub struct Wander {
+    rng: StdRng,
+    input: MoveInput,
+    remaining: f32,
+}
+
+impl Wander {
+    pub fn new() -> Self {
+        Self {
+            rng: StdRng::from_entropy(),
+            input: MoveInput::default(),
+            remaining: 0.0,
+        }
+    }
+}
+
+impl Default for Wander {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Ai for Wander {
+    fn update(&mut self, context: &AiContext) -> MoveInput {
+        self.remaining -= context.dt;
+        if self.remaining <= 0.0 {
+            self.remaining = self.rng.gen_range(0.5..3.0);
+            self.input = if self.rng.gen_bool(0.3) {
+                MoveInput::default()
+            } else {
+                let angle = self.rng.gen_range(0.0..std::f32::consts::TAU);
+                MoveInput {
+                    direction: [angle.cos(), angle.sin()],
+                }
+            };
+        }
+        self.input
+    }
+}
+
+pub struct Still;
+
+impl Ai for Still {
+    fn update(&mut self, _context: &AiContext) -> MoveInput {
+        MoveInput::default()
+    }
+}
+
+pub struct Npc {
+    pub id: u64,
+    pub name: String,
+    pub body: Body,
+    // Index of the spawn rule, None for NPCs inserted directly
+    pub rule: Option<usize>,
+    home: ChunkId,
+    ai: Box<dyn Ai>,
+}
+
+pub struct Npcs {
+    rules: Vec<SpawnRule>,
+    npcs: Vec<Npc>,
+    next_id: u64,
+    // (rule, chunk) => seconds until the next spawn
+    cooldowns: HashMap<(usize, ChunkId), f32>,
+    rng: StdRng,
+}
+
+impl Npcs {
+    pub fn new(rules: Vec<SpawnRule>) -> Self {
+        Self {
+            rules,
+            npcs: vec![],
+            next_id: NPC_ID_BASE,
+            cooldowns: HashMap::new(),
+            rng: StdRng::from_entropy(),
+        }
+    }
+
+    pub fn npcs(&self) -> &[Npc] {
+        &self.npcs
+    }
+
+    pub fn get_mut(&mut self, id: u64) -> Option<&mut Npc> {
+        self.npcs.iter_mut().find(|npc| npc.id == id)
+    }
+
+    pub fn set_rules(&mut self, rules: Vec<SpawnRule>) {
+        self.rules = rules;
+        self.cooldowns.clear();
+    }
+
+    // Add an NPC driven by the given AI, returns its id
+    pub fn insert(&mut self, name: String, body: Body, ai: Box<dyn Ai>) -> u64 {
+        self.push(name, body, None, ai)
+    }
+
+    pub fn remove(&mut self, id: u64) -> Option<Npc> {
+        let index = self.npcs.iter().position(|npc| npc.id == id)?;
+        Some(self.npcs.remove(index))
+    }
+
+    fn push(&mut self, name: String, body: Body, rule: Option<usize>, ai: Box<dyn Ai>) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.npcs.push(Npc {
+            id,
+            name,
+            home: body.chunk_id,
+            body,
+            rule,
+            ai,
+        });
+        id
+    }
+
+    // Spawn the NPCs due by the rules, returns the ids of the new ones
+    pub fn spawn(&mut self, field: &Field, tiles: &TileRegistry, dt: f32) -> Vec<u64> {
+        let mut spawned = vec![];
+        let mut chunk_ids: Vec<_> = field.chunks.keys().copied().collect();
+        chunk_ids.sort();
+        for rule_index in 0..self.rules.len() {
+            let rule = self.rules[rule_index].clone();
+            for &chunk_id in chunk_ids.iter().filter(|id| rule.applies_to(**id)) {
+                let count = self
+                    .npcs
+                    .iter()
+                    .filter(|npc| npc.rule == Some(rule_index) && npc.home == chunk_id)
+                    .count();
+                let cooldown = self.cooldowns.entry((rule_index, chunk_id)).or_insert(0.0);
+                *cooldown -= dt;
+                if count >= rule.per_chunk || *cooldown > 0.0 {
+                    continue;
+                }
+                *cooldown = rule.interval;
+
+                let Some(position) = free_position(field, tiles, chunk_id, &mut self.rng) else {
+                    continue;
+                };
+                let (name, ai) = (rule.name.clone(), rule.ai.build());
+                let body = Body::new(chunk_id, position, [0.0, 0.0]);
+                spawned.push(self.push(name, body, Some(rule_index), ai));
+            }
+        }
+        spawned
+    }
+
+    // Move all NPCs by one physics step. `players` push the NPCs but aren't moved.
+    pub fn step(
+        &mut self,
+        params: &PhysicsParams,
+        field: &Field,
+        tiles: &TileRegistry,
+        players: &[Body],
+        dt: f32,
+    ) {
+        let bodies: Vec<_> = self.npcs.iter().map(|npc| npc.body).collect();
+        for (i, npc) in self.npcs.iter_mut().enumerate() {
+            let others: Vec<_> = players
+                .iter()
+                .chain(bodies[..i].iter())
+                .chain(bodies[i + 1..].iter())
+                .copied()
+                .collect();
+            let input = npc.ai.update(&AiContext {
+                body: &npc.body,
+                field,
+                tiles,
+                others: &others,
+                dt,
+            });
+            npc.body = physics::step(params, &npc.body, &input, field, tiles, &others, dt);
+        }
+    }
+}
+
+// The center of a random tile with no solid tile around it
+pub(crate) fn free_position(
+    field: &Field,
+    tiles: &TileRegistry,
+    chunk_id: ChunkId,
+    rng: &mut impl Rng,
+) -> Option<[f32; 2]> {
+    let size = field.chunk_size() as i32;
+    for _ in 0..16 {
+        let x = rng.gen_range(0..size);
+        let y = rng.gen_range(0..size);
+        if !field
+            .solid_view(chunk_id, [x - 1, y - 1, x + 2, y + 2], tiles)
+            .iter()
+            .any(|solid| *solid)
+        {
+            return Some([x as f32 + 0.5, y as f32 + 0.5]);
+        }
+    }
+    None
+}
+
+#[test]
+fn test() {
+    let rules = parse_spawn_rules(DEFAULT_SPAWN_RULES);
+    assert_eq!(rules[0].ai, AiKind::Wander);
+
+    let tiles = crate::tiles::parse_tiles(include_str!("../assets/tiles.toml"));
+    let field = Field::new();
+    let mut npcs = Npcs::new(vec![SpawnRule {
+        name: "guard".to_string(),
+        ai: AiKind::Wander,
+        chunks: Some(vec![1]),
+        per_chunk: 2,
+        interval: 1.0,
+    }]);
+
+    // One at a time, up to the limit
+    assert_eq!(npcs.spawn(&field, &tiles, 0.5), vec![NPC_ID_BASE]);
+    assert!(npcs.spawn(&field, &tiles, 0.5).is_empty());
+    assert_eq!(npcs.spawn(&field, &tiles, 0.5), vec![NPC_ID_BASE + 1]);
+    assert!(npcs.spawn(&field, &tiles, 10.0).is_empty());
+
+    let position = free_position(&field, &tiles, ChunkId::MIN, &mut StdRng::seed_from_u64(0));
+    let id = npcs.insert(
+        "statue".to_string(),
+        Body::new(ChunkId::MIN, position.unwrap(), [0.0, 0.0]),
+        Box::new(Still),
+    );
+    let params = PhysicsParams::default();
+    for _ in 0..120 {
+        npcs.step(&params, &field, &tiles, &[], params.timestep);
+    }
+    for npc in npcs.npcs() {
+        let [x, y] = npc.body.position;
+        let tile = [x.floor() as i32, y.floor() as i32];
+        let solid = field.solid_view(
+            npc.body.chunk_id,
+            [tile[0], tile[1], tile[0] + 1, tile[1] + 1],
+            &tiles,
+        );
+        assert!(!solid[0], "{} is in a wall", npc.name);
+    }
+    assert!(npcs.remove(id).is_some());
+    assert_eq!(npcs.npcs().len(), 2);
+}
@@ -0,0 +1,178 @@
+// Where players start when they join
+
+use cark_common::{
+    field::{ChunkId, Field},
+    model::Character,
+    path::nearest_free_tile,
+    physics,
+    tile::TileRegistry,
+};
+use rand::Rng;
+
+const DEFAULT_SPAWNS: &str = include_str!("../assets/spawns.toml");
+
+// Characters within this distance of a spawn point count as crowding it
+const CROWD_RADIUS: f32 = 8.0;
+// Tiles searched for a safe place around the spawn point
+const MAX_SEARCH_NODES: usize = 1024;
+
+// Load the spawn points from the file at `SPAWNS`, or the bundled ones
+pub fn load_spawns() -> SpawnConfig {
+    let spawns = match std::env::var("SPAWNS") {
+        Ok(path) => std::fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("Failed to read spawns: path = {}, {}", path, e)),
+        Err(_) => DEFAULT_SPAWNS.to_string(),
+    };
+    parse_spawns(&spawns)
+}
+
+pub fn parse_spawns(spawns: &str) -> SpawnConfig {
+    toml::from_str(spawns).unwrap()
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct SpawnConfig {
+    #[serde(default)]
+    pub strategy: SpawnStrategy,
+    pub points: Vec<SpawnPoint>,
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct SpawnPoint {
+    pub name: String,
+    pub chunk: u32,
+    pub position: [f32; 2],
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SpawnStrategy {
+    Random,
+    LeastCrowded,
+    // Where the player left, or the least crowded point for new players
+    #[default]
+    LastPosition,
+}
+
+impl Default for SpawnConfig {
+    fn default() -> Self {
+        parse_spawns(DEFAULT_SPAWNS)
+    }
+}
+
+impl SpawnConfig {
+    // Where a player joining starts, before looking for a safe place around it
+    pub fn choose(
+        &self,
+        last: Option<(ChunkId, [f32; 2])>,
+        field: &Field,
+        characters: &[Character],
+        rng: &mut impl Rng,
+    ) -> (ChunkId, [f32; 2]) {
+        let points: Vec<_> = self
+            .points
+            .iter()
+            .filter_map(|p| {
+                let id = ChunkId::new(p.chunk).filter(|id| field.chunk(*id).is_some())?;
+                Some((id, p.position))
+            })
+            .collect();
+        if points.is_empty() {
+            return last.unwrap_or((ChunkId::MIN, [2.5, 2.5]));
+        }
+
+        match self.strategy {
+            SpawnStrategy::Random => points[rng.gen_range(0..points.len())],
+            SpawnStrategy::LastPosition if last.is_some() => last.unwrap(),
+            SpawnStrategy::LeastCrowded | SpawnStrategy::LastPosition => *points
+                .iter()
+                .min_by_key(|point| {
+                    characters
+                        .iter()
+                        .filter(|c| {
+                            physics::touching(
+                                field,
+                                **point,
+                                CROWD_RADIUS,
+                                (c.chunk_id, c.position),
+                                0.0,
+                            )
+                        })
+                        .count()
+                })
+                .unwrap(),
+        }
+    }
+}
+
+// The nearest tile center to `at` that is neither solid nor taken by another character.
+// Falls back to `at` itself if there is no such tile around.
+pub fn safe_position(
+    field: &Field,
+    tiles: &TileRegistry,
+    at: (ChunkId, [f32; 2]),
+    characters: &[Character],
+    radius: f32,
+) -> (ChunkId, [f32; 2]) {
+    nearest_free_tile(field, tiles, at, MAX_SEARCH_NODES, |position| {
+        !characters
+            .iter()
+            .any(|c| physics::touching(field, position, radius, (c.chunk_id, c.position), radius))
+    })
+    .unwrap_or(at)
+}
+
+#[test]
+fn test() {
+    use rand::SeedableRng;
+
+    let tiles = crate::tiles::parse_tiles(include_str!("../assets/tiles.toml"));
+    let mut field = Field::new();
+    let id = ChunkId::MIN;
+    for chunk in field.chunks.values_mut() {
+        chunk.walls.fill(cark_common::tile::VOID);
+    }
+    let character = |position| Character {
+        id: 1,
+        name: "alice".to_string(),
+        chunk_id: id,
+        position,
+        npc: false,
+    };
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+
+    let mut config = SpawnConfig::default();
+    let crowd = [character([1.0, 1.0])];
+    assert_eq!(
+        config.choose(None, &field, &crowd, &mut rng),
+        (id, [8.5, 8.5])
+    );
+    let last = (id, [5.0, 5.0]);
+    assert_eq!(config.choose(Some(last), &field, &crowd, &mut rng), last);
+    config.strategy = SpawnStrategy::LeastCrowded;
+    assert_eq!(
+        config.choose(Some(last), &field, &crowd, &mut rng),
+        (id, [8.5, 8.5])
+    );
+
+    // Out of walls and away from other characters
+    field.set_tile(
+        id,
+        cark_common::field::Layer::Walls,
+        [2, 2],
+        cark_common::tile::WALL,
+    );
+    let (_, position) = safe_position(&field, &tiles, (id, [2.5, 2.5]), &[], 0.5);
+    assert_ne!(position, [2.5, 2.5]);
+    let (_, position) = safe_position(&field, &tiles, (id, [8.5, 8.5]), &crowd[..0], 0.5);
+    assert_eq!(position, [8.5, 8.5]);
+    let (_, position) = safe_position(
+        &field,
+        &tiles,
+        (id, [8.5, 8.5]),
+        &[character([8.5, 8.5])],
+        0.5,
+    );
+    let d = (position[0] - 8.5).hypot(position[1] - 8.5);
+    assert!((1.0..1.5).contains(&d));
+}
@@ -1,21 +1,33 @@
-use std::net::TcpListener;
+use cark_common::{
+    model::{ClientMessage, ServerMessage},
+    transport::{self, Listener, Net, Stream, Transport},
+};
 
-use crate::{connection::Connection, IncomingEvent, OutgoingEvent};
+use crate::{
+    access::{Access, Rule},
+    connection::Connection,
+    metrics, IncomingEvent, OutgoingEvent,
+};
 
 pub struct Tcp {
-    listener: TcpListener,
+    listener: Box<dyn Listener>,
     connections: Vec<super::connection::Connection>,
     outgoing_events: Vec<OutgoingEvent>,
+    access: Access,
 }
 
 impl Tcp {
     pub fn new(addr: &str) -> std::io::Result<Self> {
-        let listener = TcpListener::bind(addr)?;
-        listener.set_nonblocking(true)?;
+        Self::with_transport(&Net, addr)
+    }
+
+    pub fn with_transport(transport: &impl Transport, addr: &str) -> std::io::Result<Self> {
+        let listener = transport.listen(transport::resolve(addr)?)?;
         Ok(Self {
             listener,
             connections: vec![],
             outgoing_events: vec![],
+            access: Access::new(),
         })
     }
 
@@ -23,6 +35,14 @@ impl Tcp {
         self.listener.local_addr()
     }
 
+    pub fn set_access(&mut self, access: Access) {
+        self.access = access;
+    }
+
+    pub fn access(&self) -> &Access {
+        &self.access
+    }
+
     pub fn push_event(&mut self, event: OutgoingEvent) {
         self.outgoing_events.push(event);
     }
@@ -32,39 +52,75 @@ impl Tcp {
         mut push_incoming_event: impl FnMut(IncomingEvent),
     ) -> std::io::Result<()> {
         // Accept new connections
-        for stream in self.listener.incoming() {
-            let stream = match stream {
-                Ok(stream) => stream,
+        loop {
+            let (stream, addr) = match self.listener.accept() {
+                Ok(accepted) => accepted,
                 Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
                 Err(e) => return Err(e),
             };
 
-            if self
+            let from_ip = self
                 .connections
                 .iter()
-                .find(|c| c.stream.peer_addr().unwrap() == stream.peer_addr().unwrap())
-                .is_some()
+                .filter(|c| c.ip() == Some(addr.ip()))
+                .count();
+            if let Err(reason) =
+                self.access
+                    .admit_connection(addr.ip(), self.connections.len(), from_ip)
             {
-                log::warn!(
-                    "Same peer address already connected: addr={}",
-                    stream.peer_addr().unwrap()
-                );
+                log::info!("Connection refused: addr = {}, {}", addr, reason);
+                metrics::inc("cark_connections_refused_total", &[], 1);
+                refuse(stream, reason);
+                continue;
             }
 
-            self.connections.push(Connection::new(stream)?);
+            self.connections.push(Connection::new(stream));
+            metrics::inc("cark_connections_accepted_total", &[], 1);
         }
 
         // Process existing connections
+        let now = std::time::Instant::now();
         for connection in &mut self.connections {
+            // Joins are checked here so that the game never sees the refused ones
+            let ip = connection.ip();
+            let access = &mut self.access;
+            let mut joined = None;
+            let mut refused = None;
             connection
-                .process(&mut push_incoming_event, &self.outgoing_events)
+                .process(
+                    |event| {
+                        if let (ClientMessage::Join(join), Some(ip)) = (&event.message, ip) {
+                            if let Err(reason) = access.admit_join(ip, &join.name, now) {
+                                refused = Some((join.name.clone(), reason));
+                                return;
+                            }
+                            joined = Some(join.name.clone());
+                        }
+                        push_incoming_event(event);
+                    },
+                    &self.outgoing_events,
+                )
                 .or_else(map_err)?;
+            if let Some(name) = joined {
+                connection.name = Some(name);
+            }
+            if let Some((name, reason)) = refused {
+                log::info!(
+                    "Join refused: id = {}, name = {}, {}",
+                    connection.id(),
+                    name,
+                    reason
+                );
+                metrics::inc("cark_join_rejections_total", &[], 1);
+                let _ = connection.write(&ServerMessage::JoinRejected { reason });
+                connection.close();
+            }
 
             if connection.is_closed() {
                 push_incoming_event(IncomingEvent {
                     connection_id: connection.id(),
                     sequence: 0,
-                    message: cark_common::model::ClientMessage::Leave,
+                    message: ClientMessage::Leave,
                 });
             }
         }
@@ -80,6 +136,60 @@ impl Tcp {
     pub fn connections(&self) -> &[Connection] {
         &self.connections
     }
+
+    // Events waiting to be sent on the next process
+    pub fn pending_events(&self) -> usize {
+        self.outgoing_events.len()
+    }
+
+    // Tell the client why and disconnect it
+    pub fn kick(&mut self, id: u64, reason: &str) -> bool {
+        let Some(connection) = self.connections.iter_mut().find(|c| c.id() == id) else {
+            return false;
+        };
+        let _ = connection.write(&ServerMessage::Notice {
+            text: reason.to_string(),
+        });
+        connection.close();
+        true
+    }
+
+    // Refuse new connections or joins matching the rule and kick the ones connected
+    pub fn ban(&mut self, rule: Rule) -> usize {
+        let ids: Vec<_> = self
+            .connections
+            .iter()
+            .filter(|c| match &rule {
+                Rule::Net(net) => c.ip().is_some_and(|ip| net.contains(ip)),
+                Rule::Account(name) => c.name.as_ref() == Some(name),
+            })
+            .map(|c| c.id())
+            .collect();
+        self.access.bans.add(rule);
+        for id in &ids {
+            self.kick(*id, "Banned");
+        }
+        ids.len()
+    }
+
+    pub fn unban(&mut self, rule: &Rule) -> bool {
+        self.access.bans.remove(rule)
+    }
+
+    // Joins already admitted are kept even if they are no longer allowed
+    pub fn allow(&mut self, rule: Rule) -> bool {
+        self.access.allowlist.add(rule)
+    }
+
+    pub fn disallow(&mut self, rule: &Rule) -> bool {
+        self.access.allowlist.remove(rule)
+    }
+}
+
+// Tell the peer why before any state is allocated for it
+fn refuse(mut stream: Box<dyn Stream>, reason: String) {
+    let _ = cark_common::write(&ServerMessage::JoinRejected { reason }, &mut stream);
+    let _ = stream.shutdown();
 }
 
 fn map_err(e: std::io::Error) -> Result<(), std::io::Error> {
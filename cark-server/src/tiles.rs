@@ -0,0 +1,26 @@
+use cark_common::tile::TileRegistry;
+
+const DEFAULT_TILES: &str = include_str!("../assets/tiles.toml");
+
+// Load the tile registry from the file at `TILES`, or the bundled one
+pub fn load_tiles() -> TileRegistry {
+    let tiles = match std::env::var("TILES") {
+        Ok(path) => std::fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("Failed to read tiles: path = {}, {}", path, e)),
+        Err(_) => DEFAULT_TILES.to_string(),
+    };
+    parse_tiles(&tiles)
+}
+
+pub fn parse_tiles(tiles: &str) -> TileRegistry {
+    toml::from_str(tiles).unwrap()
+}
+
+#[test]
+fn test() {
+    let tiles = parse_tiles(DEFAULT_TILES);
+    assert!(tiles.is_solid(cark_common::tile::WALL));
+    assert!(!tiles.is_solid(cark_common::tile::FLOOR));
+    assert_eq!(tiles.by_name("floor").unwrap().id, cark_common::tile::FLOOR);
+    assert_eq!(tiles.get(200).name, "unknown");
+}
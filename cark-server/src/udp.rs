@@ -1,26 +1,40 @@
-use std::net::{SocketAddr, UdpSocket};
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
 
 use cark_common::{
-    model::{ClientUdpMessage, ServerUdpMessage},
-    udp_stat::{Sequence, SequenceGen, UdpStat},
+    model::{ClientMessage, ClientUdpMessage, ServerMessage, ServerUdpMessage},
+    transport::{self, Datagram, Net, Transport},
+    udp_stat::{
+        read_datagram, write_datagram, DropStat, Sequence, SequenceGen, UdpStat, MAX_DATAGRAM_SIZE,
+    },
 };
 
-use crate::{IncomingEvent, OutgoingEvent};
+use crate::{metrics, IncomingEvent, OutgoingEvent};
+
+// Interval of the pings to measure the round trip time
+const PING_INTERVAL: Duration = Duration::from_secs(2);
 
 pub struct Udp {
-    socket: UdpSocket,
+    socket: Box<dyn Datagram>,
     connections: Vec<Connection>, // TODO: Remove
     outgoing_events: Vec<OutgoingEvent>,
+    drops: DropStat,
 }
 
 impl Udp {
     pub fn new(addr: &str) -> std::io::Result<Self> {
-        let socket = UdpSocket::bind(addr)?;
-        socket.set_nonblocking(true)?;
+        Self::with_transport(&Net, addr)
+    }
+
+    pub fn with_transport(transport: &impl Transport, addr: &str) -> std::io::Result<Self> {
+        let socket = transport.bind(transport::resolve(addr)?)?;
         Ok(Self {
             socket,
             connections: vec![],
             outgoing_events: vec![],
+            drops: DropStat::default(),
         })
     }
 
@@ -32,120 +46,406 @@ impl Udp {
         self.outgoing_events.push(event);
     }
 
-    pub fn process(&mut self, mut handler: impl FnMut(IncomingEvent)) -> std::io::Result<()> {
-        let mut buf = [0; 1024];
+    // `session` gives the player id of a session token, None once the player has left
+    pub fn process(
+        &mut self,
+        session: impl Fn(u64) -> Option<u64>,
+        mut handler: impl FnMut(IncomingEvent),
+    ) -> std::io::Result<()> {
+        // One byte more than the limit to tell the datagrams over it
+        let mut buf = [0; MAX_DATAGRAM_SIZE + 1];
 
         // Receive
         loop {
             match self.socket.recv_from(&mut buf) {
-                Ok((size, addr)) => {
-                    let message = &buf[..size];
-                    log::debug!("Received {:?} from {}", message, addr);
-
-                    let message: ClientUdpMessage = cark_common::read_from_slice(message).unwrap();
-                    log::debug!("Received {:?}", message);
-
-                    match message {
-                        ClientUdpMessage::Init { id } => {
-                            log::info!("Client connected, addr: {}, id: {}", addr, id);
-
-                            self.connections.retain(|c| {
-                                let detected = c.addr == addr;
-                                if detected {
-                                    // TODO
-                                    log::info!("Client reconnected, addr: {}, id: {}", addr, id);
-                                }
-                                !detected
-                            });
-
-                            self.connections.push(Connection::new(id, addr));
-                        }
-                        ClientUdpMessage::Message { sequence, message } => {
-                            let connection = self
-                                .connections
-                                .iter_mut()
-                                .find(|c| c.addr == addr)
-                                .expect("Unknown address");
-
-                            connection.update(sequence);
-
-                            handler(IncomingEvent {
-                                connection_id: connection.id,
-                                sequence,
-                                message,
-                            });
-                        }
-                    }
-                }
+                Ok((size, addr)) => self.receive(&buf[..size], addr, &session, &mut handler),
                 Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
                     break;
                 }
+                // Windows reports the ICMP port unreachable of an earlier send here
+                Err(e) if e.kind() == std::io::ErrorKind::ConnectionReset => {}
                 Err(e) => return Err(e),
             }
         }
 
+        // Forget the sessions of the players left
+        self.connections.retain(|c| {
+            let alive = session(c.token) == Some(c.id);
+            if !alive {
+                log::info!("UDP session closed: id = {}", c.id);
+            }
+            alive
+        });
+
+        // Ping
+        let now = self.socket.now();
+        for connection in &mut self.connections {
+            if let Some(nonce) = connection.ping(now) {
+                self.outgoing_events.push(OutgoingEvent {
+                    connection_id: Some(connection.id),
+                    message: ServerMessage::Ping { nonce },
+                });
+            }
+        }
+
         // Send
         for event in self.outgoing_events.drain(..) {
-            if let Some(id) = event.connection_id {
-                let connection = self
-                    .connections
-                    .iter_mut()
-                    .find(|c| c.id == id)
-                    .expect("Unknown id");
-
+            let name = event.message.name();
+            for connection in &mut self.connections {
+                if event.connection_id.is_some_and(|id| id != connection.id) {
+                    continue;
+                }
                 let message = ServerUdpMessage::Message {
-                    sequence: connection.sequence.next(),
-                    message: event.message,
+                    sequence: connection.sequence.next_sequence(),
+                    message: event.message.clone(),
                 };
-                let buf = cark_common::write_to_slice(&message, &mut buf).unwrap();
-
-                self.socket.send_to(&buf, connection.addr)?;
-            } else {
-                // Broadcast
-                for connection in &mut self.connections {
-                    let message = ServerUdpMessage::Message {
-                        sequence: connection.sequence.next(),
-                        message: event.message.clone(),
-                    };
-                    let buf = cark_common::write_to_slice(&message, &mut buf).unwrap();
-                    self.socket.send_to(&buf, connection.addr)?;
-                }
+                let buf = match write_datagram(&message, &mut buf) {
+                    Ok(buf) => buf,
+                    Err(e) => {
+                        log::warn!("Message not sent: message = {}, {}", name, e);
+                        continue;
+                    }
+                };
+                self.socket.send_to(buf, connection.addr)?;
+                record_sent(name, buf.len());
             }
         }
 
         Ok(())
     }
 
+    // Decode a datagram and pass the message on, dropping the datagram if it's malformed or
+    // its token is unknown. Sessions are keyed by the token, so they follow the client to a new
+    // address when a NAT rebinds it.
+    fn receive(
+        &mut self,
+        data: &[u8],
+        addr: SocketAddr,
+        session: &impl Fn(u64) -> Option<u64>,
+        handler: &mut impl FnMut(IncomingEvent),
+    ) {
+        metrics::inc(
+            "cark_bytes_received_total",
+            &[("transport", "udp")],
+            data.len() as u64,
+        );
+        let message: ClientUdpMessage = match read_datagram(data) {
+            Ok(message) => message,
+            Err(e) => {
+                log::debug!("Datagram dropped: addr = {}, {}", addr, e);
+                self.drop_datagram(e.reason());
+                return;
+            }
+        };
+        log::debug!("Received {:?} from {}", message, addr);
+
+        match message {
+            ClientUdpMessage::Init { token } => {
+                let Some(id) = session(token) else {
+                    log::debug!("Datagram dropped: addr = {}, unknown token", addr);
+                    self.drop_datagram("unknown_token");
+                    return;
+                };
+                log::info!("Client connected, addr: {}, id: {}", addr, id);
+
+                // A retried or repeated Init restarts the session
+                self.connections.retain(|c| c.id != id && c.token != token);
+                self.connections.push(Connection::new(id, token, addr));
+
+                let mut buf = [0; 8];
+                let ack = write_datagram(&ServerUdpMessage::Init, &mut buf).unwrap();
+                if let Err(e) = self.socket.send_to(ack, addr) {
+                    log::warn!("Failed to acknowledge Init: addr = {}, {}", addr, e);
+                }
+            }
+            ClientUdpMessage::Message {
+                token,
+                sequence,
+                message,
+            } => {
+                let Some(connection) = self.connections.iter_mut().find(|c| c.token == token)
+                else {
+                    log::debug!("Datagram dropped: addr = {}, unknown token", addr);
+                    self.drop_datagram("unknown_token");
+                    return;
+                };
+                if connection.addr != addr {
+                    log::info!(
+                        "UDP session migrated: id = {}, {} -> {}",
+                        connection.id,
+                        connection.addr,
+                        addr
+                    );
+                    metrics::inc("cark_udp_migrations_total", &[], 1);
+                    connection.addr = addr;
+                }
+
+                connection.update(sequence);
+                metrics::inc(
+                    "cark_messages_received_total",
+                    &[("transport", "udp"), ("message", message.name())],
+                    1,
+                );
+
+                if let ClientMessage::Pong { nonce } = message {
+                    connection.pong(nonce, self.socket.now());
+                    return;
+                }
+                handler(IncomingEvent {
+                    connection_id: connection.id,
+                    sequence,
+                    message,
+                });
+            }
+        }
+    }
+
+    fn drop_datagram(&mut self, reason: &'static str) {
+        self.drops.record(reason);
+        metrics::inc("cark_udp_dropped_total", &[("reason", reason)], 1);
+    }
+
+    // Datagrams dropped on receive
+    pub fn drops(&self) -> &DropStat {
+        &self.drops
+    }
+
+    pub fn connections(&self) -> &[Connection] {
+        &self.connections
+    }
+
+    // Events waiting to be sent on the next process
+    pub fn pending_events(&self) -> usize {
+        self.outgoing_events.len()
+    }
+
+    // Set the gauges of the connections, forgetting the ones gone
+    pub fn record_metrics(&self) {
+        let mut registry = metrics::registry();
+        registry.set(
+            "cark_connections",
+            &[("transport", "udp")],
+            self.connections.len() as f64,
+        );
+        registry.clear("cark_udp_loss_ratio");
+        for connection in &self.connections {
+            registry.set(
+                "cark_udp_loss_ratio",
+                &[("connection", &connection.id.to_string())],
+                connection.stat.loss_rate(),
+            );
+        }
+    }
+
     pub fn log_stat(&self) {
+        if self.drops.total() > 0 {
+            log::info!("Datagrams dropped: {:?}", self.drops);
+        }
         for connection in &self.connections {
             log::info!(
-                "Connection: id={}, addr={}, loss={:.2}%", //, rtt={:?}ms
+                "Connection: id={}, addr={}, loss={:.2}%, rtt={:?}",
                 connection.id,
                 connection.addr,
                 connection.stat.loss_rate() * 100.0,
+                connection.rtt,
             );
         }
     }
 }
 
+fn record_sent(message: &str, size: usize) {
+    metrics::inc(
+        "cark_bytes_sent_total",
+        &[("transport", "udp")],
+        size as u64,
+    );
+    metrics::inc(
+        "cark_messages_sent_total",
+        &[("transport", "udp"), ("message", message)],
+        1,
+    );
+}
+
 pub struct Connection {
     id: u64,
+    token: u64,
+    // Where the last datagram came from
     addr: SocketAddr,
     stat: UdpStat,
     sequence: SequenceGen,
+    // The ping waiting for its pong, and when it was sent
+    ping: Option<(u64, Instant)>,
+    last_ping: Option<Instant>,
+    rtt: Option<Duration>,
 }
 
 impl Connection {
-    pub fn new(id: u64, addr: SocketAddr) -> Self {
+    pub fn new(id: u64, token: u64, addr: SocketAddr) -> Self {
         Self {
             id,
+            token,
             addr,
             stat: UdpStat::new(),
             sequence: SequenceGen::default(),
+            ping: None,
+            last_ping: None,
+            rtt: None,
         }
     }
 
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn stat(&self) -> &UdpStat {
+        &self.stat
+    }
+
+    // Round trip time of the last pong
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
     pub fn update(&mut self, sequence: Sequence) {
         self.stat.update(sequence);
     }
+
+    // The nonce of a new ping if it's time to send one
+    fn ping(&mut self, now: Instant) -> Option<u64> {
+        if self
+            .last_ping
+            .is_some_and(|t| now.duration_since(t) < PING_INTERVAL)
+        {
+            return None;
+        }
+        let nonce = self.ping.map_or(0, |(nonce, _)| nonce + 1);
+        self.ping = Some((nonce, now));
+        self.last_ping = Some(now);
+        Some(nonce)
+    }
+
+    fn pong(&mut self, nonce: u64, now: Instant) {
+        if let Some((expected, sent)) = self.ping {
+            if nonce == expected {
+                self.rtt = Some(now.duration_since(sent));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn mutate(rng: &mut impl rand::Rng, sample: &[u8]) -> Vec<u8> {
+    let mut data = sample.to_vec();
+    match rng.gen_range(0..3) {
+        0 => {
+            for _ in 0..rng.gen_range(1..4) {
+                let i = rng.gen_range(0..data.len());
+                data[i] = rng.gen();
+            }
+        }
+        1 => data.truncate(rng.gen_range(0..data.len())),
+        _ => data.extend((0..rng.gen_range(1..8)).map(|_| rng.gen::<u8>())),
+    }
+    data
+}
+
+#[test]
+fn test_fuzz() {
+    use cark_common::field::ChunkId;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::net::UdpSocket;
+
+    let mut udp = Udp::new("127.0.0.1:0").unwrap();
+    let addr: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+    let mut buf = [0; MAX_DATAGRAM_SIZE];
+    let samples: Vec<Vec<u8>> = [
+        ClientUdpMessage::Init { token: 7 },
+        ClientUdpMessage::Message {
+            token: 7,
+            sequence: 0,
+            message: ClientMessage::Position {
+                chunk_id: ChunkId::MIN,
+                position: [1.5, 2.5],
+                velocity: [0.0, 0.0],
+            },
+        },
+        ClientUdpMessage::Message {
+            token: 7,
+            sequence: 1,
+            message: ClientMessage::Pong { nonce: 0 },
+        },
+    ]
+    .iter()
+    .map(|m| write_datagram(m, &mut buf).unwrap().to_vec())
+    .collect();
+
+    let session = |token| (token == 7).then_some(1);
+
+    // Random bytes and broken valid datagrams, none of which may panic
+    let mut rng = StdRng::seed_from_u64(0);
+    for i in 0..20000 {
+        let data = if i % 2 == 0 {
+            let len = rng.gen_range(0..=MAX_DATAGRAM_SIZE + 1);
+            (0..len).map(|_| rng.gen()).collect()
+        } else {
+            let sample = &samples[rng.gen_range(0..samples.len())];
+            mutate(&mut rng, sample)
+        };
+        udp.receive(&data, addr, &session, &mut |_| {});
+    }
+    assert!(udp.drops().get("malformed") > 0);
+    assert!(udp.drops().get("trailing_bytes") > 0);
+    assert!(udp.drops().get("too_large") > 0);
+
+    // Through the socket, with a message before `Init`
+    let mut udp = Udp::new("127.0.0.1:0").unwrap();
+    let udp_addr = udp.local_addr().unwrap();
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.send_to(&samples[1], udp_addr).unwrap();
+    socket.send_to(&[0xff; 2000], udp_addr).unwrap();
+    socket.send_to(&samples[0], udp_addr).unwrap();
+    socket.send_to(&samples[1], udp_addr).unwrap();
+    let mut events = vec![];
+    receive_until(&mut udp, session, &mut events, 1);
+    assert_eq!(udp.drops().get("unknown_token"), 1);
+    assert_eq!(udp.drops().get("too_large"), 1);
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].connection_id, 1);
+    let mut buf = [0; MAX_DATAGRAM_SIZE];
+    let size = socket.recv(&mut buf).unwrap();
+    assert!(matches!(
+        read_datagram(&buf[..size]),
+        Ok(ServerUdpMessage::Init)
+    ));
+
+    // The session moves with the token when the client shows up at another port
+    let rebound = UdpSocket::bind("127.0.0.1:0").unwrap();
+    rebound.send_to(&samples[1], udp_addr).unwrap();
+    receive_until(&mut udp, session, &mut events, 2);
+    assert_eq!(events.len(), 2);
+    assert_eq!(udp.connections()[0].addr(), rebound.local_addr().unwrap());
+
+    // And ends when the token is no longer given out
+    udp.process(|_| None, |_| {}).unwrap();
+    assert!(udp.connections().is_empty());
+}
+
+#[cfg(test)]
+fn receive_until(
+    udp: &mut Udp,
+    session: impl Fn(u64) -> Option<u64>,
+    events: &mut Vec<IncomingEvent>,
+    count: usize,
+) {
+    for _ in 0..100 {
+        udp.process(&session, |e| events.push(e)).unwrap();
+        if events.len() >= count {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
 }
@@ -0,0 +1,73 @@
+// The world kept in a file so that it survives restarts: its id, by which the clients key their
+// chunk caches, and the chunks generated so far
+
+use std::path::{Path, PathBuf};
+
+use cark_common::field::Chunk;
+
+// The file at `WORLD`, or `world.bin`
+pub fn world_path() -> PathBuf {
+    std::env::var("WORLD")
+        .unwrap_or("world.bin".to_string())
+        .into()
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct SavedWorld {
+    pub world_id: u64,
+    pub chunk_size: usize,
+    pub chunks: Vec<Chunk>,
+}
+
+impl SavedWorld {
+    // None if there is no file yet
+    pub fn read(path: &Path) -> Option<Self> {
+        match std::fs::read(path) {
+            Ok(data) => Some(
+                cark_common::read_from_slice(&data)
+                    .unwrap_or_else(|e| panic!("Failed to parse world: path = {:?}, {}", path, e)),
+            ),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => panic!("Failed to read world: path = {:?}, {}", path, e),
+        }
+    }
+
+    pub fn write(&self, path: &Path) {
+        // Write to a temporary file first not to lose the world on a crash
+        let tmp = path.with_extension("tmp");
+        let result = std::fs::write(&tmp, cark_common::write(self, vec![]).unwrap())
+            .and_then(|_| std::fs::rename(&tmp, path));
+        if let Err(e) = result {
+            log::error!("Failed to save world: path = {:?}, {}", path, e);
+        }
+    }
+}
+
+#[test]
+fn test() {
+    use cark_common::{direction::Direction, field::ChunkId};
+
+    let path = std::env::temp_dir().join(format!("cark-world-{}.bin", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let tiles = crate::tiles::parse_tiles(include_str!("../assets/tiles.toml"));
+    let mut global = crate::Global::new(tiles.clone(), 16);
+    global.open_world(&path);
+    global
+        .field
+        .generate_chunk(ChunkId::MIN, Direction::Right)
+        .unwrap();
+    global.save_world();
+
+    // The next start continues the same world
+    let mut restarted = crate::Global::new(tiles, 16);
+    assert_ne!(restarted.world_id, global.world_id);
+    restarted.open_world(&path);
+    assert_eq!(restarted.world_id, global.world_id);
+    assert_eq!(restarted.field.chunks.len(), 2);
+    let new_id = restarted
+        .field
+        .generate_chunk(ChunkId::MIN, Direction::Left)
+        .unwrap();
+    assert_eq!(new_id.get(), 3);
+    std::fs::remove_file(&path).unwrap();
+}
@@ -1,3 +1,5 @@
+use cark_client::chunk_manager::ChunkManagerConfig;
+
 pub fn load_config() -> Config {
     let path = "cark.toml";
     let Ok(config) = std::fs::read_to_string(path) else {
@@ -7,16 +9,42 @@ pub fn load_config() -> Config {
 }
 
 #[derive(serde::Deserialize, Debug)]
+#[serde(default)]
 pub struct Config {
     pub server_tcp_addr: String,
     pub server_udp_addr: String,
+    // Local address to bind for UDP, e.g. "0.0.0.0:34254", any port if not given
+    pub udp_local_addr: Option<String>,
+    // A random name if not given
+    pub name: Option<String>,
+    pub password: String,
+    pub chunk_radius: usize,
+    pub chunk_capacity: usize,
+    pub chunk_cache_dir: Option<String>,
 }
 
 impl Default for Config {
     fn default() -> Self {
+        let chunk_config = ChunkManagerConfig::default();
         Self {
             server_tcp_addr: "127.0.0.1:8080".to_string(),
             server_udp_addr: "127.0.0.1:8081".to_string(),
+            udp_local_addr: None,
+            name: None,
+            password: String::new(),
+            chunk_radius: chunk_config.radius,
+            chunk_capacity: chunk_config.capacity,
+            chunk_cache_dir: Some("cache".to_string()),
+        }
+    }
+}
+
+impl Config {
+    pub fn chunk_config(&self) -> ChunkManagerConfig {
+        ChunkManagerConfig {
+            radius: self.chunk_radius,
+            capacity: self.chunk_capacity,
+            cache_dir: self.chunk_cache_dir.as_ref().map(|dir| dir.into()),
         }
     }
 }
@@ -1,7 +1,7 @@
 pub mod audio;
 pub mod config;
 
-use cark_common::field::CHUNK_SIZE;
+use cark_common::{field::Layer, tile};
 
 pub fn draw<C, G>(
     glyphs: &mut C,
@@ -14,117 +14,239 @@ pub fn draw<C, G>(
     C: piston_window::character::CharacterCache,
     G: piston_window::Graphics<Texture = <C as piston_window::character::CharacterCache>::Texture>,
 {
-    use piston_window::{ellipse, text, Transformed};
+    use piston_window::{ellipse, rectangle, text, Transformed};
 
     if let Some(my_character) = game.characters.iter().find(|c| c.id() == game.player_id) {
         let chip_size = 8.0;
         let cell_size = 24.0;
         let size = 16;
-        let rect = [-size, -size, size * 2, size * 2];
-        let data = game.field().view(my_character.chunk_id, rect);
+        let chunk_size = game.field().chunk_size() as i32;
+        let rect = [-size, -size, chunk_size + size, chunk_size + size];
         let transform = ctx.transform.trans(
             -cell_size * (my_character.position[0] as f64 + size as f64)
                 + ctx.get_view_size()[0] / 2.0,
             -cell_size * (my_character.position[1] as f64 + size as f64)
                 + ctx.get_view_size()[1] / 2.0,
         );
-        for y in 0..(rect[3] - rect[1]) {
-            for x in 0..(rect[2] - rect[0]) {
-                let cell = data[(y * (rect[2] - rect[0]) + x) as usize];
-                // rectangle(
-                //     match cell {
-                //         0 => [0.1, 0.1, 0.1, 1.0],
-                //         1 => [0.0, 0.5, 0.0, 1.0],
-                //         2 => [1.0, 0.5, 0.0, 1.0],
-                //         3 => [1.0, 0.0, 0.0, 1.0],
-                //         _ => [0.9, 0.9, 0.9, 1.0],
-                //     },
-                //     [
-                //         x as f64 * cell_size,
-                //         y as f64 * cell_size,
-                //         cell_size,
-                //         cell_size,
-                //     ],
-                //     transform,
-                //     g,
-                // );
+        for layer in Layer::ALL {
+            let data = game.field().view(my_character.chunk_id, layer, rect);
+            for y in 0..(rect[3] - rect[1]) {
+                for x in 0..(rect[2] - rect[0]) {
+                    let cell = data[(y * (rect[2] - rect[0]) + x) as usize];
+                    // Only the ground is drawn where nothing is placed
+                    if cell == tile::VOID && layer != Layer::Ground {
+                        continue;
+                    }
+                    // rectangle(
+                    //     match cell {
+                    //         0 => [0.1, 0.1, 0.1, 1.0],
+                    //         1 => [0.0, 0.5, 0.0, 1.0],
+                    //         2 => [1.0, 0.5, 0.0, 1.0],
+                    //         3 => [1.0, 0.0, 0.0, 1.0],
+                    //         _ => [0.9, 0.9, 0.9, 1.0],
+                    //     },
+                    //     [
+                    //         x as f64 * cell_size,
+                    //         y as f64 * cell_size,
+                    //         cell_size,
+                    //         cell_size,
+                    //     ],
+                    //     transform,
+                    //     g,
+                    // );
+
+                    let sprite = game.tiles().get(cell).sprite;
+                    image
+                        .src_rect([chip_size * sprite as f64, 0.0, chip_size, chip_size])
+                        .draw(
+                            tex_tiles,
+                            &Default::default(),
+                            transform
+                                .trans(x as f64 * cell_size, y as f64 * cell_size)
+                                .scale(cell_size / chip_size * 1.01, cell_size / chip_size * 1.01),
+                            g,
+                        );
+                }
+            }
+        }
 
+        let chunks_around = game.field().chunks_around(my_character.chunk_id);
+        for entity in game.entities.iter() {
+            let (Some(placement), Some(sprite)) = (entity.transform, entity.sprite) else {
+                continue;
+            };
+            for (rel, _) in chunks_around
+                .iter()
+                .filter(|c| c.1.map(|c| c.id == placement.chunk_id).unwrap_or_default())
+            {
+                let transform = transform.trans(
+                    rel[0] as f64 * cell_size * chunk_size as f64
+                        + (placement.position[0] as f64 - rect[0] as f64) * cell_size,
+                    rel[1] as f64 * cell_size * chunk_size as f64
+                        + (placement.position[1] as f64 - rect[1] as f64) * cell_size,
+                );
+                let size = cell_size * sprite.scale as f64;
                 image
-                    .src_rect([chip_size * cell as f64, 0.0, chip_size, chip_size])
+                    .src_rect([
+                        chip_size * sprite.chip[0] as f64,
+                        chip_size * sprite.chip[1] as f64,
+                        chip_size,
+                        chip_size,
+                    ])
                     .draw(
                         tex_tiles,
                         &Default::default(),
                         transform
-                            .trans(x as f64 * cell_size, y as f64 * cell_size)
-                            .scale(cell_size / chip_size * 1.01, cell_size / chip_size * 1.01),
+                            .trans(-0.5 * size, -0.5 * size)
+                            .scale(size / chip_size * 1.01, size / chip_size * 1.01),
                         g,
                     );
             }
         }
 
-        let chunks_around = game.field().chunks_around(my_character.chunk_id);
         for character in &game.characters {
-            let Some((rel, _)) = chunks_around
+            // Through portals the same chunk may appear at several offsets
+            for (rel, _) in chunks_around
                 .iter()
-                .find(|c| c.1.map(|c| c.id == character.chunk_id).unwrap_or_default())
-            else {
-                continue;
-            };
-
-            let transform = transform.trans(
-                rel[0] as f64 * cell_size * CHUNK_SIZE as f64
-                    + (character.position[0] as f64 - rect[0] as f64) * cell_size,
-                rel[1] as f64 * cell_size * CHUNK_SIZE as f64
-                    + (character.position[1] as f64 - rect[1] as f64) * cell_size,
-            );
-            // ellipse(
-            //     [0.0, 0.0, 1.0, 1.0],
-            //     [-0.5 * cell_size, -0.5 * cell_size, cell_size, cell_size],
-            //     transform,
-            //     g,
-            // );
-            ellipse(
-                [0.0, 0.0, 0.0, 0.25],
-                [
-                    -0.5 * cell_size,
-                    0.5 * cell_size,
-                    cell_size,
-                    cell_size * 0.25,
-                ],
-                transform,
-                g,
-            );
-            image
-                .src_rect([chip_size * 0.0, chip_size * 1.0, chip_size, chip_size])
-                .draw(
-                    tex_tiles,
-                    &Default::default(),
-                    transform
-                        .trans(-0.5 * cell_size, -0.5 * cell_size)
-                        .scale(cell_size / chip_size * 1.01, cell_size / chip_size * 1.01),
+                .filter(|c| c.1.map(|c| c.id == character.chunk_id).unwrap_or_default())
+            {
+                let transform = transform.trans(
+                    rel[0] as f64 * cell_size * chunk_size as f64
+                        + (character.position[0] as f64 - rect[0] as f64) * cell_size,
+                    rel[1] as f64 * cell_size * chunk_size as f64
+                        + (character.position[1] as f64 - rect[1] as f64) * cell_size,
+                );
+                // ellipse(
+                //     [0.0, 0.0, 1.0, 1.0],
+                //     [-0.5 * cell_size, -0.5 * cell_size, cell_size, cell_size],
+                //     transform,
+                //     g,
+                // );
+                ellipse(
+                    [0.0, 0.0, 0.0, 0.25],
+                    [
+                        -0.5 * cell_size,
+                        0.5 * cell_size,
+                        cell_size,
+                        cell_size * 0.25,
+                    ],
+                    transform,
                     g,
                 );
-            text(
-                [0.0, 0.0, 0.0, 0.5],
-                12,
-                character.name(),
-                glyphs,
-                transform.trans(0.0, -cell_size),
-                g,
-            )
-            .unwrap();
+                image
+                    .src_rect([chip_size * 0.0, chip_size * 1.0, chip_size, chip_size])
+                    .draw(
+                        tex_tiles,
+                        &Default::default(),
+                        transform
+                            .trans(-0.5 * cell_size, -0.5 * cell_size)
+                            .scale(cell_size / chip_size * 1.01, cell_size / chip_size * 1.01),
+                        g,
+                    );
+                text(
+                    [0.0, 0.0, 0.0, 0.5],
+                    12,
+                    character.name(),
+                    glyphs,
+                    transform.trans(0.0, -cell_size),
+                    g,
+                )
+                .unwrap();
+            }
         }
     };
 
+    for (i, notice) in game.notices.iter().enumerate() {
+        text(
+            [0.0, 0.0, 0.5, 1.0],
+            12,
+            notice,
+            glyphs,
+            ctx.transform.trans(4.0, 30.0 + i as f64 * 14.0),
+            g,
+        )
+        .unwrap();
+    }
+
+    if let Some(reason) = &game.join_rejected {
+        text(
+            [0.8, 0.0, 0.0, 1.0],
+            16,
+            &format!("Join rejected: {}", reason),
+            glyphs,
+            ctx.transform.trans(4.0, ctx.get_view_size()[1] / 2.0),
+            g,
+        )
+        .unwrap();
+    }
+
     text(
         [0.0, 0.0, 0.0, 1.0],
         12,
-        &format!("ups: {:?}", game.ups),
+        &format!("ups: {:?}, transport: {}", game.ups, game.transport),
         glyphs,
         ctx.transform.trans(1.0, 13.0),
         g,
     )
     .unwrap();
+
+    // Inventory panel along the bottom, Tab selects, E uses, Q drops and G gives
+    let chip_size = 8.0;
+    let slot_size = 32.0;
+    let top = ctx.get_view_size()[1] - slot_size - 4.0;
+    for (i, stack) in game.inventory.items.iter().enumerate() {
+        let transform = ctx.transform.trans(4.0 + i as f64 * (slot_size + 4.0), top);
+        let selected = i == game.selected_item;
+        rectangle(
+            if selected {
+                [1.0, 0.8, 0.2, 0.8]
+            } else {
+                [0.0, 0.0, 0.0, 0.3]
+            },
+            [0.0, 0.0, slot_size, slot_size],
+            transform,
+            g,
+        );
+        let chip = game
+            .items()
+            .get(stack.item)
+            .map_or([0, 0], |item| item.sprite);
+        image
+            .src_rect([
+                chip_size * chip[0] as f64,
+                chip_size * chip[1] as f64,
+                chip_size,
+                chip_size,
+            ])
+            .draw(
+                tex_tiles,
+                &Default::default(),
+                transform
+                    .trans(4.0, 4.0)
+                    .scale((slot_size - 8.0) / chip_size, (slot_size - 8.0) / chip_size),
+                g,
+            );
+        text(
+            [0.0, 0.0, 0.0, 1.0],
+            10,
+            &stack.count.to_string(),
+            glyphs,
+            transform.trans(slot_size - 12.0, slot_size - 2.0),
+            g,
+        )
+        .unwrap();
+    }
+    if let Some((_, Some(kind))) = game.selected_item() {
+        text(
+            [0.0, 0.0, 0.0, 1.0],
+            12,
+            &kind.name,
+            glyphs,
+            ctx.transform.trans(4.0, top - 4.0),
+            g,
+        )
+        .unwrap();
+    }
 }
 
 pub fn system_step_se(
@@ -142,14 +264,22 @@ pub fn system_step_se(
             if d > 0.1 {
                 *step_count -= d * input.dt * 0.5;
                 if *step_count < 0.0 {
-                    if let Some(audio_sys) = &audio_sys {
+                    let footstep = &game.tile_under(chara).footstep;
+                    if let (Some(audio_sys), Some(footstep)) = (&audio_sys, footstep) {
                         audio_sys.items.lock().unwrap().push(
                             audio::AudioItem::new_se(buf_se_step.clone())
-                                .volume(16.0f32.recip() * if is_player { 1.0 } else { 0.5 })
+                                .volume(
+                                    16.0f32.recip()
+                                        * footstep.volume
+                                        * if is_player { 1.0 } else { 0.5 },
+                                )
                                 .pitch(
-                                    0.9 + ((chara.position[0] * 5.0 + chara.position[1] * 6.0)
-                                        % 1.0)
-                                        * 0.2,
+                                    footstep.pitch
+                                        * (0.9
+                                            + ((chara.position[0] * 5.0
+                                                + chara.position[1] * 6.0)
+                                                % 1.0)
+                                                * 0.2),
                                 ),
                         );
                     }
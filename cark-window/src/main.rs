@@ -34,19 +34,27 @@ fn main() {
 
     let mut touch_visualizer = touch_visualizer::TouchVisualizer::new();
 
-    let name = (std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_millis()
-        % 1000)
-        .to_string();
+    let name = config.name.clone().unwrap_or_else(|| {
+        (std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis()
+            % 1000)
+            .to_string()
+    });
 
     let communication = cark_client::communication::Communication::new(
         &config.server_tcp_addr,
         &config.server_udp_addr,
+        config.udp_local_addr.as_deref(),
     )
     .unwrap();
-    let mut client = cark_client::client::Client::new(communication, name);
+    let mut client = cark_client::client::Client::new(
+        communication,
+        name,
+        config.password.clone(),
+        config.chunk_config(),
+    );
     let mut input = cark_client::Input::new();
 
     let buf_bgm: AudioBufferRef = {
@@ -87,6 +95,10 @@ fn main() {
                 Key::D => Some(3),
                 Key::Space => Some(4),
                 Key::Escape => Some(5),
+                Key::Tab => Some(6),
+                Key::E => Some(7),
+                Key::Q => Some(8),
+                Key::G => Some(9),
                 _ => None,
             } {
                 input.key_down[k] = true;
@@ -100,6 +112,10 @@ fn main() {
                 Key::D => Some(3),
                 Key::Space => Some(4),
                 Key::Escape => Some(5),
+                Key::Tab => Some(6),
+                Key::E => Some(7),
+                Key::Q => Some(8),
+                Key::G => Some(9),
                 _ => None,
             } {
                 input.key_up[k] = true;